@@ -1,23 +1,31 @@
 use std::sync::*;
+use std::thread;
 
 use criterion::*;
 use tempfile::tempdir;
 
-use gc_test::{Payload as GcPayload, Publish as GcPublish, DB as GcDB};
-use load_consolidate_test::{Publish as LcPublish, DB as LcGC};
+#[cfg(feature = "redb")]
+use gc_test::kv::DB as KvDB;
+use gc_test::DB as GcDB;
+use load_consolidate_test::DB as LcDB;
+use page_file_test::DB as PageDB;
+use segment_log_test::DB as SegmentDB;
+use session_store::{Payload, Publish, SessionStore};
 
-fn gc_read_write_single(c: &mut Criterion) {
-    c.bench_function("gc_read_write_single", |b| {
+fn read_write_single<S: SessionStore>(c: &mut Criterion, prefix: &str) {
+    c.bench_function(&format!("{}_read_write_single", prefix), |b| {
         b.iter_batched(
             || {
                 let dir = tempdir().unwrap();
-                let db = GcDB::new(dir.as_ref()).expect("Make db");
-                let data = Faker::new().gc_publish(&[vec![1, 2, 3, 4, 5]]);
+                let db = S::open(dir.as_ref()).expect("Make db");
+                let data = Faker::new().publish(&[vec![1, 2, 3, 4, 5]]);
 
                 (dir, db, data)
             },
-            |(_dir, mut db, data)| {
-                db.write("Session 1", &data).expect("Publish 1");
+            |(_dir, db, data)| {
+                for publish in data {
+                    db.append("Session 1", publish).expect("Publish 1");
+                }
 
                 let stored = db.read("Session 1").unwrap();
 
@@ -30,20 +38,23 @@ fn gc_read_write_single(c: &mut Criterion) {
     });
 }
 
-fn gc_read_write_many_small_payload(c: &mut Criterion) {
-    c.bench_function("gc_read_write_many_small_payload", |b| {
+fn read_write_many_small_payload<S: SessionStore>(c: &mut Criterion, prefix: &str, count: u8) {
+    c.bench_function(&format!("{}_read_write_many_small_payload", prefix), |b| {
         b.iter_batched(
             || {
                 let dir = tempdir().unwrap();
-                let db = GcDB::new(dir.as_ref()).expect("Make db");
+                let db = S::open(dir.as_ref()).expect("Make db");
 
-                let payloads: Vec<Vec<u8>> = (0..5).map(|i| vec![i]).collect();
-                let data = Faker::new().gc_publish(&payloads);
+                let payloads: Vec<Vec<u8>> = (0..count).map(|i| vec![i]).collect();
+                let data = Faker::new().publish(&payloads);
 
                 (dir, db, data)
             },
-            |(_dir, mut db, data)| {
-                db.write("Session 1", &data).expect("Publish 1");
+            |(_dir, db, data)| {
+                for publish in data {
+                    db.append("Session 1", publish).expect("Publish 1");
+                }
+
                 db.read("Session 1").unwrap()
             },
             BatchSize::SmallInput,
@@ -51,95 +62,159 @@ fn gc_read_write_many_small_payload(c: &mut Criterion) {
     });
 }
 
-fn lc_read_write_single(c: &mut Criterion) {
-    c.bench_function("lc_read_write_single", |b| {
+fn read_write_many_small_payload_many_session<S: SessionStore>(c: &mut Criterion, prefix: &str) {
+    c.bench_function(
+        &format!("{}_read_write_many_small_payload_many_session", prefix),
+        |b| {
+            b.iter_batched(
+                || {
+                    let dir = tempdir().unwrap();
+                    let db = S::open(dir.as_ref()).expect("Make db");
+
+                    let payloads: Vec<Vec<u8>> = (0..100).map(|i| vec![i]).collect();
+                    let data = Faker::new().publish(&payloads);
+
+                    (dir, db, data)
+                },
+                |(_dir, db, data)| {
+                    let mut test = Vec::with_capacity(10);
+
+                    for i in 0..5 {
+                        let name = format!("Session {}", i);
+                        for publish in data.iter() {
+                            db.append(&name, publish.clone()).expect("Publish 1");
+                        }
+                        let result = db.read(&name).unwrap();
+
+                        test.push(result);
+                    }
+
+                    test
+                },
+                BatchSize::SmallInput,
+            );
+        },
+    );
+}
+
+/// Publishes and acks from many threads at once, each with its own session,
+/// where stores that sync writers' changes together pay off.
+fn write_ack_concurrent_sessions<S: SessionStore + 'static>(c: &mut Criterion, prefix: &str) {
+    c.bench_function(&format!("{}_write_ack_concurrent_sessions", prefix), |b| {
         b.iter_batched(
             || {
                 let dir = tempdir().unwrap();
-                let db = LcGC::new(dir.as_ref());
-                let data = Faker::new().lc_publish(&[vec![1, 2, 3, 4, 5]]);
+                let db = Arc::new(S::open(dir.as_ref()).expect("Make db"));
+
+                let payloads: Vec<Vec<u8>> = (0..20).map(|i| vec![i; 64]).collect();
+                let data = Faker::new().publish(&payloads);
 
                 (dir, db, data)
             },
-            |(_dir, mut db, data)| {
-                db.write("Session 1", &data).expect("Publish 1");
-
-                let stored = db.read("Session 1").unwrap();
-
-                assert_eq!(stored.len(), 1);
-                assert_eq!(stored[0].topic_name, "fake");
-                assert_eq!(stored[0].payload, Arc::new(vec![1, 2, 3, 4, 5]));
+            |(_dir, db, data)| {
+                let handles: Vec<_> = (0..8)
+                    .map(|i| {
+                        let db = db.clone();
+                        let data = data.clone();
+                        thread::spawn(move || {
+                            let name = format!("Session {}", i);
+                            for publish in data.iter() {
+                                db.append(&name, publish.clone()).expect("Publish");
+                            }
+                            for publish in data.iter() {
+                                db.ack(&name, publish.packet_id).expect("Ack");
+                            }
+                        })
+                    })
+                    .collect();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
             },
             BatchSize::SmallInput,
         );
     });
 }
 
-fn lc_read_write_many_small_payload(c: &mut Criterion) {
-    c.bench_function("lc_read_write_many_small_payload", |b| {
+/// Publishes each message to many sessions, as a broker does for a topic with
+/// many subscribers.
+fn write_fanout_many_session<S: SessionStore>(c: &mut Criterion, prefix: &str) {
+    c.bench_function(&format!("{}_write_fanout_many_session", prefix), |b| {
         b.iter_batched(
             || {
                 let dir = tempdir().unwrap();
-                let db = LcGC::new(dir.as_ref());
+                let db = S::open(dir.as_ref()).expect("Make db");
 
-                let payloads: Vec<Vec<u8>> = (0..100).map(|i| vec![i]).collect();
-                let data = Faker::new().lc_publish(&payloads);
+                let payloads: Vec<Vec<u8>> = (0..10).map(|i| vec![i; 1024]).collect();
+                let data = Faker::new().publish(&payloads);
+                let names: Vec<String> = (0..50).map(|i| format!("Session {}", i)).collect();
 
-                (dir, db, data)
+                (dir, db, data, names)
             },
-            |(_dir, mut db, data)| {
-                db.write("Session 1", &data).expect("Publish 1");
-                db.read("Session 1").unwrap()
+            |(_dir, db, data, names)| {
+                let session_ids: Vec<&str> = names.iter().map(String::as_str).collect();
+                for publish in data {
+                    db.append_fanout(publish, &session_ids).expect("Fanout");
+                }
             },
             BatchSize::SmallInput,
         );
     });
 }
 
-fn lc_read_write_many_small_payload_many_session(c: &mut Criterion) {
-    c.bench_function("lc_read_write_many_small_payload_many_session", |b| {
-        b.iter_batched(
-            || {
-                let dir = tempdir().unwrap();
-                let db = LcGC::new(dir.as_ref());
-
-                let payloads: Vec<Vec<u8>> = (0..100).map(|i| vec![i]).collect();
-                let data = Faker::new().lc_publish(&payloads);
+fn gc_benches(c: &mut Criterion) {
+    read_write_single::<GcDB>(c, "gc");
+    read_write_many_small_payload::<GcDB>(c, "gc", 5);
+    read_write_many_small_payload_many_session::<GcDB>(c, "gc");
+    write_ack_concurrent_sessions::<GcDB>(c, "gc");
+    write_fanout_many_session::<GcDB>(c, "gc");
+}
 
-                (dir, db, data)
-            },
-            |(_dir, mut db, data)| {
-                let mut test = Vec::with_capacity(10);
+fn lc_benches(c: &mut Criterion) {
+    read_write_single::<LcDB>(c, "lc");
+    read_write_many_small_payload::<LcDB>(c, "lc", 100);
+    read_write_many_small_payload_many_session::<LcDB>(c, "lc");
+    write_ack_concurrent_sessions::<LcDB>(c, "lc");
+    write_fanout_many_session::<LcDB>(c, "lc");
+}
 
-                for i in 0..5 {
-                    let name = format!("Session {}", i);
-                    db.write(&name, &data).expect("Publish 1");
-                    let result = db.read(&name).unwrap();
+fn page_benches(c: &mut Criterion) {
+    read_write_single::<PageDB>(c, "page");
+    read_write_many_small_payload::<PageDB>(c, "page", 100);
+    read_write_many_small_payload_many_session::<PageDB>(c, "page");
+    write_ack_concurrent_sessions::<PageDB>(c, "page");
+    write_fanout_many_session::<PageDB>(c, "page");
+}
 
-                    test.push(result);
-                }
+fn segment_benches(c: &mut Criterion) {
+    read_write_single::<SegmentDB>(c, "segment");
+    read_write_many_small_payload::<SegmentDB>(c, "segment", 100);
+    read_write_many_small_payload_many_session::<SegmentDB>(c, "segment");
+    write_ack_concurrent_sessions::<SegmentDB>(c, "segment");
+    write_fanout_many_session::<SegmentDB>(c, "segment");
+}
 
-                test
-            },
-            BatchSize::SmallInput,
-        );
-    });
+#[cfg(feature = "redb")]
+fn kv_benches(c: &mut Criterion) {
+    read_write_single::<KvDB>(c, "kv");
+    read_write_many_small_payload::<KvDB>(c, "kv", 100);
+    read_write_many_small_payload_many_session::<KvDB>(c, "kv");
+    write_ack_concurrent_sessions::<KvDB>(c, "kv");
+    write_fanout_many_session::<KvDB>(c, "kv");
 }
 
-criterion_group!(
-    garbage_collection,
-    gc_read_write_single,
-    gc_read_write_many_small_payload
-);
-criterion_group!(
-    load_consolidation,
-    lc_read_write_single,
-    lc_read_write_many_small_payload,
-    lc_read_write_many_small_payload_many_session
-);
-
-// criterion_main!(garbage_collection, load_consolidation);
-criterion_main!(load_consolidation);
+criterion_group!(garbage_collection, gc_benches);
+criterion_group!(load_consolidation, lc_benches);
+criterion_group!(page_file, page_benches);
+criterion_group!(segment_log, segment_benches);
+#[cfg(feature = "redb")]
+criterion_group!(key_value, kv_benches);
+
+// criterion_main!(garbage_collection, load_consolidation, page_file, segment_log);
+#[cfg(not(feature = "redb"))]
+criterion_main!(load_consolidation, page_file, segment_log);
+#[cfg(feature = "redb")]
+criterion_main!(load_consolidation, page_file, segment_log, key_value);
 
 struct Faker {
     packet_id: u16,
@@ -154,16 +229,16 @@ impl Faker {
         }
     }
 
-    fn gc_publish(&mut self, payloads: &[Vec<u8>]) -> Vec<GcPublish> {
+    fn publish(&mut self, payloads: &[Vec<u8>]) -> Vec<Publish> {
         payloads
             .iter()
             .map(|payload| {
                 self.packet_id += 2;
                 self.payload_id += 1;
 
-                GcPublish {
+                Publish {
                     packet_id: self.packet_id,
-                    payload: GcPayload {
+                    payload: Payload {
                         id: self.payload_id,
                         bytes: Arc::new(payload.clone()),
                     },
@@ -173,21 +248,4 @@ impl Faker {
             })
             .collect()
     }
-
-    fn lc_publish(&mut self, payloads: &[Vec<u8>]) -> Vec<LcPublish> {
-        payloads
-            .iter()
-            .map(|payload| {
-                self.packet_id += 2;
-                self.payload_id += 1;
-
-                LcPublish {
-                    packet_id: self.packet_id,
-                    payload: Arc::new(payload.clone()),
-                    retain: true,
-                    topic_name: "fake".to_owned(),
-                }
-            })
-            .collect()
-    }
 }
@@ -0,0 +1,504 @@
+//! Offline consistency check of a store directory, and repair of whatever it
+//! finds, for recovering a store after a crash.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, create_dir_all, remove_dir_all, remove_file, rename};
+use std::path::{Path, PathBuf};
+
+use session_store::format::{self, unseal};
+use session_store::log::{encode_record, new_log, peek_log};
+use session_store::{is_session_id_file, is_temp_file, read_session_id, sync_dir, write_atomic};
+
+use crate::{
+    CorruptionPolicy, Durability, Refcounts, StoreError, WalRecord, DB, FORMAT_VERSION, MIGRATIONS,
+};
+
+/// Something wrong with a store directory.
+#[derive(Debug)]
+pub enum Problem {
+    /// An entry whose name the store can't parse, which makes listing its
+    /// directory fail.
+    BadFileName { path: PathBuf },
+    /// Left behind by a write interrupted by a crash.
+    TempFile { path: PathBuf },
+    /// A message that fails its checksum or doesn't decode.
+    UnreadableMessage { path: PathBuf, error: StoreError },
+    /// A message whose payload file is missing.
+    DanglingReference { path: PathBuf, payload_id: u64 },
+    /// A payload file that fails its checksum.
+    CorruptPayload { payload_id: u64, error: StoreError },
+    /// A payload file no message references.
+    OrphanedPayload { payload_id: u64 },
+    /// The refcount log disagrees with the messages referencing a payload.
+    RefcountMismatch {
+        payload_id: u64,
+        recorded: u64,
+        actual: u64,
+    },
+    /// The refcount log itself can't be read.
+    UnreadableRefcounts { error: StoreError },
+    /// Changes left in the write-ahead log by a crash, which opening the
+    /// store makes to the files. Until then, references to payloads can't be
+    /// checked.
+    UnappliedWal { records: usize },
+    /// The write-ahead log can't be read, so the store can't be opened.
+    UnreadableWal { error: StoreError },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::BadFileName { path } => {
+                write!(f, "{}: not a name the store uses", path.display())
+            }
+            Problem::TempFile { path } => {
+                write!(f, "{}: left over from an interrupted write", path.display())
+            }
+            Problem::UnreadableMessage { error, .. } => write!(f, "unreadable message: {}", error),
+            Problem::DanglingReference { path, payload_id } => write!(
+                f,
+                "{}: references missing payload {}",
+                path.display(),
+                payload_id
+            ),
+            Problem::CorruptPayload { error, .. } => write!(f, "corrupt payload: {}", error),
+            Problem::OrphanedPayload { payload_id } => {
+                write!(f, "payload {} is not referenced by any message", payload_id)
+            }
+            Problem::RefcountMismatch {
+                payload_id,
+                recorded,
+                actual,
+            } => write!(
+                f,
+                "payload {} has refcount {} but {} references",
+                payload_id, recorded, actual
+            ),
+            Problem::UnreadableRefcounts { error } => {
+                write!(f, "unreadable refcount log: {}", error)
+            }
+            Problem::UnappliedWal { records } => write!(
+                f,
+                "write-ahead log holds {} records not yet applied",
+                records
+            ),
+            Problem::UnreadableWal { error } => {
+                write!(f, "unreadable write-ahead log: {}", error)
+            }
+        }
+    }
+}
+
+/// What `check` found.
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub sessions: usize,
+    pub messages: usize,
+    pub payloads: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks the store at `root` without opening it. Only reads; a torn record
+/// at the end of the refcount or write-ahead log is left to opening the store
+/// to cut off.
+/// Stores in an older format version have to be upgraded first, e.g. by
+/// `repair`.
+pub fn check(root: &Path) -> Result<FsckReport, StoreError> {
+    format::check_version(root, FORMAT_VERSION)?;
+
+    let mut report = FsckReport::default();
+
+    let wal = root.join("Wal");
+    if wal.exists() {
+        match peek_log::<WalRecord>(&wal).and_then(|scanned| scanned.into_records()) {
+            Ok(records) if records.is_empty() => {}
+            Ok(records) => report.problems.push(Problem::UnappliedWal {
+                records: records.len(),
+            }),
+            Err(error @ StoreError::Corrupt { .. }) => {
+                report.problems.push(Problem::UnreadableWal { error })
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    // Payloads and references the log adds or removes aren't in the files yet
+    let complete = report.problems.is_empty();
+
+    let mut payload_ids = BTreeSet::new();
+    for path in list(&root.join("Payloads"), &mut report)? {
+        let payload_id = match file_name(&path).parse() {
+            Ok(payload_id) => payload_id,
+            Err(_) => {
+                report.problems.push(Problem::BadFileName { path });
+                continue;
+            }
+        };
+        report.payloads += 1;
+        payload_ids.insert(payload_id);
+
+        if let Err(error) = unseal(&path, &fs::read(&path)?) {
+            report
+                .problems
+                .push(Problem::CorruptPayload { payload_id, error });
+        }
+    }
+
+    let mut references: HashMap<u64, u64> = HashMap::new();
+    let sessions = root.join("Sessions");
+    for session in list(&sessions, &mut report)? {
+        let name = file_name(&session);
+        if is_session_id_file(&name) {
+            continue;
+        }
+        match read_session_id(&sessions, &name) {
+            Ok(Some(_)) => {}
+            Ok(None) | Err(StoreError::Corrupt { .. }) => {
+                report.problems.push(Problem::BadFileName { path: session });
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        report.sessions += 1;
+
+        for path in list(&session.join("Messages"), &mut report)? {
+            if file_name(&path).parse::<u64>().is_err() {
+                report.problems.push(Problem::BadFileName { path });
+                continue;
+            }
+            report.messages += 1;
+
+            let payload_id = match DB::read_body(&path) {
+                Ok(body) => body.payload_id,
+                Err(error @ StoreError::Corrupt { .. })
+                | Err(error @ StoreError::UnsupportedVersion { .. }) => {
+                    report
+                        .problems
+                        .push(Problem::UnreadableMessage { path, error });
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            *references.entry(payload_id).or_default() += 1;
+            if !payload_ids.contains(&payload_id) {
+                report
+                    .problems
+                    .push(Problem::DanglingReference { path, payload_id });
+            }
+        }
+    }
+
+    if !complete {
+        return Ok(report);
+    }
+
+    for payload_id in payload_ids.iter() {
+        if !references.contains_key(payload_id) {
+            report.problems.push(Problem::OrphanedPayload {
+                payload_id: *payload_id,
+            });
+        }
+    }
+
+    let log = root.join("Refcounts");
+    if log.exists() {
+        match Refcounts::peek(log) {
+            Ok(refcounts) => {
+                for payload_id in payload_ids {
+                    let recorded = refcounts.counts.get(&payload_id).copied().unwrap_or(0);
+                    let actual = references.get(&payload_id).copied().unwrap_or(0);
+                    if recorded != actual {
+                        report.problems.push(Problem::RefcountMismatch {
+                            payload_id,
+                            recorded,
+                            actual,
+                        });
+                    }
+                }
+            }
+            Err(error @ StoreError::Corrupt { .. }) => {
+                report.problems.push(Problem::UnreadableRefcounts { error })
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(report)
+}
+
+/// Upgrades the store at `root` if needed, then fixes every problem `check`
+/// finds, returning what was found. Entries with bad names are moved into
+/// `LostFound/` and unreadable messages, or ones whose payload is missing or
+/// corrupt, are quarantined. Refcounts are then rebuilt, and payloads left
+/// unreferenced deleted. The write-ahead log is applied. If some of its
+/// records are unreadable, it is copied into `LostFound/` too and only the
+/// others are applied.
+pub fn repair(root: &Path, durability: Durability) -> Result<FsckReport, StoreError> {
+    format::migrate(root, durability, FORMAT_VERSION, MIGRATIONS)?;
+
+    let report = check(root)?;
+    let wal = root.join("Wal");
+    for problem in report.problems.iter() {
+        let path = match problem {
+            Problem::TempFile { path } if path.is_dir() => {
+                remove_dir_all(path)?;
+                path
+            }
+            Problem::TempFile { path } => {
+                remove_file(path)?;
+                path
+            }
+            Problem::BadFileName { path } => {
+                lose(root, path, durability)?;
+                path
+            }
+            Problem::UnreadableWal { .. } => {
+                salvage_wal(root, &wal, durability)?;
+                &wal
+            }
+            _ => continue,
+        };
+        if let Some(parent) = path.parent() {
+            sync_dir(parent, durability)?;
+        }
+    }
+
+    // Everything else is handled by the store itself once it can list its
+    // files, starting with replaying the write-ahead log
+    let mut db = DB::with_durability(root, durability)?;
+    for session_id in db.get_session_ids()? {
+        db.read_report(&session_id, CorruptionPolicy::Quarantine)?;
+    }
+    db.rebuild_refcounts()?;
+    db.clean()?;
+
+    Ok(report)
+}
+
+/// Moves `path` to the same place under `LostFound/`, never overwriting.
+fn lose(root: &Path, path: &Path, durability: Durability) -> Result<(), StoreError> {
+    let target = lost_path(root, path)?;
+    rename(path, &target)?;
+    if let Some(dir) = target.parent() {
+        sync_dir(dir, durability)?;
+    }
+
+    Ok(())
+}
+
+/// Copies the write-ahead log into `LostFound/`, then rewrites it with just
+/// the records that can still be read, for the store to replay.
+fn salvage_wal(root: &Path, wal: &Path, durability: Durability) -> Result<(), StoreError> {
+    let scanned = peek_log::<WalRecord>(wal)?;
+    let mut bytes = new_log();
+    for entry in scanned.entries {
+        encode_record(&entry.record, &mut bytes)?;
+    }
+
+    write_atomic(&lost_path(root, wal)?, &fs::read(wal)?, durability)?;
+    write_atomic(wal, &bytes, durability)?;
+
+    Ok(())
+}
+
+/// Where `path` goes under `LostFound/`, never an existing file. Creates the
+/// directory it goes into.
+fn lost_path(root: &Path, path: &Path) -> Result<PathBuf, StoreError> {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let lost = root.join("LostFound");
+    let mut target = lost.join(relative);
+    let mut attempt = 0;
+    while target.exists() {
+        attempt += 1;
+        target = lost.join(format!("{}.{}", relative.display(), attempt));
+    }
+
+    create_dir_all(target.parent().unwrap_or(&lost))?;
+
+    Ok(target)
+}
+
+/// Entries of `dir`, reporting temp files instead of returning them.
+fn list(dir: &Path, report: &mut FsckReport) -> Result<Vec<PathBuf>, StoreError> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut result = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_temp_file(&file_name(&path)) {
+            report.problems.push(Problem::TempFile { path });
+        } else {
+            result.push(path);
+        }
+    }
+    result.sort();
+
+    Ok(result)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Payload, Publish};
+    use session_store::format::{seal, HEADER_LEN};
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    fn publish(id: u16) -> Publish {
+        Publish {
+            packet_id: id,
+            retain: false,
+            topic_name: "fake".to_owned(),
+            payload: Payload {
+                id: id as u64,
+                bytes: Arc::new(vec![id as u8; 4]),
+            },
+        }
+    }
+
+    #[test]
+    fn test_check_and_repair() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let db = DB::new(root).expect("Make db");
+        for id in 1..=5 {
+            db.write("Session 1", publish(id)).expect("Publish");
+        }
+        drop(db);
+        assert!(check(root).unwrap().is_clean());
+
+        let payloads = root.join("Payloads");
+        let messages = root.join("Sessions").join("%53ession%201").join("Messages");
+        write_file(&payloads.join("junk"), b"");
+        write_file(&payloads.join("9.tmp"), b"");
+        write_file(&payloads.join("8"), &seal(&[8]));
+        write_file(&messages.join("0"), &[0xff]);
+        remove_file(payloads.join("2")).unwrap();
+        let mut bytes = fs::read(payloads.join("3")).unwrap();
+        bytes[HEADER_LEN] ^= 1;
+        write_file(&payloads.join("3"), &bytes);
+        remove_file(messages.join("3")).unwrap();
+
+        let report = check(root).unwrap();
+        assert_eq!(report.sessions, 1);
+        assert_eq!(report.messages, 4);
+        assert_eq!(report.payloads, 5);
+        let problems: Vec<String> = report.problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(report.problems.len(), 10, "{:#?}", problems);
+        let count = |f: fn(&Problem) -> bool| report.problems.iter().filter(|p| f(p)).count();
+        assert_eq!(count(|p| matches!(p, Problem::BadFileName { .. })), 1);
+        assert_eq!(count(|p| matches!(p, Problem::TempFile { .. })), 1);
+        assert_eq!(count(|p| matches!(p, Problem::UnreadableMessage { .. })), 1);
+        assert_eq!(
+            count(|p| matches!(p, Problem::DanglingReference { payload_id: 2, .. })),
+            1
+        );
+        assert_eq!(
+            count(|p| matches!(p, Problem::CorruptPayload { payload_id: 3, .. })),
+            1
+        );
+        // payload 1's only message is unreadable, 4's was deleted, 8 never had one
+        assert_eq!(count(|p| matches!(p, Problem::OrphanedPayload { .. })), 3);
+        assert_eq!(count(|p| matches!(p, Problem::RefcountMismatch { .. })), 2);
+
+        repair(root, Durability::None).expect("Repair");
+        let report = check(root).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(report.messages, 1);
+        assert_eq!(report.payloads, 1);
+        assert!(root
+            .join("LostFound")
+            .join("Payloads")
+            .join("junk")
+            .exists());
+
+        let db = DB::new(root).expect("Reopen db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![publish(5)]);
+    }
+
+    #[test]
+    fn test_write_ahead_log() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let db = DB::new(root).expect("Make db");
+        db.write("Session 1", publish(1)).expect("Publish");
+        db.checkpoint().expect("Checkpoint");
+        db.write("Session 1", publish(2)).expect("Publish");
+        db.ack("Session 1", 1).expect("Ack");
+        // crash before checkpointing
+        std::mem::forget(db);
+
+        // and a torn record at the end, which check leaves in place
+        let mut torn = fs::read(root.join("Wal")).unwrap();
+        torn.extend_from_slice(&[1, 2, 3]);
+        write_file(&root.join("Wal"), &torn);
+
+        // payload 1 looks orphaned until the log is applied
+        let report = check(root).unwrap();
+        assert!(matches!(
+            report.problems.as_slice(),
+            [Problem::UnappliedWal { records: 2 }]
+        ));
+        assert_eq!(fs::read(root.join("Wal")).unwrap(), torn);
+        repair(root, Durability::None).expect("Repair");
+        assert!(check(root).unwrap().is_clean());
+        assert_eq!(
+            DB::new(root).unwrap().read("Session 1").unwrap(),
+            vec![publish(2)]
+        );
+
+        // an unreadable log is set aside, and the records still readable
+        // in it are applied
+        let db = DB::new(root).expect("Reopen db");
+        db.write("Session 1", publish(3)).expect("Publish");
+        db.write("Session 1", publish(4)).expect("Publish");
+        std::mem::forget(db);
+        let mut bytes = fs::read(root.join("Wal")).unwrap();
+        // inside the first record, past its length and the length's checksum
+        bytes[HEADER_LEN + 12] ^= 1;
+        write_file(&root.join("Wal"), &bytes);
+
+        assert!(matches!(
+            check(root).unwrap().problems.as_slice(),
+            [Problem::UnreadableWal { .. }]
+        ));
+        repair(root, Durability::None).expect("Repair");
+        assert!(check(root).unwrap().is_clean());
+        assert_eq!(fs::read(root.join("LostFound").join("Wal")).unwrap(), bytes);
+        assert_eq!(
+            DB::new(root).unwrap().read("Session 1").unwrap(),
+            vec![publish(2), publish(4)]
+        );
+    }
+
+    #[test]
+    fn test_check_needs_current_version() {
+        let dir = tempdir().unwrap();
+        write_file(&dir.path().join("Sessions").join("Session 1"), b"");
+
+        assert!(matches!(
+            check(dir.path()),
+            Err(StoreError::UnsupportedVersion { version: 0, .. })
+        ));
+    }
+
+    fn write_file(path: &Path, bytes: &[u8]) {
+        create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, bytes).unwrap();
+    }
+}
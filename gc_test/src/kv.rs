@@ -0,0 +1,639 @@
+//! The same model as the file store, sessions of `DiskPublish` records
+//! referencing payloads by id, kept in a single redb database instead.
+//!
+//! Every call is one transaction, so there is no write-ahead log or
+//! checkpointing, and refcounts are updated along with the records that hold
+//! the references. Payloads are still only deleted by `clean`.
+
+use redb::{Database, ReadableTable, TableDefinition, WriteTransaction};
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+use session_store::format::{self, Migration};
+use session_store::{
+    encode_session_id, lock, CorruptionPolicy, Durability, Inspect, Payload, PayloadInfo, Publish,
+    SessionStore, StoreConfig, StoreError,
+};
+
+use crate::DiskPublish;
+
+/// Format version of the store written by this build, the first.
+pub const FORMAT_VERSION: u16 = 0;
+
+/// Upgrades from each older format version, see `format::migrate`.
+const MIGRATIONS: &[Migration] = &[];
+
+/// Encoded `DiskPublish` records by session id and sequence number.
+const PUBLISHES: TableDefinition<(&str, u64), &[u8]> = TableDefinition::new("publishes");
+const PAYLOADS: TableDefinition<u64, &[u8]> = TableDefinition::new("payloads");
+/// Payloads with a refcount of zero are deleted by the next `clean`.
+const REFCOUNTS: TableDefinition<u64, u64> = TableDefinition::new("refcounts");
+/// Records that failed to decode, moved out of `PUBLISHES` by
+/// `CorruptionPolicy::Quarantine`.
+const CORRUPT: TableDefinition<(&str, u64), &[u8]> = TableDefinition::new("corrupt");
+
+pub struct DB {
+    path: PathBuf,
+    db: Database,
+    durability: redb::Durability,
+    on_corrupt: CorruptionPolicy,
+    loaded_payloads: Mutex<HashMap<u64, Weak<Vec<u8>>>>,
+}
+
+impl DB {
+    pub fn new(location: &Path) -> Result<Self, StoreError> {
+        Self::with_config(location, StoreConfig::default())
+    }
+
+    pub fn with_durability(location: &Path, durability: Durability) -> Result<Self, StoreError> {
+        let config = StoreConfig {
+            durability,
+            ..StoreConfig::default()
+        };
+        Self::with_config(location, config)
+    }
+
+    /// Creates `location` if needed, keeping the database in a single file
+    /// inside it.
+    pub fn with_config(location: &Path, config: StoreConfig) -> Result<Self, StoreError> {
+        if !location.exists() {
+            create_dir_all(location)?;
+        }
+        format::migrate(location, config.durability, FORMAT_VERSION, MIGRATIONS)?;
+
+        let path = location.join("Store.redb");
+        let db = Database::create(&path).map_err(|e| redb_error(&path, e))?;
+        // redb only persists commits without durability once a later one has it
+        let durability = match config.durability {
+            Durability::None | Durability::Flush => redb::Durability::Eventual,
+            Durability::Fsync => redb::Durability::Immediate,
+        };
+
+        Ok(DB {
+            path,
+            db,
+            durability,
+            on_corrupt: config.on_corrupt,
+            loaded_payloads: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn write(&self, session_id: &str, publish: Publish) -> Result<(), StoreError> {
+        self.write_batch(&[(session_id, publish)])
+    }
+
+    /// Queues `publish` for each of `session_ids`, storing its payload once.
+    pub fn write_fanout(&self, publish: Publish, session_ids: &[&str]) -> Result<(), StoreError> {
+        let writes: Vec<(&str, Publish)> = session_ids
+            .iter()
+            .map(|session_id| (*session_id, publish.clone()))
+            .collect();
+        self.write_batch(&writes)
+    }
+
+    /// Queues several messages, in order, possibly to different sessions, in
+    /// one transaction.
+    pub fn write_batch(&self, writes: &[(&str, Publish)]) -> Result<(), StoreError> {
+        for (session_id, _) in writes {
+            encode_session_id(session_id)?;
+        }
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        self.update(|txn| {
+            let mut publishes = txn.open_table(PUBLISHES)?;
+            let mut payloads = txn.open_table(PAYLOADS)?;
+            let mut refcounts = txn.open_table(REFCOUNTS)?;
+
+            let mut next_sequences: HashMap<&str, u64> = HashMap::new();
+            for (session_id, publish) in writes {
+                let payload_id = publish.payload.id;
+                if payloads.get(payload_id)?.is_none() {
+                    payloads.insert(payload_id, publish.payload.bytes.as_slice())?;
+                }
+                let count = refcounts.get(payload_id)?.map_or(0, |count| count.value());
+                refcounts.insert(payload_id, count + 1)?;
+
+                let sequence = match next_sequences.get(session_id) {
+                    Some(sequence) => *sequence,
+                    None => publishes
+                        .range((*session_id, 0)..=(*session_id, u64::MAX))?
+                        .next_back()
+                        .transpose()?
+                        .map_or(0, |(key, _)| key.value().1 + 1),
+                };
+                next_sequences.insert(session_id, sequence + 1);
+
+                let body = DiskPublish {
+                    packet_id: publish.packet_id,
+                    retain: publish.retain,
+                    topic_name: publish.topic_name.clone(),
+                    payload_id,
+                };
+                let bytes = bincode::serialize(&body).map_err(|e| TxnError::Store(e.into()))?;
+                publishes.insert((*session_id, sequence), bytes.as_slice())?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Reads every message in a session. Records that don't decode or whose
+    /// payload is missing are handled as the store's `CorruptionPolicy` says.
+    pub fn read(&self, session_id: &str) -> Result<Vec<Publish>, StoreError> {
+        encode_session_id(session_id)?;
+
+        let txn = self.db.begin_read().map_err(|e| self.error(e))?;
+        let publishes = match txn.open_table(PUBLISHES) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(self.error(e)),
+        };
+        let payloads = txn.open_table(PAYLOADS).map_err(|e| self.error(e))?;
+
+        let mut result = Vec::new();
+        let mut dropped = Vec::new();
+        let records = publishes
+            .range((session_id, 0)..=(session_id, u64::MAX))
+            .map_err(|e| self.error(e))?;
+        for record in records {
+            let (key, value) = record.map_err(|e| self.error(e))?;
+            let sequence = key.value().1;
+
+            let error = match bincode::deserialize::<DiskPublish>(value.value()) {
+                Ok(body) => match self.get_payload(&payloads, body.payload_id)? {
+                    Some(bytes) => {
+                        result.push(Publish {
+                            packet_id: body.packet_id,
+                            retain: body.retain,
+                            topic_name: body.topic_name,
+                            payload: Payload {
+                                id: body.payload_id,
+                                bytes,
+                            },
+                        });
+                        continue;
+                    }
+                    None => StoreError::MissingPayload {
+                        payload_id: body.payload_id,
+                    },
+                },
+                Err(e) => StoreError::corrupt(&self.path, sequence, e),
+            };
+
+            if self.on_corrupt == CorruptionPolicy::Error {
+                return Err(error);
+            }
+            dropped.push(sequence);
+        }
+        drop(txn);
+
+        if !dropped.is_empty() && self.on_corrupt == CorruptionPolicy::Quarantine {
+            self.update(|txn| {
+                let mut publishes = txn.open_table(PUBLISHES)?;
+                let mut corrupt = txn.open_table(CORRUPT)?;
+                for sequence in dropped.iter() {
+                    let removed = publishes.remove((session_id, *sequence))?;
+                    if let Some(value) = removed {
+                        corrupt.insert((session_id, *sequence), value.value())?;
+                    }
+                }
+
+                Ok(())
+            })?;
+        }
+
+        Ok(result)
+    }
+
+    /// Removes the queued message with the given packet id, e.g. once its
+    /// delivery has been acknowledged. Unknown packet ids are ignored.
+    pub fn ack(&self, session_id: &str, packet_id: u16) -> Result<(), StoreError> {
+        self.remove_first(session_id, |body| body.packet_id == packet_id)
+    }
+
+    /// Removes the oldest queued message referencing the given payload. The
+    /// payload itself is deleted by the next `clean` once nothing references it.
+    pub fn remove(&self, session_id: &str, payload_id: u64) -> Result<(), StoreError> {
+        self.remove_first(session_id, |body| body.payload_id == payload_id)
+    }
+
+    /// Deletes every payload no longer referenced by a session.
+    pub fn clean(&self) -> Result<(), StoreError> {
+        self.update(|txn| {
+            let mut payloads = txn.open_table(PAYLOADS)?;
+            let mut refcounts = txn.open_table(REFCOUNTS)?;
+
+            let released: Vec<u64> = refcounts
+                .extract_if(|_, count| count == 0)?
+                .map(|entry| entry.map(|(payload_id, _)| payload_id.value()))
+                .collect::<Result<_, _>>()?;
+            for payload_id in released {
+                payloads.remove(payload_id)?;
+            }
+
+            Ok(())
+        })?;
+        lock(&self.loaded_payloads).retain(|_, val| val.strong_count() > 0);
+
+        Ok(())
+    }
+
+    fn remove_first<F>(&self, session_id: &str, matches: F) -> Result<(), StoreError>
+    where
+        F: Fn(&DiskPublish) -> bool,
+    {
+        encode_session_id(session_id)?;
+
+        self.update(|txn| {
+            let mut publishes = txn.open_table(PUBLISHES)?;
+            let mut refcounts = txn.open_table(REFCOUNTS)?;
+
+            let mut found = None;
+            for record in publishes.range((session_id, 0)..=(session_id, u64::MAX))? {
+                let (key, value) = record?;
+                // Undecodable records are left to `read` to report
+                if let Ok(body) = bincode::deserialize::<DiskPublish>(value.value()) {
+                    if matches(&body) {
+                        found = Some((key.value().1, body.payload_id));
+                        break;
+                    }
+                }
+            }
+
+            if let Some((sequence, payload_id)) = found {
+                publishes.remove((session_id, sequence))?;
+                release(&mut refcounts, payload_id)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_payload(
+        &self,
+        payloads: &redb::ReadOnlyTable<u64, &'static [u8]>,
+        payload_id: u64,
+    ) -> Result<Option<Arc<Vec<u8>>>, StoreError> {
+        let mut loaded_payloads = lock(&self.loaded_payloads);
+        if let Some(payload) = loaded_payloads
+            .get(&payload_id)
+            .and_then(|payload| payload.upgrade())
+        {
+            return Ok(Some(payload));
+        }
+
+        match payloads.get(payload_id).map_err(|e| self.error(e))? {
+            Some(bytes) => {
+                let payload = Arc::new(bytes.value().to_vec());
+                loaded_payloads.insert(payload_id, Arc::downgrade(&payload));
+                Ok(Some(payload))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Runs `f` in a write transaction, committing it if `f` succeeds.
+    fn update<F>(&self, f: F) -> Result<(), StoreError>
+    where
+        F: FnOnce(&WriteTransaction) -> Result<(), TxnError>,
+    {
+        let mut txn = self.db.begin_write().map_err(|e| self.error(e))?;
+        txn.set_durability(self.durability);
+
+        match f(&txn) {
+            Ok(()) => txn.commit().map_err(|e| self.error(e)),
+            Err(TxnError::Store(e)) => Err(e),
+            Err(TxnError::Redb(e)) => Err(redb_error(&self.path, *e)),
+        }
+    }
+
+    fn error(&self, error: impl Into<redb::Error>) -> StoreError {
+        redb_error(&self.path, error)
+    }
+}
+
+/// Error from inside a transaction, which is aborted when it is dropped.
+enum TxnError {
+    Store(StoreError),
+    Redb(Box<redb::Error>),
+}
+
+impl From<redb::StorageError> for TxnError {
+    fn from(error: redb::StorageError) -> Self {
+        TxnError::Redb(Box::new(error.into()))
+    }
+}
+
+impl From<redb::TableError> for TxnError {
+    fn from(error: redb::TableError) -> Self {
+        TxnError::Redb(Box::new(error.into()))
+    }
+}
+
+fn redb_error(path: &Path, error: impl Into<redb::Error>) -> StoreError {
+    match error.into() {
+        redb::Error::Io(e) => StoreError::Io(e),
+        redb::Error::Corrupted(reason) => StoreError::corrupt(path, 0, reason),
+        e => StoreError::Io(io::Error::other(e)),
+    }
+}
+
+fn release(
+    refcounts: &mut redb::Table<'_, u64, u64>,
+    payload_id: u64,
+) -> Result<(), redb::StorageError> {
+    let count = refcounts.get(payload_id)?.map_or(0, |count| count.value());
+    refcounts.insert(payload_id, count.saturating_sub(1))?;
+
+    Ok(())
+}
+
+impl SessionStore for DB {
+    fn open(location: &Path) -> Result<Self, StoreError> {
+        Self::new(location)
+    }
+
+    fn append(&self, session_id: &str, publish: Publish) -> Result<(), StoreError> {
+        self.write(session_id, publish)
+    }
+
+    fn append_batch(&self, writes: &[(&str, Publish)]) -> Result<(), StoreError> {
+        self.write_batch(writes)
+    }
+
+    fn read(&self, session_id: &str) -> Result<Vec<Publish>, StoreError> {
+        self.read(session_id)
+    }
+
+    fn ack(&self, session_id: &str, packet_id: u16) -> Result<(), StoreError> {
+        self.ack(session_id, packet_id)
+    }
+
+    fn remove_session(&self, session_id: &str) -> Result<(), StoreError> {
+        encode_session_id(session_id)?;
+
+        self.update(|txn| {
+            let mut publishes = txn.open_table(PUBLISHES)?;
+            let mut refcounts = txn.open_table(REFCOUNTS)?;
+
+            let removed: Vec<Vec<u8>> = publishes
+                .extract_from_if((session_id, 0)..=(session_id, u64::MAX), |_, _| true)?
+                .map(|entry| entry.map(|(_, value)| value.value().to_vec()))
+                .collect::<Result<_, _>>()?;
+            for value in removed {
+                if let Ok(body) = bincode::deserialize::<DiskPublish>(&value) {
+                    release(&mut refcounts, body.payload_id)?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Only sessions with queued messages are listed.
+    fn list_sessions(&self) -> Result<Vec<String>, StoreError> {
+        let txn = self.db.begin_read().map_err(|e| self.error(e))?;
+        let publishes = match txn.open_table(PUBLISHES) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(self.error(e)),
+        };
+
+        let mut session_ids: Vec<String> = Vec::new();
+        for record in publishes.iter().map_err(|e| self.error(e))? {
+            let (key, _) = record.map_err(|e| self.error(e))?;
+            let session_id = key.value().0;
+            if session_ids.last().map(String::as_str) != Some(session_id) {
+                session_ids.push(session_id.to_owned());
+            }
+        }
+
+        Ok(session_ids)
+    }
+
+    fn clean(&self) -> Result<(), StoreError> {
+        self.clean()
+    }
+}
+
+impl Inspect for DB {
+    /// Payloads are keyed by id. Their stored size is that of their bytes,
+    /// leaving out redb's own overhead.
+    fn payloads(&self) -> Result<Vec<PayloadInfo>, StoreError> {
+        let txn = self.db.begin_read().map_err(|e| self.error(e))?;
+        let (payloads, refcounts) = match (txn.open_table(PAYLOADS), txn.open_table(REFCOUNTS)) {
+            (Ok(payloads), Ok(refcounts)) => (payloads, refcounts),
+            (Err(redb::TableError::TableDoesNotExist(_)), _) => return Ok(Vec::new()),
+            (Err(e), _) | (_, Err(e)) => return Err(self.error(e)),
+        };
+
+        let mut result = Vec::new();
+        for entry in payloads.iter().map_err(|e| self.error(e))? {
+            let (payload_id, bytes) = entry.map_err(|e| self.error(e))?;
+            let payload_id = payload_id.value();
+            let refcount = refcounts
+                .get(payload_id)
+                .map_err(|e| self.error(e))?
+                .map_or(0, |count| count.value());
+            result.push(PayloadInfo {
+                key: payload_id.to_string(),
+                stored_bytes: bytes.value().len() as u64,
+                refcount,
+            });
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use session_store::format::Header;
+    use session_store::testing;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_read_write() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
+        db.write("Session 1", publish.clone()).expect("Publish 1");
+
+        assert_eq!(db.read("Session 1").unwrap(), vec![publish.clone()]);
+        assert!(db.read("Session 2").unwrap().is_empty());
+
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![publish]);
+        assert!(matches!(
+            db.write("", Faker::new().make_fake_publish(vec![1])),
+            Err(StoreError::InvalidSessionId(_))
+        ));
+    }
+
+    #[test]
+    fn test_ack_and_clean() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let first = faker.make_fake_publish(vec![1]);
+        let second = faker.make_fake_publish(vec![2]);
+        db.write_fanout(first.clone(), &["Session 1", "Session 2"])
+            .expect("Publish 1");
+        db.write("Session 1", second.clone()).expect("Publish 2");
+
+        db.ack("Session 1", first.packet_id).unwrap();
+        db.ack("Session 1", 9999).unwrap();
+        assert_eq!(db.read("Session 1").unwrap(), vec![second.clone()]);
+
+        db.remove("Session 2", first.payload.id).unwrap();
+        let refcounts: Vec<u64> = db.payloads().unwrap().iter().map(|p| p.refcount).collect();
+        assert_eq!(refcounts, vec![0, 1]);
+
+        db.clean().unwrap();
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.payloads().unwrap().len(), 1);
+        assert_eq!(db.list_sessions().unwrap(), vec!["Session 1"]);
+        assert_eq!(db.read("Session 1").unwrap(), vec![second]);
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let dir = tempdir().unwrap();
+        let db = DB::new(dir.path()).expect("Make db");
+        let mut faker = Faker::new();
+
+        let shared = faker.make_fake_publish(vec![1, 2, 3]);
+        let other = faker.make_fake_publish(vec![4]);
+        db.write_batch(&[
+            ("Session 1", shared.clone()),
+            ("Session 2", other.clone()),
+            ("Session 1", shared.clone()),
+        ])
+        .expect("Batch");
+
+        assert_eq!(
+            db.read("Session 1").unwrap(),
+            vec![shared.clone(), shared.clone()]
+        );
+        assert_eq!(db.read("Session 2").unwrap(), vec![other]);
+        assert_eq!(db.payloads().unwrap()[0].refcount, 2);
+
+        // an invalid session id fails the whole batch
+        assert!(db.write_fanout(shared, &["Session 3", ""]).is_err());
+        assert_eq!(db.list_sessions().unwrap().len(), 2);
+
+        db.remove_session("Session 1").unwrap();
+        assert_eq!(db.payloads().unwrap()[0].refcount, 0);
+        assert_eq!(db.list_sessions().unwrap(), vec!["Session 2"]);
+    }
+
+    #[test]
+    fn test_corrupt_record() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let kept = faker.make_fake_publish(vec![1]);
+        db.write("Session 1", kept.clone()).expect("Publish");
+        db.update(|txn| {
+            txn.open_table(PUBLISHES)?
+                .insert(("Session 1", 1), [0xffu8].as_slice())?;
+            Ok(())
+        })
+        .unwrap();
+
+        assert!(matches!(
+            db.read("Session 1"),
+            Err(StoreError::Corrupt { offset: 1, .. })
+        ));
+        drop(db);
+
+        let config = StoreConfig {
+            on_corrupt: CorruptionPolicy::Quarantine,
+            ..StoreConfig::default()
+        };
+        let db = DB::with_config(path, config).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![kept.clone()]);
+        drop(db);
+
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![kept]);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+
+        // Another store's files are left alone
+        drop(crate::DB::new(path).expect("Make gc db"));
+        assert!(matches!(
+            DB::new(path),
+            Err(StoreError::UnsupportedVersion { version, .. }) if version == crate::FORMAT_VERSION
+        ));
+        assert!(!path.join("Store.redb").exists());
+
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        drop(DB::new(path).expect("Make db"));
+        let newer = Header {
+            version: FORMAT_VERSION + 1,
+            ..Header::current()
+        };
+        std::fs::write(path.join("Format"), newer.encode()).unwrap();
+        assert!(matches!(
+            DB::new(path),
+            Err(StoreError::UnsupportedVersion { version, .. }) if version == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn test_concurrent_sessions() {
+        let dir = tempdir().unwrap();
+        let db = DB::with_durability(dir.path(), Durability::None).expect("Make db");
+        testing::check_concurrent_sessions(&db, 20);
+    }
+
+    struct Faker {
+        packet_id: u16,
+        payload_id: u64,
+    }
+
+    impl Faker {
+        fn new() -> Self {
+            Faker {
+                packet_id: 100,
+                payload_id: 1000,
+            }
+        }
+
+        fn make_fake_publish(&mut self, payload: Vec<u8>) -> Publish {
+            self.packet_id += 2;
+            self.payload_id += 1;
+
+            Publish {
+                packet_id: self.packet_id,
+                payload: Payload {
+                    id: self.payload_id,
+                    bytes: Arc::new(payload),
+                },
+                retain: true,
+                topic_name: "fake".to_owned(),
+            }
+        }
+    }
+}
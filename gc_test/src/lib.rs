@@ -12,45 +12,509 @@ use std::path::*;
 use std::sync::*;
 use std::*;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct Publish {
-    pub packet_id: u16,
-    pub retain: bool,
-    pub topic_name: String,
-    pub payload: Payload,
+use std::time::{Duration, Instant};
+
+use session_store::format::{
+    self, add_v1_header, encode_session_names, escape_uppercase_names, reseal_v1, seal,
+    seal_raw_v1, unseal, Migration, HEADER_LEN,
+};
+use session_store::log::{
+    append_log, encode_record, new_log, peek_log, read_log, upgrade_log_v1, upgrade_log_v2,
+};
+use session_store::wal::Wal;
+use session_store::{
+    encode_session_id, is_temp_file, list_session_ids, lock, remove_session_id, save_session_id,
+    sync_dir, write_append, write_atomic,
+};
+
+#[cfg(feature = "tokio")]
+pub use session_store::AsyncStore;
+pub use session_store::{
+    CorruptionPolicy, Durability, Inspect, Payload, PayloadInfo, Publish, SessionStore,
+    StoreConfig, StoreError,
+};
+pub use worker::{GcConfig, GcStats, GcWorker};
+
+pub mod fsck;
+#[cfg(feature = "redb")]
+pub mod kv;
+mod worker;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct DiskPublish {
+    packet_id: u16,
+    retain: bool,
+    topic_name: String,
+    payload_id: u64,
+}
+
+/// The refcount log is compacted once it holds this many records and at least
+/// twice as many as there are payloads.
+const MIN_COMPACTION_RECORDS: usize = 1024;
+
+/// Entry in the append-only refcount log; the last entry for a payload wins.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+enum RefRecord {
+    Count { payload_id: u64, count: u64 },
+    Deleted { payload_id: u64 },
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct Payload {
-    pub bytes: Arc<Vec<u8>>,
-    pub id: u64,
+/// The write-ahead log is checkpointed once it holds this many records.
+const MAX_WAL_RECORDS: usize = 1024;
+
+/// Entry in the write-ahead log. Replaying the log in order redoes every
+/// change not yet made to the message and payload files.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum WalRecord {
+    /// A new message, with its payload's bytes if nothing referenced the
+    /// payload before.
+    Write {
+        session_id: String,
+        sequence: u64,
+        body: DiskPublish,
+        payload: Option<Arc<Vec<u8>>>,
+    },
+    Remove {
+        session_id: String,
+        sequence: u64,
+    },
+    RemoveSession {
+        session_id: String,
+    },
+    /// Messages written together by `DB::write_batch`.
+    Batch(Vec<WalRecord>),
 }
 
-impl Clone for Payload {
-    fn clone(&self) -> Self {
-        Payload {
-            id: self.id,
-            bytes: self.bytes.clone(),
+/// Changes committed to the write-ahead log but not yet made to the files,
+/// waiting for `DB::checkpoint`.
+#[derive(Debug, Default)]
+struct Pending {
+    /// Messages without a file yet, by session and sequence number.
+    messages: HashMap<String, BTreeMap<u64, DiskPublish>>,
+    /// Message files to delete, by session and sequence number.
+    removed: HashMap<String, BTreeSet<u64>>,
+    /// Payloads first referenced by a message without a file yet.
+    payloads: HashMap<u64, Arc<Vec<u8>>>,
+    /// Sessions removed and not written to since, whose directories are only
+    /// left in place by a read-only store.
+    removed_sessions: HashSet<String>,
+}
+
+impl Pending {
+    /// Records a change committed to the write-ahead log. Removing a session's
+    /// directory is left to the caller.
+    fn apply(&mut self, record: WalRecord) {
+        match record {
+            WalRecord::Write {
+                session_id,
+                sequence,
+                body,
+                payload,
+            } => {
+                if let Some(bytes) = payload {
+                    self.payloads.insert(body.payload_id, bytes);
+                }
+                self.removed_sessions.remove(&session_id);
+                self.messages
+                    .entry(session_id)
+                    .or_default()
+                    .insert(sequence, body);
+            }
+            WalRecord::Remove {
+                session_id,
+                sequence,
+            } => self.remove(&session_id, sequence),
+            WalRecord::RemoveSession { session_id } => self.remove_session(&session_id),
+            WalRecord::Batch(records) => {
+                for record in records {
+                    self.apply(record);
+                }
+            }
         }
     }
+
+    fn remove(&mut self, session_id: &str, sequence: u64) {
+        let written = self
+            .messages
+            .get_mut(session_id)
+            .and_then(|messages| messages.remove(&sequence))
+            .is_none();
+        if written {
+            self.removed
+                .entry(session_id.to_owned())
+                .or_default()
+                .insert(sequence);
+        }
+    }
+
+    fn remove_session(&mut self, session_id: &str) {
+        self.messages.remove(session_id);
+        self.removed.remove(session_id);
+        self.removed_sessions.insert(session_id.to_owned());
+    }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-struct DiskPublish {
-    packet_id: u16,
-    retain: bool,
-    topic_name: String,
-    payload_id: u64,
+/// A queued message, either in its file or still pending.
+enum Message {
+    File(PathBuf),
+    Pending(DiskPublish),
+}
+
+impl Message {
+    fn body(self) -> Result<DiskPublish, StoreError> {
+        match self {
+            Message::File(path) => DB::read_body(&path),
+            Message::Pending(body) => Ok(body),
+        }
+    }
+}
+
+/// Work done by a single garbage collection pass.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CleanStats {
+    pub payloads_deleted: u64,
+    pub bytes_reclaimed: u64,
+    pub duration: Duration,
+    /// Unreferenced payloads left for a later pass.
+    pub remaining: usize,
 }
 
+/// Number of session messages referencing each stored payload, mirrored to an
+/// append-only log so it survives restarts. Changes are buffered until
+/// `flush`, as the write-ahead log can redo them after a crash.
+struct Refcounts {
+    log: PathBuf,
+    durability: Durability,
+    counts: HashMap<u64, u64>,
+    /// Stored payloads with no references left, waiting for `clean`.
+    garbage: BTreeSet<u64>,
+    /// Records in the log file.
+    records: usize,
+    /// Encoded records not yet appended to the log file.
+    unflushed: Vec<u8>,
+    unflushed_records: usize,
+}
+
+impl Refcounts {
+    fn new(log: PathBuf, durability: Durability) -> Self {
+        Refcounts {
+            log,
+            durability,
+            counts: HashMap::new(),
+            garbage: BTreeSet::new(),
+            records: 0,
+            unflushed: Vec::new(),
+            unflushed_records: 0,
+        }
+    }
+
+    fn load(log: PathBuf, durability: Durability) -> Result<Self, StoreError> {
+        let records = read_log(&log)?;
+        Ok(Self::from_records(log, durability, records))
+    }
+
+    /// Like `load`, but leaves a torn record at the end of the log in place.
+    fn peek(log: PathBuf) -> Result<Self, StoreError> {
+        let records = peek_log(&log)?.into_records()?;
+        Ok(Self::from_records(log, Durability::None, records))
+    }
+
+    fn from_records(
+        log: PathBuf,
+        durability: Durability,
+        records: Vec<(RefRecord, usize)>,
+    ) -> Self {
+        let mut refcounts = Self::new(log, durability);
+
+        for (record, _) in records {
+            match record {
+                RefRecord::Count { payload_id, count } => {
+                    refcounts.counts.insert(payload_id, count);
+                }
+                RefRecord::Deleted { payload_id } => {
+                    refcounts.counts.remove(&payload_id);
+                }
+            }
+            refcounts.records += 1;
+        }
+        refcounts.find_garbage();
+
+        refcounts
+    }
+
+    fn contains(&self, payload_id: u64) -> bool {
+        self.counts.contains_key(&payload_id)
+    }
+
+    fn add(&mut self, payload_id: u64) -> Result<(), StoreError> {
+        let count = self.counts.get(&payload_id).map_or(0, |c| *c) + 1;
+        self.set(payload_id, count)
+    }
+
+    fn release(&mut self, payload_id: u64) -> Result<(), StoreError> {
+        match self.counts.get(&payload_id) {
+            Some(count) if *count > 0 => self.set(payload_id, count - 1),
+            _ => Ok(()),
+        }
+    }
+
+    fn forget(&mut self, payload_id: u64) -> Result<(), StoreError> {
+        if self.counts.remove(&payload_id).is_some() {
+            self.garbage.remove(&payload_id);
+            self.append(&RefRecord::Deleted { payload_id })?;
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, payload_id: u64, count: u64) -> Result<(), StoreError> {
+        self.append(&RefRecord::Count { payload_id, count })?;
+
+        self.counts.insert(payload_id, count);
+        if count == 0 {
+            self.garbage.insert(payload_id);
+        } else {
+            self.garbage.remove(&payload_id);
+        }
+
+        Ok(())
+    }
+
+    fn append(&mut self, record: &RefRecord) -> Result<(), StoreError> {
+        encode_record(record, &mut self.unflushed)?;
+        self.unflushed_records += 1;
+
+        Ok(())
+    }
+
+    /// Appends the buffered records to the log file in one write.
+    fn flush(&mut self) -> Result<(), StoreError> {
+        if self.unflushed.is_empty() {
+            return Ok(());
+        }
+
+        append_log(&self.log, &self.unflushed, self.durability)?;
+        self.records += self.unflushed_records;
+        self.unflushed.clear();
+        self.unflushed_records = 0;
+
+        if self.records >= MIN_COMPACTION_RECORDS && self.records >= 2 * self.counts.len() {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the log with a single record per stored payload, which also
+    /// covers anything buffered.
+    fn compact(&mut self) -> Result<(), StoreError> {
+        let mut bytes = new_log();
+        for (payload_id, count) in self.counts.iter() {
+            let record = RefRecord::Count {
+                payload_id: *payload_id,
+                count: *count,
+            };
+            encode_record(&record, &mut bytes)?;
+        }
+        write_atomic(&self.log, &bytes, self.durability)?;
+
+        self.records = self.counts.len();
+        self.unflushed.clear();
+        self.unflushed_records = 0;
+        self.find_garbage();
+
+        Ok(())
+    }
+
+    fn find_garbage(&mut self) {
+        self.garbage = self
+            .counts
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(payload_id, _)| *payload_id)
+            .collect();
+    }
+}
+
+/// Format version of the store written by this build. Version 0 is the
+/// unversioned layout, with raw session ids as directory names and no
+/// headers. Version 1 added headers, version 2 checksums, and version 3
+/// escaped uppercase letters in directory names and hashed long ones. Version
+/// 4 checksums the length of every log record.
+pub const FORMAT_VERSION: u16 = 4;
+
+/// Upgrades from each older format version, see `format::migrate`.
+const MIGRATIONS: &[Migration] = &[migrate_v0, migrate_v1, migrate_v2, migrate_v3];
+
+/// Encodes session directory names and adds headers to message files and the
+/// refcount log. Payload files hold raw bytes and are left as they are.
+fn migrate_v0(root: &Path, durability: Durability) -> Result<(), StoreError> {
+    let add_message_headers = |session: &Path| {
+        upgrade_files(&session.join("Messages"), |path| {
+            add_v1_header(path, durability)
+        })
+    };
+    encode_session_names(&root.join("Sessions"), durability, add_message_headers)?;
+    encode_session_names(&root.join("Corrupt"), durability, |_| Ok(()))?;
+
+    let refcounts = root.join("Refcounts");
+    if refcounts.exists() {
+        add_v1_header(&refcounts, durability)?;
+    }
+
+    Ok(())
+}
+
+/// Adds checksums to message files, payload files and the refcount log.
+/// Quarantined messages are kept as they were found.
+fn migrate_v1(root: &Path, durability: Durability) -> Result<(), StoreError> {
+    let sessions = root.join("Sessions");
+    if sessions.exists() {
+        for entry in read_dir(&sessions)? {
+            let session = entry?.path();
+            upgrade_files(&session.join("Messages"), |path| {
+                reseal_v1(path, durability)
+            })?;
+        }
+    }
+    upgrade_files(&root.join("Payloads"), |path| seal_raw_v1(path, durability))?;
+
+    let refcounts = root.join("Refcounts");
+    if refcounts.exists() {
+        upgrade_log_v1(&refcounts, durability)?;
+    }
+
+    Ok(())
+}
+
+/// Renames session and quarantine directories so ids differing only in case
+/// can't share one on a case-insensitive filesystem.
+fn migrate_v2(root: &Path, durability: Durability) -> Result<(), StoreError> {
+    escape_uppercase_names(&root.join("Sessions"), durability)?;
+    escape_uppercase_names(&root.join("Corrupt"), durability)
+}
+
+/// Adds checksums to the record lengths of the refcount and write-ahead logs.
+fn migrate_v3(root: &Path, durability: Durability) -> Result<(), StoreError> {
+    for log in [root.join("Refcounts"), root.join("Wal")] {
+        if log.exists() {
+            upgrade_log_v2(&log, durability)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `upgrade` on every file in `dir`, skipping temp files.
+fn upgrade_files<F>(dir: &Path, upgrade: F) -> Result<(), StoreError>
+where
+    F: Fn(&Path) -> Result<(), StoreError>,
+{
+    if dir.exists() {
+        for entry in read_dir(dir)? {
+            let path = entry?.path();
+            if !is_temp_file(&path.file_name().unwrap_or_default().to_string_lossy()) {
+                upgrade(&path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A message left out of a read.
+#[derive(Debug)]
+pub struct SkippedMessage {
+    pub path: PathBuf,
+    pub error: StoreError,
+    /// Where the message file was moved to, if it was quarantined.
+    pub quarantined: Option<PathBuf>,
+}
+
+/// Result of reading a session, including the messages that couldn't be read.
+#[derive(Debug, Default)]
+pub struct ReadReport {
+    pub publishes: Vec<Publish>,
+    pub skipped: Vec<SkippedMessage>,
+}
+
+/// In-memory state of a session, guarded by the session's lock.
+#[derive(Debug, Default)]
+struct SessionState {
+    next_sequence: Option<u64>,
+}
+
+/// A session's lock, dropped from `DB::session_states` by the last user once
+/// nothing is cached in it, so ids that are only read or removed don't pile up.
+struct Session<'a> {
+    db: &'a DB,
+    session_id: &'a str,
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl ops::Deref for Session<'_> {
+    type Target = Mutex<SessionState>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        let mut states = lock(&self.db.session_states);
+        // Only the map and this handle hold it, so nobody has it locked
+        if Arc::strong_count(&self.state) == 2 && lock(&self.state).next_sequence.is_none() {
+            states.remove(self.session_id);
+        }
+    }
+}
+
+/// Safe to share between threads. Each session has its own lock, but every
+/// write also takes the store-wide refcount lock to count its payload
+/// references and goes through the shared write-ahead log, so writes to
+/// different sessions only partly run in parallel. Reads of different sessions
+/// do run in parallel.
+///
+/// Writes, acks and session removals are committed to a write-ahead log, with
+/// those from different threads synced together, and only made to the
+/// message and payload files by `checkpoint`. A message acked before then
+/// never gets a file at all.
 pub struct DB {
     payloads: PathBuf,
     sessions: PathBuf,
-    loaded_payloads: HashMap<u64, Weak<Vec<u8>>>,
+    corrupt: PathBuf,
+    durability: Durability,
+    on_corrupt: CorruptionPolicy,
+    loaded_payloads: Mutex<HashMap<u64, Weak<Vec<u8>>>>,
+    session_states: Mutex<HashMap<String, Arc<Mutex<SessionState>>>>,
+    refcounts: Mutex<Refcounts>,
+    wal: Wal,
+    /// Held shared while committing to the write-ahead log and updating
+    /// `pending` to match, or while reading the files and `pending` together,
+    /// and exclusively by `checkpoint`.
+    checkpoint_lock: RwLock<()>,
+    pending: Mutex<Pending>,
+    /// Set by `open_read_only`, which leaves every file as it is.
+    read_only: bool,
 }
 
 impl DB {
-    pub fn new(location: &Path) -> Result<Self, Box<dyn Error>> {
+    pub fn new(location: &Path) -> Result<Self, StoreError> {
+        Self::with_config(location, StoreConfig::default())
+    }
+
+    pub fn with_durability(location: &Path, durability: Durability) -> Result<Self, StoreError> {
+        let config = StoreConfig {
+            durability,
+            ..StoreConfig::default()
+        };
+        Self::with_config(location, config)
+    }
+
+    pub fn with_config(location: &Path, config: StoreConfig) -> Result<Self, StoreError> {
+        format::migrate(location, config.durability, FORMAT_VERSION, MIGRATIONS)?;
+
         let payloads = location.join("Payloads");
         let sessions = location.join("Sessions");
 
@@ -60,98 +524,647 @@ impl DB {
         if !sessions.exists() {
             create_dir(&sessions)?;
         }
-        Ok(DB {
-            payloads,
-            sessions,
-            loaded_payloads: HashMap::new(),
-        })
+
+        Self::load(location, config, false)
+    }
+
+    /// Opens an existing store to look at without changing any of its files.
+    /// It isn't upgraded, and fails if it needs to be. A write-ahead log left
+    /// by a crash is read but not checkpointed, and writes fail with
+    /// `StoreError::ReadOnly`.
+    pub fn open_read_only(location: &Path) -> Result<Self, StoreError> {
+        format::check_version(location, FORMAT_VERSION)?;
+
+        Self::load(location, StoreConfig::default(), true)
     }
 
-    pub fn write(&mut self, session_id: &str, publish: Publish) -> Result<(), Box<dyn Error>> {
-        let payload = publish.payload;
+    fn load(location: &Path, config: StoreConfig, read_only: bool) -> Result<Self, StoreError> {
+        let durability = config.durability;
+        let wal = Wal::new(location.join("Wal"), durability);
+        // Only left behind by a crash, and the refcount log may be missing
+        // some of what it redoes
+        let replay = wal.path().exists();
+
+        let refcount_log = location.join("Refcounts");
+        let loaded = if read_only {
+            Refcounts::peek(refcount_log.clone())
+        } else {
+            Refcounts::load(refcount_log.clone(), durability)
+        };
+        // Refcounts can always be recomputed, so a damaged log is just replaced
+        let (refcounts, rebuild) = match loaded {
+            Ok(_) if replay => (Refcounts::new(refcount_log, durability), true),
+            Ok(refcounts) => (refcounts, !refcount_log.exists()),
+            Err(StoreError::Corrupt { .. }) => (Refcounts::new(refcount_log, durability), true),
+            Err(e) => return Err(e),
+        };
 
-        let body = DiskPublish {
-            packet_id: publish.packet_id,
-            retain: publish.retain,
-            topic_name: publish.topic_name,
-            payload_id: payload.id,
+        let mut db = DB {
+            payloads: location.join("Payloads"),
+            sessions: location.join("Sessions"),
+            corrupt: location.join("Corrupt"),
+            durability,
+            on_corrupt: config.on_corrupt,
+            loaded_payloads: Mutex::new(HashMap::new()),
+            session_states: Mutex::new(HashMap::new()),
+            refcounts: Mutex::new(refcounts),
+            wal,
+            checkpoint_lock: RwLock::new(()),
+            pending: Mutex::new(Pending::default()),
+            read_only,
         };
 
-        self.write_body(session_id, body)?;
-        self.write_payload_if_empty(payload)?;
+        if replay {
+            db.replay()?;
+        }
+        if rebuild && read_only {
+            // Counted afresh, but only kept in memory
+            let counts = db.count_refs()?;
+            db.refcounts
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner)
+                .counts = counts;
+        } else if rebuild {
+            // Store created before refcounts were tracked, their log is
+            // corrupt, or the write-ahead log was replayed
+            db.rebuild_refcounts()?;
+        }
+
+        Ok(db)
+    }
+
+    /// Queues a message at the end of a session, returning once it is in the
+    /// write-ahead log.
+    pub fn write(&self, session_id: &str, publish: Publish) -> Result<(), StoreError> {
+        self.write_batch(&[(session_id, publish)])
+    }
+
+    /// Queues `publish` for each of `session_ids`, storing its payload once.
+    pub fn write_fanout(&self, publish: Publish, session_ids: &[&str]) -> Result<(), StoreError> {
+        let writes: Vec<(&str, Publish)> = session_ids
+            .iter()
+            .map(|session_id| (*session_id, publish.clone()))
+            .collect();
+        self.write_batch(&writes)
+    }
+
+    /// Queues several messages, in order, possibly for different sessions.
+    /// They go into the write-ahead log as a single record, so after a crash
+    /// either all of them are there or none are, and a payload they share is
+    /// stored once.
+    pub fn write_batch(&self, writes: &[(&str, Publish)]) -> Result<(), StoreError> {
+        self.check_writable()?;
+
+        // Sessions are locked in order, so concurrent batches can't deadlock
+        let mut session_ids: Vec<&str> = writes.iter().map(|(session_id, _)| *session_id).collect();
+        session_ids.sort_unstable();
+        session_ids.dedup();
+        let sessions = session_ids
+            .iter()
+            .map(|session_id| Ok((*session_id, self.session(session_id)?)))
+            .collect::<Result<Vec<_>, StoreError>>()?;
+        let mut states: HashMap<&str, MutexGuard<'_, SessionState>> = sessions
+            .iter()
+            .map(|(session_id, session)| (*session_id, lock(session)))
+            .collect();
+        {
+            let _checkpoint = self.hold_checkpoint();
+
+            let mut referenced = Vec::with_capacity(writes.len());
+            let record = match self.commit_writes(writes, &mut states, &mut referenced) {
+                Ok(Some(record)) => record,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    // Nothing was committed, so nothing holds the references.
+                    // One that fails to be released only keeps its payload
+                    // until the refcounts are next rebuilt
+                    let mut refcounts = lock(&self.refcounts);
+                    let mut pending = lock(&self.pending);
+                    for (payload_id, first) in referenced {
+                        let _ = refcounts.release(payload_id);
+                        if first && refcounts.counts.get(&payload_id) == Some(&0) {
+                            pending.payloads.remove(&payload_id);
+                        }
+                    }
+                    return Err(e);
+                }
+            };
+            lock(&self.pending).apply(record);
+        }
+        drop(states);
+
+        self.checkpoint_if_full()
+    }
+
+    /// Makes every change in the write-ahead log to the message and payload
+    /// files, then deletes the log. Runs by itself once the log holds
+    /// `MAX_WAL_RECORDS` records, and when the store is dropped.
+    pub fn checkpoint(&self) -> Result<(), StoreError> {
+        self.check_writable()?;
+        let _exclusive = self
+            .checkpoint_lock
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if self.wal.is_empty() {
+            return Ok(());
+        }
+
+        let mut refcounts = lock(&self.refcounts);
+        self.apply_pending(&mut refcounts)?;
+        refcounts.flush()?;
+        drop(refcounts);
+
+        self.wal.reset()
+    }
+
+    /// Reads every message in a session, handling any that are corrupt or
+    /// whose payload is missing as the store's `CorruptionPolicy` says. Use
+    /// `read_report` to find out which were left out.
+    pub fn read(&self, session_id: &str) -> Result<Vec<Publish>, StoreError> {
+        Ok(self.read_report(session_id, self.on_corrupt)?.publishes)
+    }
+
+    /// Reads every message in a session. Unless `on_corrupt` is
+    /// `CorruptionPolicy::Error`, the ones that are corrupt or whose payload is
+    /// missing are reported instead of failing the whole read. Other errors,
+    /// e.g. from the filesystem, always fail the read.
+    pub fn read_report(
+        &self,
+        session_id: &str,
+        on_corrupt: CorruptionPolicy,
+    ) -> Result<ReadReport, StoreError> {
+        let session = self.session(session_id)?;
+        let _session = lock(&session);
+        if on_corrupt == CorruptionPolicy::Quarantine {
+            // Only messages with a file can be moved into quarantine
+            self.checkpoint()?;
+        }
+        let _checkpoint = self.hold_checkpoint();
+
+        let mut report = ReadReport::default();
+        for (sequence, message) in self.session_messages(session_id)? {
+            let (path, result) = match message {
+                Message::File(path) => {
+                    let result = self.parse_body(&path);
+                    (path, result)
+                }
+                Message::Pending(body) => (
+                    self.message_path(session_id, sequence)?,
+                    self.to_publish(body),
+                ),
+            };
+            let error = match result {
+                Ok(publish) => {
+                    report.publishes.push(publish);
+                    continue;
+                }
+                Err(e @ StoreError::Corrupt { .. })
+                | Err(e @ StoreError::MissingPayload { .. }) => e,
+                Err(e) => return Err(e),
+            };
+
+            let quarantined = match on_corrupt {
+                CorruptionPolicy::Error => return Err(error),
+                CorruptionPolicy::Skip => None,
+                CorruptionPolicy::Quarantine => {
+                    Some(self.quarantine(session_id, sequence, &path, &error)?)
+                }
+            };
+            report.skipped.push(SkippedMessage {
+                path,
+                error,
+                quarantined,
+            });
+        }
+
+        Ok(report)
+    }
+
+    /// Removes the queued message with the given packet id, e.g. once its
+    /// delivery has been acknowledged. Unknown packet ids are ignored.
+    pub fn ack(&self, session_id: &str, packet_id: u16) -> Result<(), StoreError> {
+        self.remove_first(session_id, |body| body.packet_id == packet_id)
+    }
+
+    /// Removes the oldest queued message referencing the given payload. The
+    /// payload itself is deleted by the next `clean` once nothing references it.
+    pub fn remove(&self, session_id: &str, payload_id: u64) -> Result<(), StoreError> {
+        self.remove_first(session_id, |body| body.payload_id == payload_id)
+    }
+
+    /// Deletes every payload no longer referenced by a session.
+    pub fn clean(&self) -> Result<(), StoreError> {
+        self.collect_garbage(None, usize::MAX)?;
 
         Ok(())
     }
 
-    pub fn read(&mut self, session_id: &str) -> Result<Vec<Publish>, Box<dyn Error>> {
-        let session_root = self.sessions.join(session_id);
-        if !session_root.exists() {
-            return Ok(Vec::new());
+    /// Deletes unreferenced payloads until `budget` runs out. Returns whether
+    /// all garbage was collected.
+    pub fn clean_for(&self, budget: Duration) -> Result<bool, StoreError> {
+        let stats = self.collect_garbage(Some(Instant::now() + budget), usize::MAX)?;
+
+        Ok(stats.remaining == 0)
+    }
+
+    /// Deletes at most `max_payloads` unreferenced payloads.
+    pub fn clean_some(&self, max_payloads: usize) -> Result<CleanStats, StoreError> {
+        self.collect_garbage(None, max_payloads)
+    }
+
+    /// Recomputes every payload's refcount by scanning all sessions, e.g. after
+    /// a crash leaked references. Takes time proportional to the whole store,
+    /// and exclusive access so no write lands mid-scan.
+    pub fn rebuild_refcounts(&mut self) -> Result<(), StoreError> {
+        self.check_writable()?;
+
+        // Pending messages are only counted once they have files, and the
+        // write-ahead log is kept until the new counts are written
+        self.apply_pending(&mut lock(&self.refcounts))?;
+
+        let counts = self.count_refs()?;
+
+        let refcounts = self
+            .refcounts
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        refcounts.counts = counts;
+        refcounts.compact()?;
+
+        self.wal.reset()
+    }
+
+    /// Commits `writes` to the write-ahead log as one record, returning it, or
+    /// `None` if there are none. Adds the id of each payload reference it
+    /// takes to `referenced`, and whether it staged the payload's bytes, for
+    /// the caller to undo should it fail.
+    fn commit_writes<'a>(
+        &self,
+        writes: &[(&'a str, Publish)],
+        states: &mut HashMap<&'a str, MutexGuard<'_, SessionState>>,
+        referenced: &mut Vec<(u64, bool)>,
+    ) -> Result<Option<WalRecord>, StoreError> {
+        let mut records = Vec::with_capacity(writes.len());
+        for (session_id, publish) in writes {
+            let state = states.get_mut(session_id).expect("session locked");
+            let sequence = self.next_sequence(session_id, state)?;
+            // The reference is taken first, so clean can't delete the
+            // payload before the message is committed
+            let first = self.add_ref(&publish.payload)?;
+            referenced.push((publish.payload.id, first));
+            records.push(WalRecord::Write {
+                session_id: session_id.to_string(),
+                sequence,
+                body: DiskPublish {
+                    packet_id: publish.packet_id,
+                    retain: publish.retain,
+                    topic_name: publish.topic_name.clone(),
+                    payload_id: publish.payload.id,
+                },
+                payload: if first {
+                    Some(publish.payload.bytes.clone())
+                } else {
+                    None
+                },
+            });
         }
 
-        let messages = session_root.join("Messages");
+        let record = match records.len() {
+            0 => return Ok(None),
+            1 => records.remove(0),
+            _ => WalRecord::Batch(records),
+        };
+        self.wal.commit(slice::from_ref(&record))?;
 
-        let result = fs::read_dir(messages)?
-            .filter_map(|f| if let Ok(f) = f { Some(f.path()) } else { None })
-            .map(|p| self.parse_body(&p))
-            .filter_map(|p| if let Ok(p) = p { Some(p) } else { None })
+        Ok(Some(record))
+    }
+
+    /// Counts the references to every stored payload by scanning all sessions.
+    fn count_refs(&self) -> Result<HashMap<u64, u64>, StoreError> {
+        let mut counts: HashMap<u64, u64> = self
+            .get_payload_ids()?
+            .into_iter()
+            .chain(lock(&self.pending).payloads.keys().copied())
+            .map(|payload_id| (payload_id, 0))
             .collect();
 
-        Ok(result)
+        for session_id in self.get_session_ids()? {
+            for (_, message) in self.session_messages(&session_id)? {
+                // Corrupt messages are never read back, so hold no reference
+                let payload_id = match message.body() {
+                    Ok(body) => body.payload_id,
+                    Err(StoreError::Corrupt { .. }) => continue,
+                    Err(e) => return Err(e),
+                };
+                if let Some(count) = counts.get_mut(&payload_id) {
+                    *count += 1;
+                }
+            }
+        }
+
+        Ok(counts)
     }
 
-    pub fn clean(&mut self) -> Result<(), Box<dyn Error>> {
-        // Collect stored and referenced payload ids
-        let stored_ids: HashSet<u64> = HashSet::from_iter(self.get_payload_ids()?);
+    /// Fails if the store was opened read-only.
+    fn check_writable(&self) -> Result<(), StoreError> {
+        if self.read_only {
+            return Err(StoreError::ReadOnly);
+        }
 
-        let mut referenced_ids: HashSet<u64> = HashSet::new();
-        for session_id in self.get_session_ids()? {
-            for payload_id in self.get_session_payload_ids(&session_id)? {
-                referenced_ids.insert(payload_id);
+        Ok(())
+    }
+
+    /// Keeps `checkpoint` from running until dropped.
+    fn hold_checkpoint(&self) -> RwLockReadGuard<'_, ()> {
+        self.checkpoint_lock
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn checkpoint_if_full(&self) -> Result<(), StoreError> {
+        if self.wal.len() >= MAX_WAL_RECORDS {
+            self.checkpoint()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes pending messages and payloads to their files and deletes the
+    /// files of removed messages. The write-ahead log is left for the caller.
+    fn apply_pending(&self, refcounts: &mut Refcounts) -> Result<(), StoreError> {
+        let mut pending = lock(&self.pending);
+
+        for (payload_id, bytes) in pending.payloads.iter() {
+            // Every message referencing it was removed before it got a file
+            if refcounts.counts.get(payload_id) == Some(&0) {
+                refcounts.forget(*payload_id)?;
+                lock(&self.loaded_payloads).remove(payload_id);
+                continue;
             }
+
+            let path = self.payloads.join(payload_id.to_string());
+            write_atomic(&path, &seal(bytes), self.durability)?;
         }
 
-        // Delete unreferenced payloads
-        let ids_to_remove = stored_ids.difference(&referenced_ids);
-        for id_to_remove in ids_to_remove {
-            self.delete_payload(*id_to_remove)?;
+        for (session_id, messages) in pending.messages.iter() {
+            for (sequence, body) in messages.iter() {
+                self.write_body(session_id, *sequence, body)?;
+            }
         }
 
-        // Clean loaded payloads
-        self.loaded_payloads.retain(|_, val| val.strong_count() > 0);
+        for (session_id, removed) in pending.removed.iter() {
+            for sequence in removed.iter() {
+                match remove_file(self.message_path(session_id, *sequence)?) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            let dir = self.session_dir(session_id)?.join("Messages");
+            if dir.exists() {
+                sync_dir(&dir, self.durability)?;
+            }
+        }
+
+        *pending = Pending::default();
 
         Ok(())
     }
 
-    fn write_body(&self, session_id: &str, body: DiskPublish) -> Result<(), Box<dyn Error>> {
-        let dir = self.sessions.join(session_id).join("Messages");
-        if !dir.exists() {
-            create_dir_all(&dir)?;
+    /// Loads what the write-ahead log holds into `pending`, deleting removed
+    /// sessions straight away as `remove_session` does. A read-only store
+    /// leaves the log and the sessions' files in place and hides the files.
+    fn replay(&self) -> Result<(), StoreError> {
+        if self.read_only {
+            let records = peek_log(self.wal.path())?.into_records()?;
+            let mut pending = lock(&self.pending);
+            for (record, _) in records {
+                let removed_session = match &record {
+                    WalRecord::RemoveSession { session_id } => Some(session_id.clone()),
+                    _ => None,
+                };
+                pending.apply(record);
+                if let Some(session_id) = removed_session {
+                    let files = self.get_session_messages(&session_id)?;
+                    pending
+                        .removed
+                        .entry(session_id)
+                        .or_default()
+                        .extend(files.into_iter().map(|(sequence, _)| sequence));
+                }
+            }
+            return Ok(());
         }
 
-        let bytes = bincode::serialize(&body)?;
+        let mut pending = lock(&self.pending);
 
-        OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(dir.join(body.payload_id.to_string()))?
-            .write_all(&bytes)?;
+        for (record, _) in read_log(self.wal.path())? {
+            if let WalRecord::RemoveSession { session_id } = &record {
+                let session_root = self.session_dir(session_id)?;
+                if session_root.exists() {
+                    remove_dir_all(session_root)?;
+                    sync_dir(&self.sessions, self.durability)?;
+                }
+                remove_session_id(&self.sessions, session_id)?;
+            }
+            pending.apply(record);
+        }
 
         Ok(())
     }
 
-    fn write_payload_if_empty(&self, payload: Payload) -> Result<(), Box<dyn Error>> {
-        let path = self.payloads.join(payload.id.to_string());
-        if !path.exists() {
-            File::create(path)?.write_all(&payload.bytes)?;
+    fn session<'a>(&'a self, session_id: &'a str) -> Result<Session<'a>, StoreError> {
+        encode_session_id(session_id)?;
+
+        let state = lock(&self.session_states)
+            .entry(session_id.to_owned())
+            .or_default()
+            .clone();
+
+        Ok(Session {
+            db: self,
+            session_id,
+            state,
+        })
+    }
+
+    fn write_body(
+        &self,
+        session_id: &str,
+        sequence: u64,
+        body: &DiskPublish,
+    ) -> Result<(), StoreError> {
+        let session_root = self.session_dir(session_id)?;
+        let dir = session_root.join("Messages");
+        if !dir.exists() {
+            save_session_id(&self.sessions, session_id, self.durability)?;
+            create_dir_all(&dir)?;
+            sync_dir(&self.sessions, self.durability)?;
+            sync_dir(&session_root, self.durability)?;
         }
 
+        let bytes = seal(&bincode::serialize(body)?);
+        write_atomic(&dir.join(sequence.to_string()), &bytes, self.durability)?;
+
         Ok(())
     }
 
-    fn parse_body(&mut self, path: &Path) -> Result<Publish, Box<dyn Error>> {
-        let body: DiskPublish = bincode::deserialize_from(File::open(path)?)?;
+    /// Takes a reference to a payload, returning whether it is the first. The
+    /// bytes of a payload nothing referenced yet are kept until it is written.
+    fn add_ref(&self, payload: &Payload) -> Result<bool, StoreError> {
+        // Held throughout so clean can't delete the payload in between
+        let mut refcounts = lock(&self.refcounts);
+
+        let first = !refcounts.contains(payload.id);
+        if first {
+            lock(&self.pending)
+                .payloads
+                .insert(payload.id, payload.bytes.clone());
+        }
+        refcounts.add(payload.id)?;
+
+        Ok(first)
+    }
+
+    fn collect_garbage(
+        &self,
+        deadline: Option<Instant>,
+        max_payloads: usize,
+    ) -> Result<CleanStats, StoreError> {
+        self.check_writable()?;
+
+        let start = Instant::now();
+        let mut stats = CleanStats::default();
+
+        // Relocks per payload so writers aren't held up for the whole pass
+        loop {
+            if stats.payloads_deleted as usize >= max_payloads
+                || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                break;
+            }
+
+            let mut refcounts = lock(&self.refcounts);
+            let payload_id = match refcounts.garbage.iter().next() {
+                Some(payload_id) => *payload_id,
+                None => break,
+            };
+
+            stats.bytes_reclaimed += self.delete_payload_locked(&mut refcounts, payload_id)?;
+            stats.payloads_deleted += 1;
+        }
+
+        // Clean loaded payloads
+        lock(&self.loaded_payloads).retain(|_, val| val.strong_count() > 0);
+
+        let mut refcounts = lock(&self.refcounts);
+        refcounts.flush()?;
+        stats.remaining = refcounts.garbage.len();
+        stats.duration = start.elapsed();
+
+        Ok(stats)
+    }
+
+    fn next_sequence(
+        &self,
+        session_id: &str,
+        session: &mut SessionState,
+    ) -> Result<u64, StoreError> {
+        let next = match session.next_sequence {
+            Some(next) => next,
+            None => self
+                .get_session_messages(session_id)?
+                .last()
+                .map_or(0, |(sequence, _)| sequence + 1),
+        };
+
+        session.next_sequence = Some(next + 1);
+
+        Ok(next)
+    }
+
+    fn remove_first<F>(&self, session_id: &str, predicate: F) -> Result<(), StoreError>
+    where
+        F: Fn(&DiskPublish) -> bool,
+    {
+        self.check_writable()?;
+
+        let session = self.session(session_id)?;
+        let session = lock(&session);
+        {
+            let _checkpoint = self.hold_checkpoint();
+
+            for (sequence, message) in self.session_messages(session_id)? {
+                let body = message.body()?;
+                if predicate(&body) {
+                    let record = WalRecord::Remove {
+                        session_id: session_id.to_owned(),
+                        sequence,
+                    };
+                    self.wal.commit(&[record])?;
+
+                    lock(&self.pending).remove(session_id, sequence);
+                    lock(&self.refcounts).release(body.payload_id)?;
+                    break;
+                }
+            }
+        }
+        drop(session);
+
+        self.checkpoint_if_full()
+    }
+
+    /// Moves an unreadable message out of its session, returning where to.
+    fn quarantine(
+        &self,
+        session_id: &str,
+        sequence: u64,
+        path: &Path,
+        error: &StoreError,
+    ) -> Result<PathBuf, StoreError> {
+        let dir = self.corrupt.join(encode_session_id(session_id)?);
+        if !dir.exists() {
+            save_session_id(&self.corrupt, session_id, self.durability)?;
+            create_dir_all(&dir)?;
+        }
+
+        // Sequence numbers can be reused after a restart, never overwrite
+        let mut target = dir.join(sequence.to_string());
+        let mut attempt = 0;
+        while target.exists() {
+            attempt += 1;
+            target = dir.join(format!("{}.{}", sequence, attempt));
+        }
+
+        rename(path, &target)?;
+        sync_dir(&dir, self.durability)?;
+        if let Some(parent) = path.parent() {
+            sync_dir(parent, self.durability)?;
+        }
+
+        // A readable body still holds a reference to its (missing) payload.
+        // Quarantining isn't in the write-ahead log, so the release is
+        // flushed straight away.
+        if let StoreError::MissingPayload { payload_id } = error {
+            let mut refcounts = lock(&self.refcounts);
+            refcounts.release(*payload_id)?;
+            refcounts.flush()?;
+        }
+
+        Ok(target)
+    }
+
+    fn read_body(path: &Path) -> Result<DiskPublish, StoreError> {
+        let bytes = fs::read(path)?;
+        bincode::deserialize(unseal(path, &bytes)?)
+            .map_err(|e| StoreError::corrupt(path, HEADER_LEN as u64, e))
+    }
+
+    fn parse_body(&self, path: &Path) -> Result<Publish, StoreError> {
+        self.to_publish(Self::read_body(path)?)
+    }
+
+    fn to_publish(&self, body: DiskPublish) -> Result<Publish, StoreError> {
         let payload = self.get_payload(body.payload_id)?;
 
         Ok(Publish {
@@ -162,8 +1175,8 @@ impl DB {
         })
     }
 
-    fn get_payload(&mut self, payload_id: u64) -> Result<Payload, Box<dyn Error>> {
-        if let Some(bytes) = self.loaded_payloads.get(&payload_id) {
+    fn get_payload(&self, payload_id: u64) -> Result<Payload, StoreError> {
+        if let Some(bytes) = lock(&self.loaded_payloads).get(&payload_id) {
             if let Some(b) = bytes.upgrade() {
                 return Ok(Payload {
                     id: payload_id,
@@ -172,13 +1185,41 @@ impl DB {
             }
         }
 
-        let mut file = File::open(self.payloads.join(payload_id.to_string()))?;
+        // Not written yet, shared straight from the write instead
+        let pending = lock(&self.pending).payloads.get(&payload_id).cloned();
+        if let Some(bytes) = pending {
+            lock(&self.loaded_payloads).insert(payload_id, Arc::downgrade(&bytes));
+            return Ok(Payload {
+                id: payload_id,
+                bytes,
+            });
+        }
+
+        let path = self.payloads.join(payload_id.to_string());
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(StoreError::MissingPayload { payload_id })
+            }
+            Err(e) => return Err(e.into()),
+        };
         let mut buffer: Vec<u8> = Vec::with_capacity(file.metadata()?.len() as usize);
         file.read_to_end(&mut buffer)?;
-        let bytes = Arc::new(buffer);
-
-        self.loaded_payloads
-            .insert(payload_id, Arc::downgrade(&bytes));
+        // Strip the header and checksum in place rather than copying the bytes
+        let len = unseal(&path, &buffer)?.len();
+        buffer.truncate(HEADER_LEN + len);
+        buffer.drain(..HEADER_LEN);
+
+        // Another reader may have loaded it meanwhile, keep sharing theirs
+        let mut loaded_payloads = lock(&self.loaded_payloads);
+        let bytes = match loaded_payloads.get(&payload_id).and_then(|b| b.upgrade()) {
+            Some(bytes) => bytes,
+            None => {
+                let bytes = Arc::new(buffer);
+                loaded_payloads.insert(payload_id, Arc::downgrade(&bytes));
+                bytes
+            }
+        };
 
         Ok(Payload {
             id: payload_id,
@@ -186,65 +1227,262 @@ impl DB {
         })
     }
 
-    fn delete_payload(&mut self, payload_id: u64) -> Result<(), Box<dyn Error>> {
-        remove_file(self.payloads.join(payload_id.to_string()))?;
-        self.loaded_payloads.remove(&payload_id);
+    /// Returns the number of bytes freed.
+    fn delete_payload(&self, payload_id: u64) -> Result<u64, StoreError> {
+        self.delete_payload_locked(&mut lock(&self.refcounts), payload_id)
+    }
 
-        Ok(())
+    fn delete_payload_locked(
+        &self,
+        refcounts: &mut Refcounts,
+        payload_id: u64,
+    ) -> Result<u64, StoreError> {
+        let path = self.payloads.join(payload_id.to_string());
+        let len = match metadata(&path) {
+            Ok(metadata) => {
+                remove_file(&path)?;
+                metadata.len()
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        lock(&self.loaded_payloads).remove(&payload_id);
+        lock(&self.pending).payloads.remove(&payload_id);
+        refcounts.forget(payload_id)?;
+
+        Ok(len)
     }
 
-    fn get_session_ids(&self) -> Result<Vec<String>, Box<dyn Error>> {
-        Self::list_children(&self.sessions)
+    /// Sessions with a directory, or with a message not yet written.
+    fn get_session_ids(&self) -> Result<Vec<String>, StoreError> {
+        let pending = lock(&self.pending);
+        let mut session_ids = list_session_ids(&self.sessions)?;
+        session_ids.retain(|session_id| !pending.removed_sessions.contains(session_id));
+        for (session_id, messages) in pending.messages.iter() {
+            if !messages.is_empty() && !session_ids.contains(session_id) {
+                session_ids.push(session_id.clone());
+            }
+        }
+
+        Ok(session_ids)
     }
 
-    fn get_session_payload_ids(&self, session_id: &str) -> Result<Vec<u64>, Box<dyn Error>> {
-        let path = self.sessions.join(session_id).join("Messages");
-        let result: Result<Vec<u64>, _> = Self::list_children(path)?
-            .iter()
-            .map(|p| p.parse())
+    /// Directory holding a session, named by its encoded id.
+    fn session_dir(&self, session_id: &str) -> Result<PathBuf, StoreError> {
+        Ok(self.sessions.join(encode_session_id(session_id)?))
+    }
+
+    /// File a message is written to.
+    fn message_path(&self, session_id: &str, sequence: u64) -> Result<PathBuf, StoreError> {
+        Ok(self
+            .session_dir(session_id)?
+            .join("Messages")
+            .join(sequence.to_string()))
+    }
+
+    fn get_session_payload_ids(&self, session_id: &str) -> Result<Vec<u64>, StoreError> {
+        self.session_messages(session_id)?
+            .into_iter()
+            .map(|(_, message)| Ok(message.body()?.payload_id))
+            .collect()
+    }
+
+    /// A session's messages ordered by sequence number: its message files,
+    /// less any removed since the last checkpoint, and its pending messages.
+    fn session_messages(&self, session_id: &str) -> Result<Vec<(u64, Message)>, StoreError> {
+        let files = self.get_session_messages(session_id)?;
+
+        let pending = lock(&self.pending);
+        let removed = pending.removed.get(session_id);
+        let mut result: Vec<(u64, Message)> = files
+            .into_iter()
+            .filter(|(sequence, _)| !removed.is_some_and(|r| r.contains(sequence)))
+            .map(|(sequence, path)| (sequence, Message::File(path)))
             .collect();
+        if let Some(messages) = pending.messages.get(session_id) {
+            result.extend(
+                messages
+                    .iter()
+                    .map(|(sequence, body)| (*sequence, Message::Pending(body.clone()))),
+            );
+        }
+        result.sort_by_key(|(sequence, _)| *sequence);
 
-        Ok(result?)
+        Ok(result)
     }
 
-    fn get_payload_ids(&self) -> Result<Vec<u64>, Box<dyn Error>> {
-        let result: Result<Vec<u64>, _> = Self::list_children(&self.payloads)?
+    /// Lists a session's message files ordered by sequence number.
+    fn get_session_messages(&self, session_id: &str) -> Result<Vec<(u64, PathBuf)>, StoreError> {
+        let path = self.session_dir(session_id)?.join("Messages");
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut result = Self::list_children(&path)?
             .iter()
-            .map(|p| p.parse())
-            .collect();
+            .map(|name| Ok((Self::parse_name(&path, name)?, path.join(name))))
+            .collect::<Result<Vec<(u64, PathBuf)>, StoreError>>()?;
+        result.sort_by_key(|(sequence, _)| *sequence);
+
+        Ok(result)
+    }
+
+    fn get_payload_ids(&self) -> Result<Vec<u64>, StoreError> {
+        Self::list_children(&self.payloads)?
+            .iter()
+            .map(|p| Self::parse_name(&self.payloads, p))
+            .collect()
+    }
 
-        Ok(result?)
+    /// Parses the number a message or payload file is named by.
+    fn parse_name(dir: &Path, name: &str) -> Result<u64, StoreError> {
+        name.parse()
+            .map_err(|e| StoreError::corrupt(dir.join(name), 0, format!("bad file name: {}", e)))
     }
 
-    fn list_children<P>(path: P) -> Result<Vec<String>, Box<dyn Error>>
+    fn list_children<P>(path: P) -> Result<Vec<String>, StoreError>
     where
         P: AsRef<Path>,
     {
         let result = fs::read_dir(path)?
             .filter_map(|f| if let Ok(f) = f { Some(f.path()) } else { None })
             .filter_map(|f| f.file_name().map(|f| f.to_owned()))
-            .filter_map(|f| {
-                if let Ok(f) = f.into_string() {
-                    Some(f)
-                } else {
-                    None
+            .filter_map(|f| f.into_string().ok())
+            .filter(|f| !is_temp_file(f))
+            .collect();
+
+        Ok(result)
+    }
+}
+
+impl SessionStore for DB {
+    fn open(location: &Path) -> Result<Self, StoreError> {
+        Self::new(location)
+    }
+
+    fn append(&self, session_id: &str, publish: Publish) -> Result<(), StoreError> {
+        self.write(session_id, publish)
+    }
+
+    fn append_batch(&self, writes: &[(&str, Publish)]) -> Result<(), StoreError> {
+        self.write_batch(writes)
+    }
+
+    fn read(&self, session_id: &str) -> Result<Vec<Publish>, StoreError> {
+        self.read(session_id)
+    }
+
+    fn ack(&self, session_id: &str, packet_id: u16) -> Result<(), StoreError> {
+        self.ack(session_id, packet_id)
+    }
+
+    fn remove_session(&self, session_id: &str) -> Result<(), StoreError> {
+        self.check_writable()?;
+
+        let session = self.session(session_id)?;
+        let mut session = lock(&session);
+        {
+            let _checkpoint = self.hold_checkpoint();
+
+            let session_root = self.session_dir(session_id)?;
+            let payload_ids = self.get_session_payload_ids(session_id)?;
+            if session_root.exists() || !payload_ids.is_empty() {
+                let record = WalRecord::RemoveSession {
+                    session_id: session_id.to_owned(),
+                };
+                self.wal.commit(&[record])?;
+
+                lock(&self.pending).remove_session(session_id);
+                if session_root.exists() {
+                    remove_dir_all(session_root)?;
+                    sync_dir(&self.sessions, self.durability)?;
+                }
+                remove_session_id(&self.sessions, session_id)?;
+
+                let mut refcounts = lock(&self.refcounts);
+                for payload_id in payload_ids {
+                    refcounts.release(payload_id)?;
                 }
+            }
+            session.next_sequence = None;
+        }
+        drop(session);
+
+        self.checkpoint_if_full()
+    }
+
+    fn list_sessions(&self) -> Result<Vec<String>, StoreError> {
+        let _checkpoint = self.hold_checkpoint();
+        self.get_session_ids()
+    }
+
+    fn clean(&self) -> Result<(), StoreError> {
+        self.clean()
+    }
+}
+
+impl Inspect for DB {
+    /// A read-only store measures payloads without a file yet by the size of
+    /// the file they will be written to.
+    fn payloads(&self) -> Result<Vec<PayloadInfo>, StoreError> {
+        // Pending payloads have no file to measure yet
+        if !self.read_only {
+            self.checkpoint()?;
+        }
+
+        // Held so clean can't delete a payload while it is listed
+        let refcounts = lock(&self.refcounts);
+        let pending = lock(&self.pending);
+
+        let mut payload_ids = self.get_payload_ids()?;
+        // As at a checkpoint, one no longer referenced never gets a file
+        payload_ids.extend(
+            pending
+                .payloads
+                .keys()
+                .filter(|payload_id| refcounts.counts.get(payload_id) != Some(&0)),
+        );
+        payload_ids.sort_unstable();
+        payload_ids.dedup();
+        payload_ids
+            .into_iter()
+            .map(|payload_id| {
+                let stored_bytes = match pending.payloads.get(&payload_id) {
+                    Some(bytes) => seal(bytes).len() as u64,
+                    None => metadata(self.payloads.join(payload_id.to_string()))?.len(),
+                };
+                Ok(PayloadInfo {
+                    key: payload_id.to_string(),
+                    stored_bytes,
+                    refcount: refcounts.counts.get(&payload_id).copied().unwrap_or(0),
+                })
             })
-            .collect();
+            .collect()
+    }
+}
 
-        Ok(result)
+/// Checkpoints, so a store closed cleanly leaves no write-ahead log behind.
+/// Should that fail, the log is replayed when the store is next opened.
+impl Drop for DB {
+    fn drop(&mut self) {
+        if !self.read_only {
+            let _ = self.checkpoint();
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use session_store::testing::{
+        self, assert_golden_contents, golden_dir, golden_fill, read_tree, write_tree,
+    };
     use tempfile::tempdir;
 
     #[test]
     fn test_clean() {
-        let path = tempdir().unwrap().into_path();
-        let mut db = DB::new(&path).expect("Make db");
+        let path = tempdir().unwrap().keep();
+        let db = DB::new(&path).expect("Make db");
         let mut faker = Faker::new();
 
         let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
@@ -262,13 +1500,7 @@ mod tests {
         assert_eq!(stored[0].topic_name, "fake");
         assert_eq!(stored[0].payload.bytes, Arc::new(vec![1, 2, 3, 4, 5]));
 
-        remove_file(
-            path.join("Sessions")
-                .join("Session 1")
-                .join("Messages")
-                .join(publish.payload.id.to_string()),
-        )
-        .unwrap();
+        db.remove("Session 1", publish.payload.id).expect("Remove");
 
         // payload has no referances, clean deletes
         db.clean().expect("Clean");
@@ -278,8 +1510,8 @@ mod tests {
 
     #[test]
     fn test_read_write() {
-        let path = tempdir().unwrap().into_path();
-        let mut db = DB::new(&path).expect("Make db");
+        let path = tempdir().unwrap().keep();
+        let db = DB::new(&path).expect("Make db");
         let mut faker = Faker::new();
 
         db.write("Session 1", faker.make_fake_publish(vec![1, 2, 3, 4, 5]))
@@ -293,8 +1525,8 @@ mod tests {
 
     #[test]
     fn test_read_write_multiple() {
-        let path = tempdir().unwrap().into_path();
-        let mut db = DB::new(&path).expect("Make db");
+        let path = tempdir().unwrap().keep();
+        let db = DB::new(&path).expect("Make db");
         let mut faker = Faker::new();
 
         db.write("Session 1", faker.make_fake_publish(vec![1, 2, 3]))
@@ -314,8 +1546,8 @@ mod tests {
 
     #[test]
     fn test_shared_payload() {
-        let path = tempdir().unwrap().into_path();
-        let mut db = DB::new(&path).expect("Make db");
+        let path = tempdir().unwrap().keep();
+        let db = DB::new(&path).expect("Make db");
         let mut faker = Faker::new();
 
         let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
@@ -335,8 +1567,8 @@ mod tests {
 
     #[test]
     fn test_shared_payload_in_memory() {
-        let path = tempdir().unwrap().into_path();
-        let mut db = DB::new(&path).expect("Make db");
+        let path = tempdir().unwrap().keep();
+        let db = DB::new(&path).expect("Make db");
         let mut faker = Faker::new();
 
         let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
@@ -350,6 +1582,7 @@ mod tests {
 
         // Ensure it is using cached data by deleting from disk.
         // Normally the referance in cache should never be deleted from disk before all referances are gone.
+        db.checkpoint().expect("Checkpoint");
         remove_file(path.join("Payloads").join(publish.payload.id.to_string())).unwrap();
 
         let stored = db.read("Session 2").unwrap();
@@ -360,8 +1593,12 @@ mod tests {
 
     #[test]
     fn test_add_remove_payload() {
-        let path = tempdir().unwrap().into_path();
-        let mut db = DB::new(&path).expect("Make db");
+        let path = tempdir().unwrap().keep();
+        let config = StoreConfig {
+            on_corrupt: CorruptionPolicy::Skip,
+            ..StoreConfig::default()
+        };
+        let db = DB::with_config(&path, config).expect("Make db");
         let mut faker = Faker::new();
 
         let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
@@ -377,28 +1614,461 @@ mod tests {
 
         let stored = db.read("Session 1").expect("Get Payload");
         assert_eq!(stored.len(), 0);
+
+        let report = db.read_report("Session 1", CorruptionPolicy::Skip).unwrap();
+        assert_eq!(report.skipped.len(), 1);
+        assert!(matches!(
+            report.skipped[0].error,
+            StoreError::MissingPayload { payload_id } if payload_id == publish.payload.id
+        ));
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let kept = faker.make_fake_publish(vec![1]);
+        let orphaned = faker.make_fake_publish(vec![2]);
+        db.write("Session 1", kept.clone()).expect("Publish 1");
+        db.write("Session 1", orphaned.clone()).expect("Publish 2");
+        db.write("Session 1", faker.make_fake_publish(vec![3]))
+            .expect("Publish 3");
+        db.checkpoint().expect("Checkpoint");
+
+        let messages = path.join("Sessions").join("%53ession%201").join("Messages");
+        remove_file(path.join("Payloads").join(orphaned.payload.id.to_string())).unwrap();
+        write(messages.join("2"), [0xff]).unwrap();
+
+        let report = db
+            .read_report("Session 1", CorruptionPolicy::Quarantine)
+            .unwrap();
+        assert_eq!(report.publishes, vec![kept]);
+        assert_eq!(report.skipped.len(), 2);
+        assert!(matches!(
+            report.skipped[0].error,
+            StoreError::MissingPayload { .. }
+        ));
+        assert!(matches!(
+            report.skipped[1].error,
+            StoreError::Corrupt { .. }
+        ));
+
+        let corrupt = path.join("Corrupt").join("%53ession%201");
+        assert_eq!(report.skipped[0].quarantined, Some(corrupt.join("1")));
+        assert_eq!(report.skipped[1].quarantined, Some(corrupt.join("2")));
+        assert_eq!(read_dir(&messages).unwrap().count(), 1);
+        assert_eq!(db.refcounts.lock().unwrap().counts[&orphaned.payload.id], 0);
+
+        let report = db
+            .read_report("Session 1", CorruptionPolicy::Quarantine)
+            .unwrap();
+        assert_eq!(report.publishes.len(), 1);
+        assert!(report.skipped.is_empty());
+    }
+
+    #[test]
+    fn test_checksums() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let kept = faker.make_fake_publish(vec![1, 2, 3]);
+        let flipped = faker.make_fake_publish(vec![4, 5, 6]);
+        db.write("Session 1", kept.clone()).expect("Publish 1");
+        db.write("Session 1", flipped.clone()).expect("Publish 2");
+        drop(db);
+
+        // a flipped bit in the payload bytes is caught, not returned as data
+        let payload = path.join("Payloads").join(flipped.payload.id.to_string());
+        let mut bytes = read(&payload).unwrap();
+        bytes[HEADER_LEN + 1] ^= 0x10;
+        write(&payload, bytes).unwrap();
+
+        let db = DB::new(path).expect("Reopen db");
+        match db.read("Session 1") {
+            Err(StoreError::Corrupt { path, .. }) => assert_eq!(path, payload),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let report = db.read_report("Session 1", CorruptionPolicy::Skip).unwrap();
+        assert_eq!(report.publishes, vec![kept.clone()]);
+        assert_eq!(report.skipped.len(), 1);
+
+        // so is one in a message, and a damaged refcount log is rebuilt
+        let message = path
+            .join("Sessions")
+            .join("%53ession%201")
+            .join("Messages")
+            .join("0");
+        let mut bytes = read(&message).unwrap();
+        bytes[HEADER_LEN] ^= 0x01;
+        write(&message, bytes).unwrap();
+        let mut bytes = read(path.join("Refcounts")).unwrap();
+        bytes[HEADER_LEN + 4] ^= 0x01;
+        write(path.join("Refcounts"), bytes).unwrap();
+
+        let config = StoreConfig {
+            on_corrupt: CorruptionPolicy::Skip,
+            ..StoreConfig::default()
+        };
+        let db = DB::with_config(path, config).expect("Reopen db");
+        assert!(db.read("Session 1").unwrap().is_empty());
+        assert_eq!(db.refcounts.lock().unwrap().counts[&flipped.payload.id], 1);
+    }
+
+    #[test]
+    fn test_read_preserves_order() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let payloads: Vec<Vec<u8>> = (0..20).map(|i| vec![i]).collect();
+        for payload in payloads.iter() {
+            db.write("Session 1", faker.make_fake_publish(payload.clone()))
+                .expect("Publish");
+        }
+
+        let stored: Vec<Vec<u8>> = db
+            .read("Session 1")
+            .unwrap()
+            .iter()
+            .map(|p| p.payload.bytes.to_vec())
+            .collect();
+        assert_eq!(stored, payloads);
+    }
+
+    #[test]
+    fn test_duplicate_payload_in_session() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publish = faker.make_fake_publish(vec![1, 2, 3]);
+        let other = faker.make_fake_publish(vec![4, 5, 6]);
+        db.write("Session 1", publish.clone()).expect("Publish 1");
+        db.write("Session 1", other).expect("Publish 2");
+        db.write("Session 1", publish.clone()).expect("Publish 3");
+
+        let stored = db.read("Session 1").unwrap();
+        assert_eq!(stored.len(), 3);
+        assert_eq!(stored[0].payload.bytes, Arc::new(vec![1, 2, 3]));
+        assert_eq!(stored[1].payload.bytes, Arc::new(vec![4, 5, 6]));
+        assert_eq!(stored[2].payload.bytes, Arc::new(vec![1, 2, 3]));
+
+        // remove only drops the oldest entry for the payload
+        db.remove("Session 1", publish.payload.id).expect("Remove");
+        let stored = db.read("Session 1").unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].payload.bytes, Arc::new(vec![4, 5, 6]));
+        assert_eq!(stored[1].payload.bytes, Arc::new(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_sequence_survives_reopen() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let mut faker = Faker::new();
+
+        let db = DB::new(path).expect("Make db");
+        db.write("Session 1", faker.make_fake_publish(vec![1]))
+            .expect("Publish 1");
+        db.write("Session 1", faker.make_fake_publish(vec![2]))
+            .expect("Publish 2");
+
+        let db = DB::new(path).expect("Reopen db");
+        db.write("Session 1", faker.make_fake_publish(vec![3]))
+            .expect("Publish 3");
+
+        let stored: Vec<Vec<u8>> = db
+            .read("Session 1")
+            .unwrap()
+            .iter()
+            .map(|p| p.payload.bytes.to_vec())
+            .collect();
+        assert_eq!(stored, vec![vec![1], vec![2], vec![3]]);
+    }
+
+    #[test]
+    fn test_ignores_temp_files() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::with_durability(path, Durability::None).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
+        db.write("Session 1", publish).expect("Publish 1");
+        db.checkpoint().expect("Checkpoint");
+
+        // simulate a crash in the middle of an atomic write
+        let messages = path.join("Sessions").join("%53ession%201").join("Messages");
+        File::create(messages.join("1.tmp")).unwrap();
+        File::create(path.join("Payloads").join("2000.tmp")).unwrap();
+
+        let stored = db.read("Session 1").unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].payload.bytes, Arc::new(vec![1, 2, 3, 4, 5]));
+
+        db.clean().expect("Clean");
+        assert_eq!(db.get_payload_ids().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_ack() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let first = faker.make_fake_publish(vec![1, 2, 3]);
+        let second = faker.make_fake_publish(vec![4, 5, 6]);
+        db.write("Session 1", first.clone()).expect("Publish 1");
+        db.write("Session 1", second).expect("Publish 2");
+
+        db.ack("Session 1", first.packet_id).expect("Ack");
+        let stored = db.read("Session 1").unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].payload.bytes, Arc::new(vec![4, 5, 6]));
+
+        // acking again or acking an unknown session is a no-op
+        db.ack("Session 1", first.packet_id).expect("Ack");
+        db.ack("Session 2", first.packet_id).expect("Ack");
+
+        db.clean().expect("Clean");
+        assert!(!path
+            .join("Payloads")
+            .join(first.payload.id.to_string())
+            .exists());
+    }
+
+    #[test]
+    fn test_remove_shared_payload() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
+        db.write("Session 1", publish.clone()).expect("Publish 1");
+        db.write("Session 2", publish.clone()).expect("Publish 2");
+
+        // payload is still referenced by Session 2
+        db.remove("Session 1", publish.payload.id).expect("Remove");
+        db.clean().expect("Clean");
+        assert_eq!(db.read("Session 1").unwrap().len(), 0);
+        assert_eq!(db.read("Session 2").unwrap().len(), 1);
+
+        db.remove("Session 2", publish.payload.id).expect("Remove");
+        db.clean().expect("Clean");
+        assert_eq!(db.get_payload_ids().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_refcounts_persist() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let mut faker = Faker::new();
+
+        let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
+        let payload = path.join("Payloads").join(publish.payload.id.to_string());
+
+        let db = DB::new(path).expect("Make db");
+        db.write("Session 1", publish.clone()).expect("Publish 1");
+        db.write("Session 2", publish.clone()).expect("Publish 2");
+        db.write("Session 2", publish.clone()).expect("Publish 3");
+        assert_eq!(db.refcounts.lock().unwrap().counts[&publish.payload.id], 3);
+
+        let db = DB::new(path).expect("Reopen db");
+        assert_eq!(db.refcounts.lock().unwrap().counts[&publish.payload.id], 3);
+
+        db.ack("Session 1", publish.packet_id).expect("Ack");
+        db.remove_session("Session 2").expect("Remove session");
+        assert_eq!(db.refcounts.lock().unwrap().counts[&publish.payload.id], 0);
+        assert!(payload.exists());
+
+        let db = DB::new(path).expect("Reopen db");
+        db.clean().expect("Clean");
+        assert!(!payload.exists());
+        assert!(db.refcounts.lock().unwrap().counts.is_empty());
+
+        let db = DB::new(path).expect("Reopen db");
+        assert!(db.refcounts.lock().unwrap().counts.is_empty());
+        assert!(db.refcounts.lock().unwrap().garbage.is_empty());
+    }
+
+    #[test]
+    fn test_clean_for() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publishes: Vec<Publish> = (0..10).map(|i| faker.make_fake_publish(vec![i])).collect();
+        for publish in publishes.iter() {
+            db.write("Session 1", publish.clone()).expect("Publish");
+        }
+        db.checkpoint().expect("Checkpoint");
+        for publish in publishes.iter() {
+            db.ack("Session 1", publish.packet_id).expect("Ack");
+        }
+        assert_eq!(db.refcounts.lock().unwrap().garbage.len(), 10);
+
+        // An exhausted budget leaves the garbage for a later slice
+        assert!(!db.clean_for(Duration::from_secs(0)).expect("Clean"));
+        assert_eq!(db.get_payload_ids().unwrap().len(), 10);
+
+        assert!(db.clean_for(Duration::from_secs(60)).expect("Clean"));
+        assert_eq!(db.get_payload_ids().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_clean_some() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publishes: Vec<Publish> = (0..10)
+            .map(|i| faker.make_fake_publish(vec![i; 100]))
+            .collect();
+        for publish in publishes.iter() {
+            db.write("Session 1", publish.clone()).expect("Publish");
+        }
+        db.checkpoint().expect("Checkpoint");
+        for publish in publishes.iter() {
+            db.ack("Session 1", publish.packet_id).expect("Ack");
+        }
+
+        let stats = db.clean_some(4).expect("Clean");
+        assert_eq!(stats.payloads_deleted, 4);
+        assert_eq!(stats.bytes_reclaimed, 4 * seal(&[0; 100]).len() as u64);
+        assert_eq!(stats.remaining, 6);
+
+        let stats = db.clean_some(100).expect("Clean");
+        assert_eq!(stats.payloads_deleted, 6);
+        assert_eq!(stats.remaining, 0);
+        assert_eq!(db.get_payload_ids().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_rebuild_refcounts() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let kept = faker.make_fake_publish(vec![1, 2, 3]);
+        let acked = faker.make_fake_publish(vec![4, 5, 6]);
+        db.write("Session 1", kept.clone()).expect("Publish 1");
+        db.write("Session 2", kept.clone()).expect("Publish 2");
+        db.write("Session 1", acked.clone()).expect("Publish 3");
+        db.ack("Session 1", acked.packet_id).expect("Ack");
+
+        // stores without a refcount log are scanned when opened
+        remove_file(path.join("Refcounts")).unwrap();
+        let db = DB::new(path).expect("Reopen db");
+        assert_eq!(db.refcounts.lock().unwrap().counts[&kept.payload.id], 2);
+        assert_eq!(db.refcounts.lock().unwrap().counts[&acked.payload.id], 0);
+
+        db.clean().expect("Clean");
+        assert_eq!(db.get_payload_ids().unwrap(), vec![kept.payload.id]);
+    }
+
+    #[test]
+    fn test_refcount_log_compaction() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::with_durability(path, Durability::None).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publish = faker.make_fake_publish(vec![1, 2, 3]);
+        for _ in 0..MIN_COMPACTION_RECORDS {
+            db.write("Session 1", publish.clone()).expect("Publish");
+            db.ack("Session 1", publish.packet_id).expect("Ack");
+        }
+        assert!(db.refcounts.lock().unwrap().records < MIN_COMPACTION_RECORDS);
+
+        // never written, as every message referencing it was acked first
+        let db = DB::new(path).expect("Reopen db");
+        assert!(!path
+            .join("Payloads")
+            .join(publish.payload.id.to_string())
+            .exists());
+        assert!(db.refcounts.lock().unwrap().records < MIN_COMPACTION_RECORDS);
     }
 
     #[test]
     fn test_get_session_ids() {
-        let path = tempdir().unwrap().into_path();
-        let mut db = DB::new(&path).expect("Make db");
+        let path = tempdir().unwrap().keep();
+        let db = DB::new(&path).expect("Make db");
         let mut faker = Faker::new();
 
         let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
         db.write("Session 1", publish.clone()).expect("Publish 1");
         db.write("Session 2", publish).expect("Publish 2");
 
-        assert_eq!(
-            db.get_session_ids().unwrap(),
-            vec!["Session 1", "Session 2"]
-        )
+        let mut session_ids = db.get_session_ids().unwrap();
+        session_ids.sort();
+        assert_eq!(session_ids, vec!["Session 1", "Session 2"])
+    }
+
+    #[test]
+    fn test_invalid_session_id() {
+        let dir = tempdir().unwrap();
+        let db = DB::new(dir.path()).expect("Make db");
+        testing::check_invalid_session_id(&db, dir.path());
+    }
+
+    #[test]
+    fn test_session_ids_stay_inside_store() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("store");
+        create_dir(&path).unwrap();
+        let db = DB::new(&path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let long = "x".repeat(300);
+        let session_ids = ["a/b", "Session 1", "session 1", "Session%201", &long];
+        for session_id in session_ids.iter() {
+            db.write(session_id, faker.make_fake_publish(vec![1]))
+                .expect("Publish");
+        }
+        db.checkpoint().unwrap();
+        drop(db);
+        let db = DB::new(&path).expect("Open db");
+
+        let mut stored = db.get_session_ids().unwrap();
+        stored.sort();
+        let mut expected = session_ids.to_vec();
+        expected.sort();
+        assert_eq!(stored, expected);
+        for session_id in session_ids.iter() {
+            assert_eq!(db.read(session_id).unwrap().len(), 1);
+        }
+
+        // Names can't differ only in case
+        let mut names: Vec<String> = read_dir(path.join("Sessions"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_lowercase())
+            .collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), session_ids.len() + 1);
+
+        // The long id's file goes with it
+        SessionStore::remove_session(&db, &long).unwrap();
+        assert_eq!(read_dir(path.join("Sessions")).unwrap().count(), 4);
     }
 
     #[test]
     fn test_get_session_payload_ids() {
-        let path = tempdir().unwrap().into_path();
-        let mut db = DB::new(&path).expect("Make db");
+        let path = tempdir().unwrap().keep();
+        let db = DB::new(&path).expect("Make db");
         let mut faker = Faker::new();
 
         let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
@@ -412,16 +2082,468 @@ mod tests {
 
     #[test]
     fn test_get_payload_ids() {
-        let path = tempdir().unwrap().into_path();
-        let mut db = DB::new(&path).expect("Make db");
+        let path = tempdir().unwrap().keep();
+        let db = DB::new(&path).expect("Make db");
         let mut faker = Faker::new();
 
         let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
         db.write("Session 1", publish.clone()).expect("Publish 1");
+        assert!(db.get_payload_ids().unwrap().is_empty());
 
+        db.checkpoint().expect("Checkpoint");
         assert_eq!(db.get_payload_ids().unwrap(), vec![publish.payload.id])
     }
 
+    #[test]
+    fn test_session_store() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = <DB as SessionStore>::open(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
+        db.append("Session 1", publish.clone()).expect("Publish 1");
+        db.append("Session 2", publish).expect("Publish 2");
+
+        let mut sessions = db.list_sessions().unwrap();
+        sessions.sort();
+        assert_eq!(sessions, vec!["Session 1", "Session 2"]);
+
+        db.remove_session("Session 1").expect("Remove session");
+        assert_eq!(db.list_sessions().unwrap(), vec!["Session 2"]);
+        assert_eq!(SessionStore::read(&db, "Session 1").unwrap().len(), 0);
+
+        db.remove_session("Session 2").expect("Remove session");
+        SessionStore::clean(&db).expect("Clean");
+        assert_eq!(db.get_payload_ids().unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_async_store() {
+        let dir = tempdir().unwrap();
+        let store = session_store::AsyncStore::<DB>::open(dir.path().to_owned())
+            .await
+            .expect("Make db");
+        let mut faker = Faker::new();
+
+        let first = faker.make_fake_publish(vec![1, 2, 3]);
+        let second = faker.make_fake_publish(vec![4, 5, 6]);
+        store
+            .append_fanout(first.clone(), &["Session 1", "Session 2"])
+            .await
+            .expect("Fan out");
+        store
+            .append("Session 1", second.clone())
+            .await
+            .expect("Publish");
+        store.ack("Session 1", first.packet_id).await.expect("Ack");
+        store.run(|db| db.checkpoint()).await.expect("Checkpoint");
+
+        assert_eq!(store.read("Session 1").await.unwrap(), vec![second]);
+        assert_eq!(store.read("Session 2").await.unwrap(), vec![first]);
+
+        store.remove_session("Session 2").await.expect("Remove");
+        store.clean().await.expect("Clean");
+        assert_eq!(store.list_sessions().await.unwrap(), vec!["Session 1"]);
+        assert_eq!(store.inner().get_payload_ids().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_inspect_payloads() {
+        let dir = tempdir().unwrap();
+        let db = DB::new(dir.path()).expect("Make db");
+        let mut faker = Faker::new();
+
+        let shared = faker.make_fake_publish(vec![1, 2, 3]);
+        let acked = faker.make_fake_publish(vec![4, 5]);
+        db.write("Session 1", shared.clone()).expect("Publish 1");
+        db.write("Session 2", shared.clone()).expect("Publish 2");
+        db.write("Session 1", acked.clone()).expect("Publish 3");
+        db.checkpoint().expect("Checkpoint");
+        db.ack("Session 1", acked.packet_id).expect("Ack");
+
+        assert_eq!(
+            db.payloads().unwrap(),
+            vec![
+                PayloadInfo {
+                    key: shared.payload.id.to_string(),
+                    stored_bytes: seal(&[1, 2, 3]).len() as u64,
+                    refcount: 2,
+                },
+                PayloadInfo {
+                    key: acked.payload.id.to_string(),
+                    stored_bytes: seal(&[4, 5]).len() as u64,
+                    refcount: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        assert!(matches!(
+            db.write("", faker.make_fake_publish(vec![1])),
+            Err(StoreError::InvalidSessionId(_))
+        ));
+
+        let publish = faker.make_fake_publish(vec![1, 2, 3]);
+        db.write("Session 1", publish.clone()).expect("Publish 1");
+        db.checkpoint().expect("Checkpoint");
+        remove_file(path.join("Payloads").join(publish.payload.id.to_string())).unwrap();
+        assert!(matches!(
+            db.get_payload(publish.payload.id),
+            Err(StoreError::MissingPayload { payload_id }) if payload_id == publish.payload.id
+        ));
+
+        let stray = path
+            .join("Sessions")
+            .join("%53ession%201")
+            .join("Messages")
+            .join("x");
+        write(&stray, b"").unwrap();
+        match db.get_session_messages("Session 1") {
+            Err(StoreError::Corrupt { path, .. }) => assert_eq!(path, stray),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let dir = tempdir().unwrap();
+        let db = Arc::new(DB::new(dir.path()).expect("Make db"));
+        let mut shared = Faker::new().make_fake_publish(vec![1, 2, 3]);
+        shared.payload.id = 0;
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let db = db.clone();
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let mut faker = Faker::new();
+                    let name = format!("Session {}", i);
+                    for _ in 0..10 {
+                        db.write(&name, shared.clone()).expect("Publish shared");
+                        db.write("Common", faker.make_fake_publish(vec![i]))
+                            .expect("Publish common");
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        for i in 0..4 {
+            assert_eq!(db.read(&format!("Session {}", i)).unwrap().len(), 10);
+        }
+        assert_eq!(db.read("Common").unwrap().len(), 40);
+        assert_eq!(db.refcounts.lock().unwrap().counts[&shared.payload.id], 40);
+    }
+
+    #[test]
+    fn test_session_states_forgotten() {
+        let dir = tempdir().unwrap();
+        let db = DB::new(dir.path()).expect("Make db");
+        let mut faker = Faker::new();
+
+        for i in 0..10 {
+            db.read(&format!("Unknown {}", i)).unwrap();
+        }
+        assert!(lock(&db.session_states).is_empty());
+
+        db.write("Session 1", faker.make_fake_publish(vec![1]))
+            .unwrap();
+        assert_eq!(lock(&db.session_states).len(), 1);
+        SessionStore::remove_session(&db, "Session 1").unwrap();
+        assert!(lock(&db.session_states).is_empty());
+    }
+
+    #[test]
+    fn test_write_fanout() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publish = faker.make_fake_publish(vec![1, 2, 3]);
+        let session_ids = ["Session 1", "Session 2", "Session 3"];
+        db.write_fanout(publish.clone(), &session_ids)
+            .expect("Fan out");
+
+        // one record in the write-ahead log, with the payload in it once
+        assert_eq!(db.wal.len(), 1);
+        let records = read_log::<WalRecord>(&path.join("Wal")).unwrap();
+        match &records[0].0 {
+            WalRecord::Batch(writes) => {
+                let payloads = writes.iter().filter(|write| {
+                    matches!(
+                        write,
+                        WalRecord::Write {
+                            payload: Some(_),
+                            ..
+                        }
+                    )
+                });
+                assert_eq!(writes.len(), 3);
+                assert_eq!(payloads.count(), 1);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        db.checkpoint().expect("Checkpoint");
+        assert_eq!(db.get_payload_ids().unwrap(), vec![publish.payload.id]);
+        assert_eq!(db.refcounts.lock().unwrap().counts[&publish.payload.id], 3);
+        for session_id in session_ids.iter() {
+            assert_eq!(db.read(session_id).unwrap(), vec![publish.clone()]);
+        }
+
+        // a bad session id fails the whole batch
+        assert!(matches!(
+            db.write_fanout(publish, &["Session 1", ""]),
+            Err(StoreError::InvalidSessionId(_))
+        ));
+        assert_eq!(db.read("Session 1").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_write_batch_replays_whole() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let mut faker = Faker::new();
+
+        let first = faker.make_fake_publish(vec![1]);
+        let second = faker.make_fake_publish(vec![2]);
+        let db = DB::new(path).expect("Make db");
+        db.write_batch(&[
+            ("Session 1", first.clone()),
+            ("Session 2", second.clone()),
+            ("Session 1", second.clone()),
+        ])
+        .expect("Batch");
+        mem::forget(db);
+
+        let db = DB::new(path).expect("Reopen db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![first, second.clone()]);
+        assert_eq!(db.read("Session 2").unwrap(), vec![second.clone()]);
+        assert_eq!(db.refcounts.lock().unwrap().counts[&second.payload.id], 2);
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let mut faker = Faker::new();
+
+        let shared = faker.make_fake_publish(vec![1, 2, 3]);
+        let acked = faker.make_fake_publish(vec![4, 5, 6]);
+        let db = DB::new(path).expect("Make db");
+        db.write_fanout(shared.clone(), &["Session 1", "Session 2", "Session 3"])
+            .expect("Fanout");
+        db.checkpoint().expect("Checkpoint");
+        db.write("Session 1", acked.clone()).expect("Publish");
+        db.ack("Session 1", acked.packet_id).expect("Ack");
+        db.remove_session("Session 2").expect("Remove");
+        // Left for the next open to replay, with a torn record at its end
+        mem::forget(db);
+        let mut wal = read(path.join("Wal")).unwrap();
+        wal.extend_from_slice(&[1, 2, 3]);
+        write(path.join("Wal"), &wal).unwrap();
+        let files = read_tree(path);
+
+        let db = DB::open_read_only(path).expect("Open db");
+        let mut session_ids = db.list_sessions().unwrap();
+        session_ids.sort();
+        assert_eq!(session_ids, vec!["Session 1", "Session 3"]);
+        assert_eq!(db.read("Session 1").unwrap(), vec![shared.clone()]);
+        assert!(db.read("Session 2").unwrap().is_empty());
+        let payloads = db.payloads().unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].refcount, 2);
+        assert!(matches!(
+            db.write("Session 1", acked.clone()),
+            Err(StoreError::ReadOnly)
+        ));
+        assert!(matches!(db.clean(), Err(StoreError::ReadOnly)));
+        drop(db);
+        assert_eq!(read_tree(path), files);
+
+        let db = DB::new(path).expect("Reopen db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![shared]);
+        assert!(db.read("Session 2").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_open_read_only_needs_current_version() {
+        let dir = tempdir().unwrap();
+        write_tree(
+            dir.path(),
+            &read_tree(&golden_dir(env!("CARGO_MANIFEST_DIR"), "v3")),
+        );
+        let files = read_tree(dir.path());
+
+        assert!(matches!(
+            DB::open_read_only(dir.path()),
+            Err(StoreError::UnsupportedVersion { version: 3, .. })
+        ));
+        assert_eq!(read_tree(dir.path()), files);
+    }
+
+    #[test]
+    fn test_failed_write_releases_references() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let shared = faker.make_fake_publish(vec![1, 2, 3]);
+        let new = faker.make_fake_publish(vec![4, 5, 6]);
+        db.write("Session 1", shared.clone()).expect("Publish");
+        db.checkpoint().expect("Checkpoint");
+
+        // the write-ahead log can't be appended to
+        create_dir(path.join("Wal")).unwrap();
+        assert!(db
+            .write_batch(&[("Session 2", shared.clone()), ("Session 2", new.clone())])
+            .is_err());
+
+        let refcounts = db.refcounts.lock().unwrap();
+        assert_eq!(refcounts.counts[&shared.payload.id], 1);
+        assert_eq!(
+            refcounts.counts.get(&new.payload.id).copied().unwrap_or(0),
+            0
+        );
+        drop(refcounts);
+        assert!(!db
+            .pending
+            .lock()
+            .unwrap()
+            .payloads
+            .contains_key(&new.payload.id));
+        assert!(db.read("Session 2").unwrap().is_empty());
+        mem::forget(db);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let kept = faker.make_fake_publish(vec![1, 2, 3]);
+        let acked = faker.make_fake_publish(vec![4, 5, 6]);
+        db.write("Session 1", kept.clone()).expect("Publish 1");
+        db.write("Session 1", acked.clone()).expect("Publish 2");
+        db.ack("Session 1", acked.packet_id).expect("Ack");
+
+        // only the write-ahead log has been written to
+        assert!(path.join("Wal").exists());
+        assert!(db.get_payload_ids().unwrap().is_empty());
+        assert_eq!(db.list_sessions().unwrap(), vec!["Session 1"]);
+        assert_eq!(db.read("Session 1").unwrap(), vec![kept.clone()]);
+
+        // the acked message and its payload never get a file
+        db.checkpoint().expect("Checkpoint");
+        assert!(!path.join("Wal").exists());
+        assert_eq!(db.get_payload_ids().unwrap(), vec![kept.payload.id]);
+        assert_eq!(db.get_session_messages("Session 1").unwrap().len(), 1);
+        assert_eq!(db.read("Session 1").unwrap(), vec![kept]);
+        assert!(!db.refcounts.lock().unwrap().contains(acked.payload.id));
+    }
+
+    #[test]
+    fn test_wal_replay() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let mut faker = Faker::new();
+
+        let shared = faker.make_fake_publish(vec![1, 2, 3]);
+        let acked = faker.make_fake_publish(vec![4, 5, 6]);
+        let db = DB::new(path).expect("Make db");
+        db.write("Session 1", shared.clone()).expect("Publish 1");
+        db.write("Session 2", acked.clone()).expect("Publish 2");
+        db.checkpoint().expect("Checkpoint");
+        db.write("Session 2", shared.clone()).expect("Publish 3");
+        db.ack("Session 2", acked.packet_id).expect("Ack");
+        db.write("Session 3", shared.clone()).expect("Publish 4");
+        db.remove_session("Session 1").expect("Remove session");
+        // crash, skipping the checkpoint on drop
+        mem::forget(db);
+
+        let db = DB::new(path).expect("Reopen db");
+        assert!(!path.join("Wal").exists());
+        let mut sessions = db.list_sessions().unwrap();
+        sessions.sort();
+        assert_eq!(sessions, vec!["Session 2", "Session 3"]);
+        assert_eq!(db.read("Session 2").unwrap(), vec![shared.clone()]);
+        assert_eq!(db.read("Session 3").unwrap(), vec![shared.clone()]);
+        assert_eq!(db.refcounts.lock().unwrap().counts[&shared.payload.id], 2);
+        assert_eq!(db.refcounts.lock().unwrap().counts[&acked.payload.id], 0);
+
+        db.clean().expect("Clean");
+        assert_eq!(db.get_payload_ids().unwrap(), vec![shared.payload.id]);
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let dir = tempdir().unwrap();
+        let db = DB::new(dir.path()).expect("Make db");
+        let imported_dir = tempdir().unwrap();
+        let imported = DB::new(imported_dir.path()).expect("Make db");
+        testing::check_archive_round_trip(&db, &imported);
+    }
+
+    #[test]
+    fn test_golden_old_versions_migrate() {
+        for version in ["v0", "v1", "v2", "v3"] {
+            let dir = tempdir().unwrap();
+            write_tree(
+                dir.path(),
+                &read_tree(&golden_dir(env!("CARGO_MANIFEST_DIR"), version)),
+            );
+
+            let db = DB::new(dir.path()).expect("Make db");
+            assert_golden_contents(&db);
+            assert_eq!(
+                read_tree(dir.path()),
+                read_tree(&golden_dir(env!("CARGO_MANIFEST_DIR"), "v4")),
+                "{}",
+                version
+            );
+        }
+    }
+
+    /// Set `UPDATE_GOLDEN=1` to rewrite `testdata/v4` after a deliberate
+    /// format change, along with a new version and migration.
+    #[test]
+    fn test_golden_v4() {
+        let dir = tempdir().unwrap();
+        golden_fill(&DB::new(dir.path()).expect("Make db"));
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            let _ = remove_dir_all(golden_dir(env!("CARGO_MANIFEST_DIR"), "v4"));
+            write_tree(
+                &golden_dir(env!("CARGO_MANIFEST_DIR"), "v4"),
+                &read_tree(dir.path()),
+            );
+        }
+        assert_eq!(
+            read_tree(dir.path()),
+            read_tree(&golden_dir(env!("CARGO_MANIFEST_DIR"), "v4"))
+        );
+
+        let dir = tempdir().unwrap();
+        write_tree(
+            dir.path(),
+            &read_tree(&golden_dir(env!("CARGO_MANIFEST_DIR"), "v4")),
+        );
+        assert_golden_contents(&DB::new(dir.path()).expect("Make db"));
+    }
+
     struct Faker {
         packet_id: u16,
         payload_id: u64,
@@ -0,0 +1,178 @@
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::DB;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GcConfig {
+    /// Time to wait between garbage collection passes.
+    pub interval: Duration,
+    /// Most payloads deleted per pass, bounding how long a pass takes.
+    pub max_payloads_per_tick: usize,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            interval: Duration::from_secs(10),
+            max_payloads_per_tick: 1000,
+        }
+    }
+}
+
+/// Totals over every pass run by a `GcWorker`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GcStats {
+    pub ticks: u64,
+    pub payloads_deleted: u64,
+    pub bytes_reclaimed: u64,
+    pub duration: Duration,
+    pub errors: u64,
+    pub last_error: Option<String>,
+}
+
+/// Runs `DB::clean_some` on a background thread until shut down or dropped.
+pub struct GcWorker {
+    stats: Arc<Mutex<GcStats>>,
+    shutdown: Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl GcWorker {
+    pub fn spawn(db: Arc<DB>, config: GcConfig) -> Self {
+        let stats = Arc::new(Mutex::new(GcStats::default()));
+        let (shutdown, shutdown_rx) = channel();
+
+        let thread_stats = stats.clone();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = shutdown_rx.recv_timeout(config.interval) {
+                let result = db
+                    .clean_some(config.max_payloads_per_tick)
+                    .map_err(|e| e.to_string());
+
+                let mut stats = match thread_stats.lock() {
+                    Ok(stats) => stats,
+                    Err(_) => break,
+                };
+                stats.ticks += 1;
+                match result {
+                    Ok(clean) => {
+                        stats.payloads_deleted += clean.payloads_deleted;
+                        stats.bytes_reclaimed += clean.bytes_reclaimed;
+                        stats.duration += clean.duration;
+                    }
+                    Err(e) => {
+                        stats.errors += 1;
+                        stats.last_error = Some(e);
+                    }
+                }
+            }
+        });
+
+        GcWorker {
+            stats,
+            shutdown,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    /// Stops the worker, waiting for any pass in progress to finish.
+    pub fn shutdown(mut self) -> GcStats {
+        self.stop();
+        self.stats()
+    }
+
+    fn stop(&mut self) {
+        let _ = self.shutdown.send(());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for GcWorker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Payload, Publish};
+    use session_store::format::seal;
+    use std::time::Instant;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_worker_collects_garbage() {
+        let dir = tempdir().unwrap();
+        let db = Arc::new(DB::new(dir.path()).expect("Make db"));
+
+        for i in 0..10 {
+            let publish = Publish {
+                packet_id: i,
+                retain: false,
+                topic_name: "fake".to_owned(),
+                payload: Payload {
+                    id: i as u64,
+                    bytes: Arc::new(vec![0; 10]),
+                },
+            };
+            db.write("Session 1", publish).expect("Publish");
+        }
+        db.checkpoint().expect("Checkpoint");
+        for i in 0..10 {
+            db.ack("Session 1", i).expect("Ack");
+        }
+
+        let worker = GcWorker::spawn(
+            db,
+            GcConfig {
+                interval: Duration::from_millis(1),
+                max_payloads_per_tick: 3,
+            },
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while worker.stats().payloads_deleted < 10 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let stats = worker.shutdown();
+        assert_eq!(stats.payloads_deleted, 10);
+        assert_eq!(stats.bytes_reclaimed, 10 * seal(&[0; 10]).len() as u64);
+        assert!(stats.ticks >= 4);
+        assert_eq!(stats.errors, 0);
+        assert_eq!(read_dir_count(&dir.path().join("Payloads")), 0);
+    }
+
+    #[test]
+    fn test_shutdown_is_prompt() {
+        let dir = tempdir().unwrap();
+        let db = Arc::new(DB::new(dir.path()).expect("Make db"));
+
+        let worker = GcWorker::spawn(
+            db,
+            GcConfig {
+                interval: Duration::from_secs(3600),
+                max_payloads_per_tick: 1,
+            },
+        );
+
+        let start = Instant::now();
+        let stats = worker.shutdown();
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert_eq!(stats.ticks, 0);
+    }
+
+    fn read_dir_count(path: &std::path::Path) -> usize {
+        std::fs::read_dir(path).unwrap().count()
+    }
+}
@@ -12,82 +12,1051 @@ use std::path::*;
 use std::sync::*;
 use std::*;
 
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "tokio")]
+pub use session_store::AsyncStore;
+pub use session_store::{
+    CorruptionPolicy, Durability, Inspect, Payload, PayloadInfo, Publish, SessionStore,
+    StoreConfig, StoreError,
+};
+
+use serde::de::DeserializeOwned;
+use session_store::format::{
+    self, add_v1_header, encode_session_names, escape_uppercase_names, Migration, HEADER_LEN,
+};
+use session_store::log::{
+    append_log, decode_record, encode_record, new_log, peek_log, read_log, scan_log,
+    upgrade_log_v1, upgrade_log_v2, ScannedLog,
+};
+use session_store::{
+    encode_session_id, is_session_id_file, is_temp_file, list_session_ids, lock, remove_session_id,
+    save_session_id, sync_dir, write_append, write_atomic,
+};
+
+/// Compaction is skipped until a session log holds at least this many dead records.
+const MIN_COMPACTION_RECORDS: usize = 32;
+
+/// One entry in a session's append-only log.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+enum Record {
+    Publish(DiskPublish),
+    /// Tombstone removing the oldest live publish with this packet id.
+    Ack(u16),
+}
+
+/// A publish as stored in a session log, with its payload kept once in the
+/// shared payload file and referenced by content hash.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct DiskPublish {
+    packet_id: u16,
+    retain: bool,
+    topic_name: String,
+    payload_id: u64,
+    payload_key: PayloadKey,
+}
+
+/// Identifies a stored payload: its content hash, plus a slot telling apart
+/// different payloads whose hashes collide.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+struct PayloadKey {
+    hash: u64,
+    slot: u32,
+}
+
+/// Buckets payloads by hash, mapping each slot to the offset and length of its
+/// record in the payload file.
+type PayloadIndex = HashMap<u64, BTreeMap<u32, (u64, usize)>>;
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub struct Publish {
-    pub packet_id: u16,
-    pub retain: bool,
-    pub topic_name: String,
-    pub payload: Arc<Vec<u8>>,
+struct PayloadRecord {
+    key: PayloadKey,
+    bytes: Vec<u8>,
+}
+
+/// A batch appended to several session logs, recorded before any of them is
+/// appended to so it can be redone after a crash, see `DB::append_batch`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct BatchRecord {
+    /// Each session's records, with how long its log was beforehand, or
+    /// `None` if it had none.
+    sessions: Vec<(String, Option<u64>, Vec<Record>)>,
+}
+
+/// What is known about a session log without re-reading it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct LogState {
+    records: usize,
+    live: usize,
+}
+
+impl LogState {
+    fn dead(&self) -> usize {
+        self.records - self.live
+    }
+
+    fn needs_compaction(&self) -> bool {
+        self.dead() >= MIN_COMPACTION_RECORDS && self.dead() >= self.live
+    }
+}
+
+/// The shared payload file and its index.
+struct PayloadFile {
+    path: PathBuf,
+    durability: Durability,
+    read_only: bool,
+    /// Loaded on first use.
+    index: Option<PayloadIndex>,
+    /// Keys handed out to writers while `compact_payloads` scans the sessions,
+    /// which must survive the rewrite even if the scan missed them.
+    handed_out: Option<HashSet<PayloadKey>>,
+}
+
+impl PayloadFile {
+    fn index(&mut self) -> Result<&mut PayloadIndex, StoreError> {
+        if self.index.is_none() {
+            let mut index = PayloadIndex::new();
+            // Corrupt records are left out, so they read as missing payloads
+            for entry in scan::<PayloadRecord>(&self.path, self.read_only)?.entries {
+                index
+                    .entry(entry.record.key.hash)
+                    .or_default()
+                    .insert(entry.record.key.slot, (entry.offset, entry.len));
+            }
+
+            self.index = Some(index);
+        }
+
+        Ok(self.index.as_mut().expect("payload index loaded"))
+    }
+
+    fn append(&mut self, key: PayloadKey, payload: &[u8]) -> Result<(), StoreError> {
+        let mut bytes = Vec::new();
+        let record = PayloadRecord {
+            key,
+            bytes: payload.to_vec(),
+        };
+        encode_record(&record, &mut bytes)?;
+
+        let offset = append_log(&self.path, &bytes, self.durability)?;
+
+        self.index()?
+            .entry(key.hash)
+            .or_default()
+            .insert(key.slot, (offset, bytes.len()));
+
+        Ok(())
+    }
+
+    /// Returns `None` if no payload is stored under `key`.
+    fn read(&mut self, key: PayloadKey) -> Result<Option<Vec<u8>>, StoreError> {
+        let location = self
+            .index()?
+            .get(&key.hash)
+            .and_then(|bucket| bucket.get(&key.slot))
+            .cloned();
+        let (offset, len) = match location {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+
+        let mut buffer = vec![0; len];
+        let mut file = File::open(&self.path)?;
+        file.seek(io::SeekFrom::Start(offset))?;
+        file.read_exact(&mut buffer)?;
+
+        match decode_record::<PayloadRecord>(&self.path, offset, &buffer)? {
+            Some((record, _)) if record.key == key => Ok(Some(record.bytes)),
+            _ => Err(StoreError::corrupt(
+                &self.path,
+                offset,
+                format!("expected payload {:?}", key),
+            )),
+        }
+    }
+}
+
+/// Format version of the store written by this build. Version 0 is the
+/// unversioned layout, with raw session ids as file names and no headers.
+/// Version 1 added headers, version 2 checksums, and version 3 escaped
+/// uppercase letters in file names and hashed long ones. Version 4 checksums
+/// the length of every log record.
+pub const FORMAT_VERSION: u16 = 4;
+
+/// Upgrades from each older format version, see `format::migrate`.
+const MIGRATIONS: &[Migration] = &[migrate_v0, migrate_v1, migrate_v2, migrate_v3];
+
+/// Encodes session file names and adds headers to the session logs and the
+/// payload log.
+fn migrate_v0(root: &Path, durability: Durability) -> Result<(), StoreError> {
+    encode_session_names(&root.join("Sessions"), durability, |session| {
+        add_v1_header(session, durability)
+    })?;
+
+    let payloads = root.join("Payloads");
+    if payloads.exists() {
+        add_v1_header(&payloads, durability)?;
+    }
+
+    Ok(())
+}
+
+/// Adds checksums to every record of the session logs and the payload log.
+fn migrate_v1(root: &Path, durability: Durability) -> Result<(), StoreError> {
+    upgrade_logs(root, durability, upgrade_log_v1)
+}
+
+/// Renames session logs so ids differing only in case can't share one on a
+/// case-insensitive filesystem. Quarantined copies keep their names.
+fn migrate_v2(root: &Path, durability: Durability) -> Result<(), StoreError> {
+    escape_uppercase_names(&root.join("Sessions"), durability)
+}
+
+/// Adds checksums to the record lengths of the session logs and the payload
+/// log.
+fn migrate_v3(root: &Path, durability: Durability) -> Result<(), StoreError> {
+    upgrade_logs(root, durability, upgrade_log_v2)
+}
+
+/// Runs `upgrade` on every session log and the payload log.
+fn upgrade_logs(
+    root: &Path,
+    durability: Durability,
+    upgrade: fn(&Path, Durability) -> Result<(), StoreError>,
+) -> Result<(), StoreError> {
+    let sessions = root.join("Sessions");
+    if sessions.exists() {
+        for entry in read_dir(&sessions)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if !is_temp_file(&name) && !is_session_id_file(&name) {
+                upgrade(&path, durability)?;
+            }
+        }
+    }
+
+    let payloads = root.join("Payloads");
+    if payloads.exists() {
+        upgrade(&payloads, durability)?;
+    }
+
+    Ok(())
+}
+
+/// Scans a log, cutting off a record torn by a crash at its end unless the
+/// store is read-only.
+fn scan<T>(path: &Path, read_only: bool) -> Result<ScannedLog<T>, StoreError>
+where
+    T: DeserializeOwned,
+{
+    if read_only {
+        peek_log(path)
+    } else {
+        scan_log(path)
+    }
+}
+
+/// Log state of a session, `None` until its log is first replayed.
+type SessionLock = Arc<Mutex<Option<LogState>>>;
+
+/// A session's lock, dropped from `DB::logs` by the last user once its log
+/// isn't loaded, so ids that are only read or removed don't pile up.
+struct Session<'a> {
+    db: &'a DB,
+    session_id: &'a str,
+    state: SessionLock,
+}
+
+impl ops::Deref for Session<'_> {
+    type Target = Mutex<Option<LogState>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        let mut logs = lock(&self.db.logs);
+        // Only the map and this handle hold it, so nobody has it locked
+        if Arc::strong_count(&self.state) == 2 && lock(&self.state).is_none() {
+            logs.remove(self.session_id);
+        }
+    }
 }
 
+/// Safe to share between threads. Each session log has its own lock, taken
+/// before the payload file's lock whenever both are needed.
 pub struct DB {
-    loaded_payloads: HashMap<u64, Weak<Vec<u8>>>,
-    location: PathBuf,
+    loaded_payloads: Mutex<HashMap<PayloadKey, Weak<Vec<u8>>>>,
+    hasher: fn(&[u8]) -> u64,
+    sessions: PathBuf,
+    /// Copies of session logs with unreadable records, see `CorruptionPolicy`.
+    corrupt: PathBuf,
+    durability: Durability,
+    on_corrupt: CorruptionPolicy,
+    logs: Mutex<HashMap<String, SessionLock>>,
+    payloads: Mutex<PayloadFile>,
+    /// Serializes `compact_payloads`, which tracks handed out keys in one set.
+    compacting: Mutex<()>,
+    /// Batches of several publishes being appended, see `append_batch`.
+    batches: PathBuf,
+    /// Names the next file in `batches`.
+    next_batch: AtomicU64,
+    /// Set by `open_read_only`, which leaves every file as it is.
+    read_only: bool,
 }
 
 impl DB {
-    pub fn new(location: &Path) -> Self {
+    pub fn new(location: &Path) -> Result<Self, StoreError> {
+        Self::with_config(location, StoreConfig::default())
+    }
+
+    pub fn with_durability(location: &Path, durability: Durability) -> Result<Self, StoreError> {
+        let config = StoreConfig {
+            durability,
+            ..StoreConfig::default()
+        };
+        Self::with_config(location, config)
+    }
+
+    /// Creates `location` if needed, upgrading a store written by an older
+    /// version.
+    pub fn with_config(location: &Path, config: StoreConfig) -> Result<Self, StoreError> {
+        let durability = config.durability;
+        if !location.exists() {
+            create_dir_all(location)?;
+        }
+        format::migrate(location, durability, FORMAT_VERSION, MIGRATIONS)?;
+
+        let db = Self::with_files(location, config, false);
+        db.finish_batches()?;
+
+        Ok(db)
+    }
+
+    /// Opens an existing store to look at without changing any of its files.
+    /// It isn't upgraded, and fails if it needs to be. Records torn by a crash
+    /// and batches it interrupted are left as they are, and writes fail with
+    /// `StoreError::ReadOnly`.
+    pub fn open_read_only(location: &Path) -> Result<Self, StoreError> {
+        format::check_version(location, FORMAT_VERSION)?;
+
+        Ok(Self::with_files(location, StoreConfig::default(), true))
+    }
+
+    fn with_files(location: &Path, config: StoreConfig, read_only: bool) -> Self {
         Self {
-            location: location.to_owned(),
-            loaded_payloads: HashMap::new(),
+            loaded_payloads: Mutex::new(HashMap::new()),
+            hasher: Self::content_hash,
+            sessions: location.join("Sessions"),
+            corrupt: location.join("Corrupt"),
+            durability: config.durability,
+            on_corrupt: config.on_corrupt,
+            logs: Mutex::new(HashMap::new()),
+            payloads: Mutex::new(PayloadFile {
+                path: location.join("Payloads"),
+                durability: config.durability,
+                read_only,
+                index: None,
+                handed_out: None,
+            }),
+            compacting: Mutex::new(()),
+            batches: location.join("Batches"),
+            next_batch: AtomicU64::new(0),
+            read_only,
         }
     }
 
-    pub fn write(&mut self, session_id: &str, publish: &[Publish]) -> Result<(), Box<dyn Error>> {
-        let bytes = bincode::serialize(publish)?;
+    /// Replaces the whole contents of a session.
+    pub fn write(&self, session_id: &str, publish: &[Publish]) -> Result<(), StoreError> {
+        self.check_writable()?;
 
-        OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(self.location.join(session_id))?
-            .write_all(&bytes)?;
+        let session = self.session(session_id)?;
+        let mut state = lock(&session);
+
+        let mut bytes = new_log();
+        for p in publish {
+            let body = self.store_payload(p)?;
+            encode_record(&Record::Publish(body), &mut bytes)?;
+        }
+        self.write_session(session_id, &bytes)?;
+
+        *state = Some(LogState {
+            records: publish.len(),
+            live: publish.len(),
+        });
 
         Ok(())
     }
 
-    pub fn read(&mut self, session_id: &str) -> Result<Vec<Publish>, Box<dyn Error>> {
-        let mut publishes: Vec<Publish> =
-            bincode::deserialize_from(File::open(self.location.join(session_id))?)?;
+    /// Adds a publish to the end of a session without rewriting the session.
+    pub fn append(&self, session_id: &str, publish: Publish) -> Result<(), StoreError> {
+        self.check_writable()?;
 
-        for mut publish in publishes.iter_mut() {
-            let hash = Self::calculate_hash(&publish.payload);
-            if let Some(payload) = self.loaded_payloads.get(&hash) {
-                if let Some(payload) = payload.upgrade() {
-                    // Hash collision
-                    if publish.payload == payload {
-                        publish.payload = payload;
-                        continue;
+        let session = self.session(session_id)?;
+        let mut state = lock(&session);
+
+        let body = self.store_payload(&publish)?;
+        let state = self.append_records(session_id, &mut state, &[Record::Publish(body)])?;
+        state.live += 1;
+
+        Ok(())
+    }
+
+    /// Appends `publish` to each of `session_ids`, storing its payload once.
+    pub fn append_fanout(&self, publish: Publish, session_ids: &[&str]) -> Result<(), StoreError> {
+        let writes: Vec<(&str, Publish)> = session_ids
+            .iter()
+            .map(|session_id| (*session_id, publish.clone()))
+            .collect();
+        self.append_batch(&writes)
+    }
+
+    /// Appends several publishes, in order, possibly to different sessions.
+    /// A payload shared by several of them is looked up and stored once, and
+    /// each session's publishes are appended in a single write. A batch of
+    /// more than one publish is recorded in `Batches/` first, and redone when
+    /// the store is next opened if a crash left only part of it written, so
+    /// after a crash either all of the batch is there or none is.
+    pub fn append_batch(&self, writes: &[(&str, Publish)]) -> Result<(), StoreError> {
+        self.check_writable()?;
+
+        // Sessions are locked in order, so concurrent batches can't deadlock
+        let mut session_ids: Vec<&str> = writes.iter().map(|(session_id, _)| *session_id).collect();
+        session_ids.sort_unstable();
+        session_ids.dedup();
+        let sessions = session_ids
+            .iter()
+            .map(|session_id| Ok((*session_id, self.session(session_id)?)))
+            .collect::<Result<Vec<_>, StoreError>>()?;
+        let mut states: HashMap<&str, MutexGuard<'_, Option<LogState>>> = sessions
+            .iter()
+            .map(|(session_id, session)| (*session_id, lock(session)))
+            .collect();
+
+        // Keyed by the bytes' address, since fanned out publishes share them
+        let mut keys: HashMap<*const Vec<u8>, PayloadKey> = HashMap::new();
+        let mut records: BTreeMap<&str, Vec<Record>> = BTreeMap::new();
+        for (session_id, publish) in writes {
+            let bytes = &publish.payload.bytes;
+            let payload_key = match keys.get(&Arc::as_ptr(bytes)) {
+                Some(key) => *key,
+                None => {
+                    let key = self.store_bytes(bytes)?;
+                    keys.insert(Arc::as_ptr(bytes), key);
+                    key
+                }
+            };
+            records
+                .entry(session_id)
+                .or_default()
+                .push(Record::Publish(DiskPublish {
+                    packet_id: publish.packet_id,
+                    retain: publish.retain,
+                    topic_name: publish.topic_name.clone(),
+                    payload_id: publish.payload.id,
+                    payload_key,
+                }));
+        }
+
+        let batch = if writes.len() > 1 {
+            // Torn tails are cut off first, so the lengths recorded are final
+            for (session_id, state) in states.iter_mut() {
+                if state.is_none() {
+                    self.load(session_id, state)?;
+                }
+            }
+            Some(self.record_batch(&records)?)
+        } else {
+            None
+        };
+
+        for (session_id, records) in records {
+            let state = states.get_mut(session_id).expect("session locked");
+            let state = self.append_records(session_id, state, &records)?;
+            state.live += records.len();
+        }
+
+        // Removed while the sessions are still locked, so a later batch to
+        // them can't be redone before this one
+        if let Some(batch) = batch {
+            remove_file(batch)?;
+            sync_dir(&self.batches, self.durability)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a file in `Batches/` recording `records` and how long each
+    /// session's log is now, returning its path.
+    fn record_batch(&self, records: &BTreeMap<&str, Vec<Record>>) -> Result<PathBuf, StoreError> {
+        let mut sessions = Vec::with_capacity(records.len());
+        for (session_id, records) in records {
+            let len = match metadata(self.session_path(session_id)?) {
+                Ok(metadata) => Some(metadata.len()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+            sessions.push((session_id.to_string(), len, records.clone()));
+        }
+
+        let mut bytes = new_log();
+        encode_record(&BatchRecord { sessions }, &mut bytes)?;
+        if !self.batches.exists() {
+            create_dir_all(&self.batches)?;
+        }
+        let path = self
+            .batches
+            .join(self.next_batch.fetch_add(1, Ordering::Relaxed).to_string());
+        write_atomic(&path, &bytes, self.durability)?;
+
+        Ok(path)
+    }
+
+    /// Redoes every batch a crash interrupted, putting each session's log back
+    /// the way it was before appending the batch's records to it again.
+    fn finish_batches(&self) -> Result<(), StoreError> {
+        if !self.batches.exists() {
+            return Ok(());
+        }
+
+        for entry in read_dir(&self.batches)? {
+            let path = entry?.path();
+            if is_temp_file(&path.file_name().unwrap_or_default().to_string_lossy()) {
+                remove_file(&path)?;
+                continue;
+            }
+
+            for (batch, _) in read_log::<BatchRecord>(&path)? {
+                for (session_id, len, records) in batch.sessions {
+                    let log = self.session_path(&session_id)?;
+                    match len {
+                        Some(len) => OpenOptions::new().write(true).open(&log)?.set_len(len)?,
+                        None if log.exists() => remove_file(&log)?,
+                        None => {}
                     }
+
+                    let session = self.session(&session_id)?;
+                    let mut state = lock(&session);
+                    self.append_records(&session_id, &mut state, &records)?;
                 }
             }
+            remove_file(&path)?;
+        }
+        sync_dir(&self.batches, self.durability)?;
+
+        Ok(())
+    }
+
+    /// Writes a tombstone for the oldest publish with the given packet id,
+    /// compacting the session once enough of its log is dead.
+    pub fn ack(&self, session_id: &str, packet_id: u16) -> Result<(), StoreError> {
+        self.check_writable()?;
+
+        let session = self.session(session_id)?;
+        let mut state = lock(&session);
+
+        if !self.session_path(session_id)?.exists() {
+            return Ok(());
+        }
+
+        let log = self.append_records(session_id, &mut state, &[Record::Ack(packet_id)])?;
+        // Assumes the ack matched a queued publish, compaction recounts exactly
+        log.live = log.live.saturating_sub(1);
+
+        if log.needs_compaction() {
+            self.compact_locked(session_id, &mut state)?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites a session log with only its live publishes.
+    pub fn compact(&self, session_id: &str) -> Result<(), StoreError> {
+        self.check_writable()?;
+
+        let session = self.session(session_id)?;
+        let mut state = lock(&session);
+
+        self.compact_locked(session_id, &mut state)
+    }
+
+    /// Unreadable records and payloads are handled as the store's
+    /// `CorruptionPolicy` says.
+    pub fn read(&self, session_id: &str) -> Result<Vec<Publish>, StoreError> {
+        // Held until the payloads are loaded so compaction can't drop them
+        let session = self.session(session_id)?;
+        let mut state = lock(&session);
+
+        if !self.session_path(session_id)?.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut result = Vec::new();
+        let mut kept = Vec::new();
+        let mut dropped = false;
+        for body in self.load(session_id, &mut state)? {
+            let error = match self.get_payload(body.payload_key) {
+                Ok(Some(bytes)) => {
+                    result.push(Publish {
+                        packet_id: body.packet_id,
+                        retain: body.retain,
+                        topic_name: body.topic_name.clone(),
+                        payload: Payload {
+                            id: body.payload_id,
+                            bytes,
+                        },
+                    });
+                    kept.push(body);
+                    continue;
+                }
+                Ok(None) => StoreError::MissingPayload {
+                    payload_id: body.payload_id,
+                },
+                Err(e @ StoreError::Corrupt { .. }) => e,
+                Err(e) => return Err(e),
+            };
+
+            if self.on_corrupt == CorruptionPolicy::Error {
+                return Err(error);
+            }
+            dropped = true;
+        }
+
+        if dropped && self.on_corrupt == CorruptionPolicy::Quarantine {
+            self.quarantine(session_id)?;
+            self.write_publishes(session_id, &mut state, &kept)?;
+        }
+
+        Ok(result)
+    }
+
+    /// Rewrites the payload file keeping only payloads referenced by a session.
+    pub fn compact_payloads(&self) -> Result<(), StoreError> {
+        self.check_writable()?;
+
+        let _compacting = lock(&self.compacting);
+
+        // Sessions are scanned without holding the payload lock, which writers
+        // take while holding their session lock
+        lock(&self.payloads).handed_out = Some(HashSet::new());
+
+        let mut referenced: HashSet<PayloadKey> = HashSet::new();
+        let scanned = self.scan_references(&mut referenced);
+
+        let mut payloads = lock(&self.payloads);
+        let handed_out = payloads.handed_out.take().unwrap_or_default();
+        scanned?;
+        referenced.extend(handed_out);
+
+        let mut bytes = new_log();
+        let mut index = PayloadIndex::new();
+        // Corrupt records can't be read back anyway, so are dropped too
+        for entry in scan_log::<PayloadRecord>(&payloads.path)?.entries {
+            let record = entry.record;
+            if referenced.remove(&record.key) {
+                let offset = bytes.len();
+                encode_record(&record, &mut bytes)?;
+                index
+                    .entry(record.key.hash)
+                    .or_default()
+                    .insert(record.key.slot, (offset as u64, bytes.len() - offset));
+            }
+        }
+
+        write_atomic(&payloads.path, &bytes, self.durability)?;
+        // A dropped key can be handed out again, so must not read as its old bytes
+        lock(&self.loaded_payloads).retain(|key, _| {
+            index
+                .get(&key.hash)
+                .is_some_and(|b| b.contains_key(&key.slot))
+        });
+        payloads.index = Some(index);
+
+        Ok(())
+    }
+
+    fn scan_references(&self, referenced: &mut HashSet<PayloadKey>) -> Result<(), StoreError> {
+        // Writers register in `logs` before storing a payload, so waiting for
+        // their lock covers a session whose log they haven't created yet
+        let mut session_ids: BTreeSet<String> = self.list_sessions()?.into_iter().collect();
+        session_ids.extend(lock(&self.logs).keys().cloned());
 
-            self.loaded_payloads
-                .insert(hash, Arc::downgrade(&publish.payload));
+        for session_id in session_ids {
+            let session = self.session(&session_id)?;
+            let mut state = lock(&session);
+            if !self.session_path(&session_id)?.exists() {
+                continue;
+            }
+            for body in self.load(&session_id, &mut state)? {
+                referenced.insert(body.payload_key);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn session<'a>(&'a self, session_id: &'a str) -> Result<Session<'a>, StoreError> {
+        encode_session_id(session_id)?;
+
+        let state = lock(&self.logs)
+            .entry(session_id.to_owned())
+            .or_default()
+            .clone();
+
+        Ok(Session {
+            db: self,
+            session_id,
+            state,
+        })
+    }
+
+    fn compact_locked(
+        &self,
+        session_id: &str,
+        state: &mut Option<LogState>,
+    ) -> Result<(), StoreError> {
+        if !self.session_path(session_id)?.exists() {
+            return Ok(());
+        }
+
+        let publishes = self.load(session_id, state)?;
+        self.write_publishes(session_id, state, &publishes)
+    }
+
+    /// Replaces a session log with just `publishes`.
+    fn write_publishes(
+        &self,
+        session_id: &str,
+        state: &mut Option<LogState>,
+        publishes: &[DiskPublish],
+    ) -> Result<(), StoreError> {
+        let mut bytes = new_log();
+        for body in publishes.iter() {
+            encode_record(&Record::Publish(body.clone()), &mut bytes)?;
+        }
+        self.write_session(session_id, &bytes)?;
+
+        *state = Some(LogState {
+            records: publishes.len(),
+            live: publishes.len(),
+        });
+
+        Ok(())
+    }
+
+    /// Copies a session log into `Corrupt/` before it is rewritten without its
+    /// unreadable records, returning where to.
+    fn quarantine(&self, session_id: &str) -> Result<PathBuf, StoreError> {
+        if !self.corrupt.exists() {
+            create_dir_all(&self.corrupt)?;
+        }
+
+        // A session can be quarantined more than once, never overwrite
+        save_session_id(&self.corrupt, session_id, self.durability)?;
+        let name = encode_session_id(session_id)?;
+        let mut target = self.corrupt.join(&name);
+        let mut attempt = 0;
+        while target.exists() {
+            attempt += 1;
+            target = self.corrupt.join(format!("{}.{}", name, attempt));
+        }
+
+        let bytes = fs::read(self.session_path(session_id)?)?;
+        write_atomic(&target, &bytes, self.durability)?;
+
+        Ok(target)
+    }
+
+    /// Makes sure the publish's payload is in the payload file, returning the
+    /// session record that references it.
+    fn store_payload(&self, publish: &Publish) -> Result<DiskPublish, StoreError> {
+        Ok(DiskPublish {
+            packet_id: publish.packet_id,
+            retain: publish.retain,
+            topic_name: publish.topic_name.clone(),
+            payload_id: publish.payload.id,
+            payload_key: self.store_bytes(&publish.payload.bytes)?,
+        })
+    }
+
+    /// Makes sure a payload is in the payload file, returning its key.
+    fn store_bytes(&self, bytes: &[u8]) -> Result<PayloadKey, StoreError> {
+        let hash = (self.hasher)(bytes);
+        let mut payloads = lock(&self.payloads);
+
+        let slots: Vec<u32> = match payloads.index()?.get(&hash) {
+            Some(bucket) => bucket.keys().cloned().collect(),
+            None => Vec::new(),
+        };
+
+        let mut existing = None;
+        for slot in slots.iter() {
+            let key = PayloadKey { hash, slot: *slot };
+            let stored = self.get_payload_locked(&mut payloads, key)?;
+            if stored.is_some_and(|stored| *stored == bytes) {
+                existing = Some(key);
+                break;
+            }
+        }
+
+        let payload_key = match existing {
+            Some(key) => key,
+            None => {
+                let key = PayloadKey {
+                    hash,
+                    slot: slots.last().map_or(0, |slot| slot + 1),
+                };
+                payloads.append(key, bytes)?;
+                key
+            }
+        };
+        if let Some(handed_out) = payloads.handed_out.as_mut() {
+            handed_out.insert(payload_key);
+        }
+
+        Ok(payload_key)
+    }
+
+    fn get_payload(&self, key: PayloadKey) -> Result<Option<Arc<Vec<u8>>>, StoreError> {
+        if let Some(payload) = self.cached_payload(key) {
+            return Ok(Some(payload));
+        }
+
+        self.get_payload_locked(&mut lock(&self.payloads), key)
+    }
+
+    fn get_payload_locked(
+        &self,
+        payloads: &mut PayloadFile,
+        key: PayloadKey,
+    ) -> Result<Option<Arc<Vec<u8>>>, StoreError> {
+        if let Some(payload) = self.cached_payload(key) {
+            return Ok(Some(payload));
+        }
+
+        let bytes = match payloads.read(key)? {
+            Some(bytes) => Arc::new(bytes),
+            None => return Ok(None),
+        };
+        lock(&self.loaded_payloads).insert(key, Arc::downgrade(&bytes));
+
+        Ok(Some(bytes))
+    }
+
+    fn cached_payload(&self, key: PayloadKey) -> Option<Arc<Vec<u8>>> {
+        lock(&self.loaded_payloads)
+            .get(&key)
+            .and_then(|payload| payload.upgrade())
+    }
+
+    /// Log file of a session, named by its encoded id.
+    fn session_path(&self, session_id: &str) -> Result<PathBuf, StoreError> {
+        Ok(self.sessions.join(encode_session_id(session_id)?))
+    }
+
+    fn write_session(&self, session_id: &str, bytes: &[u8]) -> Result<(), StoreError> {
+        if !self.sessions.exists() {
+            create_dir_all(&self.sessions)?;
+        }
+        save_session_id(&self.sessions, session_id, self.durability)?;
+
+        write_atomic(&self.session_path(session_id)?, bytes, self.durability)?;
+
+        Ok(())
+    }
+
+    /// Appends `records` to a session log in a single write.
+    fn append_records<'a>(
+        &self,
+        session_id: &str,
+        state: &'a mut Option<LogState>,
+        records: &[Record],
+    ) -> Result<&'a mut LogState, StoreError> {
+        if state.is_none() {
+            // Replays the log once so a torn tail is cut off before appending to it
+            self.load(session_id, state)?;
+        }
+        if !self.sessions.exists() {
+            create_dir_all(&self.sessions)?;
+        }
+        save_session_id(&self.sessions, session_id, self.durability)?;
+
+        let mut bytes = Vec::new();
+        for record in records {
+            encode_record(record, &mut bytes)?;
+        }
+        append_log(&self.session_path(session_id)?, &bytes, self.durability)?;
+
+        let state = state.as_mut().expect("log state loaded");
+        state.records += records.len();
+
+        Ok(state)
+    }
+
+    /// Replays a session log into its live publishes.
+    fn load(
+        &self,
+        session_id: &str,
+        state: &mut Option<LogState>,
+    ) -> Result<Vec<DiskPublish>, StoreError> {
+        let scanned = scan(&self.session_path(session_id)?, self.read_only)?;
+        let damaged = !scanned.corrupt.is_empty();
+        if let Some(error) = scanned.corrupt.into_iter().next() {
+            if self.on_corrupt == CorruptionPolicy::Error {
+                return Err(error);
+            }
+        }
+
+        let mut publishes: Vec<DiskPublish> = Vec::new();
+        let mut records = 0;
+        for entry in scanned.entries {
+            match entry.record {
+                Record::Publish(publish) => publishes.push(publish),
+                Record::Ack(packet_id) => {
+                    if let Some(i) = publishes.iter().position(|p| p.packet_id == packet_id) {
+                        publishes.remove(i);
+                    }
+                }
+            }
+
+            records += 1;
+        }
+
+        *state = Some(LogState {
+            records,
+            live: publishes.len(),
+        });
+
+        if damaged && self.on_corrupt == CorruptionPolicy::Quarantine {
+            self.quarantine(session_id)?;
+            self.write_publishes(session_id, state, &publishes)?;
         }
 
         Ok(publishes)
     }
 
-    fn calculate_hash<T: Hash>(t: &T) -> u64 {
-        let mut s = DefaultHasher::new();
-        t.hash(&mut s);
-        s.finish()
+    /// Fails if the store was opened read-only.
+    fn check_writable(&self) -> Result<(), StoreError> {
+        if self.read_only {
+            return Err(StoreError::ReadOnly);
+        }
+
+        Ok(())
+    }
+
+    /// Stable across platforms and Rust releases, unlike `DefaultHasher`, since
+    /// the hash is persisted.
+    fn content_hash(bytes: &[u8]) -> u64 {
+        let mut hash = [0; 8];
+        hash.copy_from_slice(&blake3::hash(bytes).as_bytes()[..8]);
+        u64::from_le_bytes(hash)
+    }
+}
+
+impl SessionStore for DB {
+    fn open(location: &Path) -> Result<Self, StoreError> {
+        Self::new(location)
+    }
+
+    fn append(&self, session_id: &str, publish: Publish) -> Result<(), StoreError> {
+        self.append(session_id, publish)
+    }
+
+    fn append_batch(&self, writes: &[(&str, Publish)]) -> Result<(), StoreError> {
+        self.append_batch(writes)
+    }
+
+    fn read(&self, session_id: &str) -> Result<Vec<Publish>, StoreError> {
+        self.read(session_id)
+    }
+
+    fn ack(&self, session_id: &str, packet_id: u16) -> Result<(), StoreError> {
+        self.ack(session_id, packet_id)
+    }
+
+    fn remove_session(&self, session_id: &str) -> Result<(), StoreError> {
+        self.check_writable()?;
+
+        let session = self.session(session_id)?;
+        let mut state = lock(&session);
+
+        let path = self.session_path(session_id)?;
+        if path.exists() {
+            remove_file(path)?;
+        }
+        remove_session_id(&self.sessions, session_id)?;
+        *state = None;
+
+        Ok(())
+    }
+
+    fn list_sessions(&self) -> Result<Vec<String>, StoreError> {
+        list_session_ids(&self.sessions)
+    }
+
+    fn clean(&self) -> Result<(), StoreError> {
+        self.check_writable()?;
+
+        let sessions: Vec<(String, SessionLock)> = lock(&self.logs)
+            .iter()
+            .map(|(session_id, session)| (session_id.clone(), session.clone()))
+            .collect();
+        for (session_id, session) in sessions {
+            let mut state = lock(&session);
+            if state.is_some_and(|state| state.dead() > 0) {
+                self.compact_locked(&session_id, &mut state)?;
+            }
+        }
+
+        self.compact_payloads()?;
+        lock(&self.loaded_payloads).retain(|_, val| val.strong_count() > 0);
+
+        Ok(())
+    }
+}
+
+impl Inspect for DB {
+    /// Payloads are keyed by content hash and slot, as `<hash>:<slot>`.
+    fn payloads(&self) -> Result<Vec<PayloadInfo>, StoreError> {
+        let mut refcounts: HashMap<PayloadKey, u64> = HashMap::new();
+        for session_id in self.list_sessions()? {
+            let session = self.session(&session_id)?;
+            let mut state = lock(&session);
+            for body in self.load(&session_id, &mut state)? {
+                *refcounts.entry(body.payload_key).or_default() += 1;
+            }
+        }
+
+        let payloads = lock(&self.payloads);
+        let result = scan::<PayloadRecord>(&payloads.path, self.read_only)?
+            .entries
+            .into_iter()
+            .map(|entry| PayloadInfo {
+                key: format!("{:016x}:{}", entry.record.key.hash, entry.record.key.slot),
+                stored_bytes: entry.len as u64,
+                refcount: refcounts.get(&entry.record.key).copied().unwrap_or(0),
+            })
+            .collect();
+
+        Ok(result)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use session_store::format::ChecksumAlgorithm;
+    use session_store::testing::{
+        self, assert_golden_contents, golden_dir, golden_fill, read_tree, write_tree,
+    };
     use tempfile::tempdir;
 
     #[test]
     fn test_read_write() {
-        let path = tempdir().unwrap().into_path();
-        let mut db = DB::new(&path);
+        let path = tempdir().unwrap().keep();
+        let db = DB::new(&path).expect("Make db");
         let mut faker = Faker::new();
 
         let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
@@ -96,41 +1065,822 @@ mod tests {
         let stored = db.read("Session 1").unwrap();
         assert_eq!(stored.len(), 1);
         assert_eq!(stored[0].topic_name, "fake");
-        assert_eq!(stored[0].payload, Arc::new(vec![1, 2, 3, 4, 5]));
+        assert_eq!(stored[0].payload.bytes, Arc::new(vec![1, 2, 3, 4, 5]));
     }
 
     #[test]
     fn test_dedupe() {
-        let path = tempdir().unwrap().into_path();
-        let mut db = DB::new(&path);
+        let path = tempdir().unwrap().keep();
+        let db = DB::new(&path).expect("Make db");
         let mut faker = Faker::new();
 
         let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
-        db.write("Session 1", &[publish.clone()])
+        db.write("Session 1", slice::from_ref(&publish))
             .expect("Publish 1");
         db.write("Session 2", &[publish]).expect("Publish 2");
 
         db.read("Session 1").unwrap();
         db.read("Session 2").unwrap();
 
-        assert_eq!(db.loaded_payloads.len(), 1);
+        assert_eq!(db.loaded_payloads.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_write_replaces_atomically() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::with_durability(path, Durability::Flush).expect("Make db");
+        let mut faker = Faker::new();
+
+        db.write("Session 1", &[faker.make_fake_publish(vec![1, 2, 3])])
+            .expect("Publish 1");
+        db.write("Session 1", &[faker.make_fake_publish(vec![4, 5, 6])])
+            .expect("Publish 2");
+
+        // simulate a crash in the middle of an atomic write
+        File::create(path.join("Sessions").join("Session 2.tmp")).unwrap();
+
+        let stored = db.read("Session 1").unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].payload.bytes, Arc::new(vec![4, 5, 6]));
+        assert_eq!(db.list_sessions().unwrap(), vec!["Session 1"]);
+    }
+
+    #[test]
+    fn test_append_ack() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let first = faker.make_fake_publish(vec![1, 2, 3]);
+        db.append("Session 1", first.clone()).expect("Publish 1");
+        db.append("Session 1", faker.make_fake_publish(vec![4, 5, 6]))
+            .expect("Publish 2");
+        db.ack("Session 1", first.packet_id).expect("Ack");
+
+        // a fresh handle replays the log from disk
+        let db = DB::new(path).expect("Make db");
+        let stored = db.read("Session 1").unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].payload.bytes, Arc::new(vec![4, 5, 6]));
+        assert_eq!(
+            db.session("Session 1").unwrap().lock().unwrap().unwrap(),
+            LogState {
+                records: 3,
+                live: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_compaction() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::with_durability(path, Durability::None).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publishes: Vec<Publish> = (0..MIN_COMPACTION_RECORDS)
+            .map(|i| faker.make_fake_publish(vec![i as u8]))
+            .collect();
+        for publish in publishes.iter() {
+            db.append("Session 1", publish.clone()).expect("Publish");
+        }
+        for publish in publishes.iter().take(MIN_COMPACTION_RECORDS / 2) {
+            db.ack("Session 1", publish.packet_id).expect("Ack");
+        }
+
+        // Acking half the publishes leaves a mostly dead log, which gets compacted
+        assert_eq!(
+            db.session("Session 1").unwrap().lock().unwrap().unwrap(),
+            LogState {
+                records: MIN_COMPACTION_RECORDS / 2,
+                live: MIN_COMPACTION_RECORDS / 2
+            }
+        );
+
+        let stored = db.read("Session 1").unwrap();
+        assert_eq!(stored, publishes[MIN_COMPACTION_RECORDS / 2..].to_vec());
+    }
+
+    #[test]
+    fn test_clean_compacts() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publish = faker.make_fake_publish(vec![1, 2, 3]);
+        db.append("Session 1", publish.clone()).expect("Publish 1");
+        db.ack("Session 1", publish.packet_id).expect("Ack");
+        assert!(
+            metadata(path.join("Sessions").join("%53ession%201"))
+                .unwrap()
+                .len()
+                > HEADER_LEN as u64
+        );
+
+        db.clean().expect("Clean");
+        assert_eq!(
+            metadata(path.join("Sessions").join("%53ession%201"))
+                .unwrap()
+                .len(),
+            HEADER_LEN as u64
+        );
+        assert_eq!(db.read("Session 1").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_torn_tail() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        db.append("Session 1", faker.make_fake_publish(vec![1, 2, 3]))
+            .expect("Publish 1");
+        let len = metadata(path.join("Sessions").join("%53ession%201"))
+            .unwrap()
+            .len();
+
+        // simulate a crash part way through appending a record
+        OpenOptions::new()
+            .append(true)
+            .open(path.join("Sessions").join("%53ession%201"))
+            .unwrap()
+            .write_all(&[100, 0, 0, 0, 1, 2])
+            .unwrap();
+
+        let db = DB::new(path).expect("Make db");
+        db.append("Session 1", faker.make_fake_publish(vec![4, 5, 6]))
+            .expect("Publish 2");
+        assert!(
+            metadata(path.join("Sessions").join("%53ession%201"))
+                .unwrap()
+                .len()
+                > len
+        );
+
+        let stored = db.read("Session 1").unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].payload.bytes, Arc::new(vec![1, 2, 3]));
+        assert_eq!(stored[1].payload.bytes, Arc::new(vec![4, 5, 6]));
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let shared = faker.make_fake_publish(vec![1, 2, 3]);
+        db.append_fanout(shared.clone(), &["Session 1", "Session 2"])
+            .expect("Fanout");
+        drop(db);
+
+        // simulate crashes part way through appending records
+        for file in [
+            path.join("Sessions").join("%53ession%201"),
+            path.join("Payloads"),
+        ] {
+            OpenOptions::new()
+                .append(true)
+                .open(file)
+                .unwrap()
+                .write_all(&[100, 0, 0, 0, 1, 2])
+                .unwrap();
+        }
+        let files = read_tree(path);
+
+        let db = DB::open_read_only(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![shared.clone()]);
+        let payloads = db.payloads().unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].refcount, 2);
+        assert!(matches!(
+            db.append("Session 1", shared.clone()),
+            Err(StoreError::ReadOnly)
+        ));
+        assert!(matches!(db.clean(), Err(StoreError::ReadOnly)));
+        drop(db);
+        assert_eq!(read_tree(path), files);
+
+        let dir = tempdir().unwrap();
+        write_tree(
+            dir.path(),
+            &read_tree(&golden_dir(env!("CARGO_MANIFEST_DIR"), "v3")),
+        );
+        assert!(matches!(
+            DB::open_read_only(dir.path()),
+            Err(StoreError::UnsupportedVersion { version: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_record() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        db.append("Session 1", faker.make_fake_publish(vec![1, 2, 3]))
+            .expect("Publish 1");
+        let len = metadata(path.join("Sessions").join("%53ession%201"))
+            .unwrap()
+            .len();
+
+        // a complete record that doesn't decode
+        let mut frame = Vec::new();
+        ChecksumAlgorithm::Crc32c.seal(&1u32.to_le_bytes(), &mut frame);
+        frame.push(9);
+        OpenOptions::new()
+            .append(true)
+            .open(path.join("Sessions").join("%53ession%201"))
+            .unwrap()
+            .write_all(&frame)
+            .unwrap();
+
+        match DB::new(path).expect("Make db").read("Session 1") {
+            Err(StoreError::Corrupt {
+                path: p, offset, ..
+            }) => {
+                assert_eq!(p, path.join("Sessions").join("%53ession%201"));
+                assert_eq!(offset, len);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_missing_payload() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let publish = Faker::new().make_fake_publish(vec![1, 2, 3]);
+
+        db.append("Session 1", publish.clone()).expect("Publish 1");
+        remove_file(path.join("Payloads")).unwrap();
+
+        match DB::new(path).expect("Make db").read("Session 1") {
+            Err(StoreError::MissingPayload { payload_id }) => {
+                assert_eq!(payload_id, publish.payload.id)
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_checksums() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let kept = faker.make_fake_publish(vec![1, 2, 3]);
+        db.append("Session 1", kept.clone()).expect("Publish 1");
+        db.append("Session 1", faker.make_fake_publish(vec![4, 5, 6]))
+            .expect("Publish 2");
+
+        // a flipped bit in the last payload's bytes is caught, not returned as data
+        let mut bytes = read(path.join("Payloads")).unwrap();
+        let last = bytes.len() - 6;
+        bytes[last] ^= 0x10;
+        write(path.join("Payloads"), bytes).unwrap();
+
+        assert!(matches!(
+            DB::new(path).expect("Reopen db").read("Session 1"),
+            Err(StoreError::MissingPayload { .. })
+        ));
+
+        let policy = |on_corrupt| StoreConfig {
+            on_corrupt,
+            ..StoreConfig::default()
+        };
+        let db = DB::with_config(path, policy(CorruptionPolicy::Skip)).expect("Reopen db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![kept.clone()]);
+        assert!(!path.join("Corrupt").exists());
+
+        // quarantine keeps a copy and rewrites the session without the publish
+        let session = path.join("Sessions").join("%53ession%201");
+        let original = read(&session).unwrap();
+        let db = DB::with_config(path, policy(CorruptionPolicy::Quarantine)).expect("Reopen db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![kept.clone()]);
+        assert_eq!(
+            read(path.join("Corrupt").join("%53ession%201")).unwrap(),
+            original
+        );
+        assert_eq!(
+            DB::new(path).expect("Reopen db").read("Session 1").unwrap(),
+            vec![kept]
+        );
+    }
+
+    #[test]
+    fn test_inspect_payloads() {
+        let dir = tempdir().unwrap();
+        let mut db = DB::new(dir.path()).expect("Make db");
+        db.hasher = |_| 7;
+        let mut faker = Faker::new();
+
+        let shared = faker.make_fake_publish(vec![1, 2, 3]);
+        db.append("Session 1", shared.clone()).expect("Publish 1");
+        db.append("Session 2", shared).expect("Publish 2");
+        db.append("Session 2", faker.make_fake_publish(vec![4, 5]))
+            .expect("Publish 3");
+
+        let payloads = db.payloads().unwrap();
+        let keys: Vec<&str> = payloads.iter().map(|p| p.key.as_str()).collect();
+        assert_eq!(keys, vec!["0000000000000007:0", "0000000000000007:1"]);
+        assert_eq!(payloads[0].refcount, 2);
+        assert_eq!(payloads[1].refcount, 1);
+        assert_eq!(
+            payloads.iter().map(|p| p.stored_bytes).sum::<u64>() as usize,
+            metadata(dir.path().join("Payloads")).unwrap().len() as usize - HEADER_LEN
+        );
+    }
+
+    #[test]
+    fn test_invalid_session_id() {
+        let dir = tempdir().unwrap();
+        let db = DB::new(dir.path()).expect("Make db");
+        testing::check_invalid_session_id(&db, dir.path());
+    }
+
+    #[test]
+    fn test_session_ids_case_and_length() {
+        let dir = tempdir().unwrap();
+        let db = DB::new(dir.path()).expect("Make db");
+        let mut faker = Faker::new();
+
+        let long = "x".repeat(300);
+        let session_ids = ["Session 1", "session 1", "SESSION 1", &long];
+        for session_id in session_ids.iter() {
+            db.append(session_id, faker.make_fake_publish(vec![1]))
+                .expect("Publish");
+        }
+        drop(db);
+        let db = DB::new(dir.path()).expect("Open db");
+
+        let mut stored = db.list_sessions().unwrap();
+        stored.sort();
+        let mut expected = session_ids.to_vec();
+        expected.sort();
+        assert_eq!(stored, expected);
+        for session_id in session_ids.iter() {
+            assert_eq!(db.read(session_id).unwrap().len(), 1);
+        }
+
+        SessionStore::remove_session(&db, &long).unwrap();
+        assert_eq!(read_dir(dir.path().join("Sessions")).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_payload_stored_once() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publish = faker.make_fake_publish(vec![7; 1024]);
+        for i in 0..10 {
+            db.append(&format!("Session {}", i), publish.clone())
+                .expect("Publish");
+        }
+        let len = metadata(path.join("Payloads")).unwrap().len();
+        assert!(len > 1024 && len < 2048);
+
+        // A fresh handle still shares the payload between sessions
+        let db = DB::new(path).expect("Make db");
+        let first = db.read("Session 0").unwrap();
+        let last = db.read("Session 9").unwrap();
+        assert!(Arc::ptr_eq(&first[0].payload.bytes, &last[0].payload.bytes));
+        assert_eq!(first[0].payload, publish.payload);
+    }
+
+    #[test]
+    fn test_append_fanout() {
+        let dir = tempdir().unwrap();
+        let db = DB::new(dir.path()).expect("Make db");
+        let mut faker = Faker::new();
+
+        let shared = faker.make_fake_publish(vec![1, 2, 3]);
+        db.append_fanout(shared.clone(), &["Session 1", "Session 2", "Session 3"])
+            .expect("Fanout");
+        let other = faker.make_fake_publish(vec![4, 5]);
+        db.append_batch(&[("Session 2", other.clone()), ("Session 2", shared.clone())])
+            .expect("Batch");
+
+        assert_eq!(db.read("Session 1").unwrap(), vec![shared.clone()]);
+        assert_eq!(
+            db.read("Session 2").unwrap(),
+            vec![shared.clone(), other.clone(), shared.clone()]
+        );
+        let payloads = db.payloads().unwrap();
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads.iter().map(|p| p.refcount).sum::<u64>(), 5);
+
+        // an invalid session id fails the whole batch before anything is written
+        assert!(matches!(
+            db.append_fanout(other, &["Session 4", ""]),
+            Err(StoreError::InvalidSessionId(_))
+        ));
+        assert_eq!(db.list_sessions().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_append_batch_redone_after_crash() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let first = faker.make_fake_publish(vec![1, 2, 3]);
+        let second = faker.make_fake_publish(vec![4, 5]);
+        db.append("Session 1", first.clone()).expect("Publish");
+
+        // crash after the batch is recorded and only Session 1 has its share,
+        // with part of Session 2's written
+        let body = db.store_payload(&second).unwrap();
+        let mut records = BTreeMap::new();
+        records.insert("Session 1", vec![Record::Publish(body.clone())]);
+        records.insert("Session 2", vec![Record::Publish(body.clone())]);
+        db.record_batch(&records).unwrap();
+        let session = db.session("Session 1").unwrap();
+        db.append_records("Session 1", &mut lock(&session), &records["Session 1"])
+            .unwrap();
+        drop(session);
+        write(path.join("Sessions").join("%53ession%202"), &new_log()[..4]).unwrap();
+        drop(db);
+
+        let db = DB::new(path).expect("Reopen db");
+        assert_eq!(
+            db.read("Session 1").unwrap(),
+            vec![first.clone(), second.clone()]
+        );
+        assert_eq!(db.read("Session 2").unwrap(), vec![second.clone()]);
+        assert_eq!(read_dir(path.join("Batches")).unwrap().count(), 0);
+
+        // crash part way through a batch to a single session
+        let mut records = BTreeMap::new();
+        records.insert(
+            "Session 3",
+            vec![Record::Publish(body.clone()), Record::Publish(body.clone())],
+        );
+        db.record_batch(&records).unwrap();
+        let session = db.session("Session 3").unwrap();
+        db.append_records("Session 3", &mut lock(&session), &records["Session 3"][..1])
+            .unwrap();
+        drop(session);
+        drop(db);
+
+        let db = DB::new(path).expect("Reopen db");
+        assert_eq!(
+            db.read("Session 3").unwrap(),
+            vec![second.clone(), second.clone()]
+        );
+        assert_eq!(read_dir(path.join("Batches")).unwrap().count(), 0);
+
+        // a finished batch isn't redone
+        db.append_batch(&[("Session 1", first.clone()), ("Session 2", first.clone())])
+            .expect("Batch");
+        drop(db);
+        let db = DB::new(path).expect("Reopen db");
+        assert_eq!(
+            db.read("Session 2").unwrap(),
+            vec![second.clone(), first.clone()]
+        );
+        assert_eq!(read_dir(path.join("Batches")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_clean_payloads() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let acked = faker.make_fake_publish(vec![1; 1024]);
+        let kept = faker.make_fake_publish(vec![2, 3]);
+        db.append("Session 1", acked.clone()).expect("Publish 1");
+        db.append("Session 2", acked.clone()).expect("Publish 2");
+        db.append("Session 2", kept.clone()).expect("Publish 3");
+
+        // still referenced by Session 2
+        db.ack("Session 1", acked.packet_id).expect("Ack");
+        db.clean().expect("Clean");
+        assert!(metadata(path.join("Payloads")).unwrap().len() > 1024);
+
+        db.ack("Session 2", acked.packet_id).expect("Ack");
+        db.clean().expect("Clean");
+        assert!(metadata(path.join("Payloads")).unwrap().len() < 1024);
+
+        let db = DB::new(path).expect("Make db");
+        let stored = db.read("Session 2").unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].payload, kept.payload);
+    }
+
+    #[test]
+    fn test_content_hash_is_stable() {
+        // Persisted on disk, so must never change
+        assert_eq!(DB::content_hash(&[1, 2, 3, 4, 5]), 0xc03d_5a42_c067_4f02);
+    }
+
+    #[test]
+    fn test_hash_collision() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let mut db = DB::new(path).expect("Make db");
+        db.hasher = |_| 42;
+        let mut faker = Faker::new();
+
+        let first = faker.make_fake_publish(vec![1, 2, 3]);
+        let second = faker.make_fake_publish(vec![4, 5, 6]);
+        db.append("Session 1", first.clone()).expect("Publish 1");
+        db.append("Session 1", second.clone()).expect("Publish 2");
+        db.append("Session 2", first.clone()).expect("Publish 3");
+        db.append("Session 2", second.clone()).expect("Publish 4");
+
+        let session_1 = db.read("Session 1").unwrap();
+        let session_2 = db.read("Session 2").unwrap();
+        assert_eq!(session_1[0].payload, first.payload);
+        assert_eq!(session_1[1].payload, second.payload);
+
+        // Colliding payloads are still each shared between sessions
+        assert_eq!(db.loaded_payloads.lock().unwrap().len(), 2);
+        assert!(Arc::ptr_eq(
+            &session_1[0].payload.bytes,
+            &session_2[0].payload.bytes
+        ));
+        assert!(Arc::ptr_eq(
+            &session_1[1].payload.bytes,
+            &session_2[1].payload.bytes
+        ));
+    }
+
+    #[test]
+    fn test_hash_collision_after_clean() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let mut db = DB::new(path).expect("Make db");
+        db.hasher = |_| 42;
+        let mut faker = Faker::new();
+
+        let first = faker.make_fake_publish(vec![1, 2, 3]);
+        let second = faker.make_fake_publish(vec![4, 5, 6]);
+        let third = faker.make_fake_publish(vec![7, 8, 9]);
+        db.append("Session 1", first.clone()).expect("Publish 1");
+        db.append("Session 1", second.clone()).expect("Publish 2");
+        db.ack("Session 1", first.packet_id).expect("Ack");
+        db.clean().expect("Clean");
+
+        // The freed slot is not reused while a later slot is still live
+        db.append("Session 1", third.clone()).expect("Publish 3");
+
+        let mut db = DB::new(path).expect("Make db");
+        db.hasher = |_| 42;
+        let stored = db.read("Session 1").unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].payload, second.payload);
+        assert_eq!(stored[1].payload, third.payload);
+    }
+
+    #[test]
+    fn test_hash_collision_slot_reused() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let mut db = DB::new(path).expect("Make db");
+        db.hasher = |_| 42;
+        let mut faker = Faker::new();
+
+        let first = faker.make_fake_publish(vec![1, 2, 3]);
+        let second = faker.make_fake_publish(vec![4, 5, 6]);
+        db.append("Session 1", first.clone()).expect("Publish 1");
+        // Still holding the payload keeps it in the cache
+        let held = db.read("Session 1").unwrap();
+        db.ack("Session 1", first.packet_id).expect("Ack");
+        db.clean().expect("Clean");
+
+        // The bucket is empty, so the new payload gets the dropped slot
+        db.append("Session 2", second.clone()).expect("Publish 2");
+        let stored = db.read("Session 2").unwrap();
+        assert_eq!(stored[0].payload, second.payload);
+        assert_eq!(held[0].payload, first.payload);
+    }
+
+    #[test]
+    fn test_session_store() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = <DB as SessionStore>::open(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        db.append("Session 1", faker.make_fake_publish(vec![1, 2, 3]))
+            .expect("Publish 1");
+        db.append("Session 1", faker.make_fake_publish(vec![4, 5, 6]))
+            .expect("Publish 2");
+        db.append("Session 2", faker.make_fake_publish(vec![7, 8, 9]))
+            .expect("Publish 3");
+
+        let stored = SessionStore::read(&db, "Session 1").unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].payload.bytes, Arc::new(vec![1, 2, 3]));
+        assert_eq!(stored[1].payload.bytes, Arc::new(vec![4, 5, 6]));
+
+        let mut sessions = db.list_sessions().unwrap();
+        sessions.sort();
+        assert_eq!(sessions, vec!["Session 1", "Session 2"]);
+
+        SessionStore::ack(&db, "Session 2", stored[0].packet_id).expect("Ack");
+        assert_eq!(SessionStore::read(&db, "Session 2").unwrap().len(), 1);
+
+        db.remove_session("Session 1").expect("Remove session");
+        assert_eq!(db.list_sessions().unwrap(), vec!["Session 2"]);
+        assert_eq!(SessionStore::read(&db, "Session 1").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_writers_and_clean() {
+        let dir = tempdir().unwrap();
+        let db = Arc::new(DB::new(dir.path()).expect("Make db"));
+
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    let mut faker = Faker::new();
+                    let name = format!("Session {}", i);
+                    for j in 0..20 {
+                        let publish = faker.make_fake_publish(vec![i, j]);
+                        let packet_id = publish.packet_id;
+                        db.append(&name, publish).expect("Publish");
+                        if j % 2 == 0 {
+                            db.ack(&name, packet_id).expect("Ack");
+                        }
+                    }
+                })
+            })
+            .collect();
+        let cleaner = {
+            let db = db.clone();
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    SessionStore::clean(&*db).expect("Clean");
+                }
+            })
+        };
+        for handle in writers {
+            handle.join().unwrap();
+        }
+        cleaner.join().unwrap();
+
+        SessionStore::clean(&*db).expect("Clean");
+        for i in 0..4 {
+            let stored = db.read(&format!("Session {}", i)).unwrap();
+            assert_eq!(stored.len(), 10);
+            assert!(stored.iter().all(|p| p.payload.bytes[0] == i));
+        }
+    }
+
+    #[test]
+    fn test_compaction_with_new_sessions() {
+        let dir = tempdir().unwrap();
+        let db = Arc::new(DB::new(dir.path()).expect("Make db"));
+
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    let mut faker = Faker::new();
+                    // Every publish goes to a session that doesn't exist yet
+                    for j in 0..20 {
+                        let name = format!("Session {} {}", i, j);
+                        db.append(&name, faker.make_fake_publish(vec![i, j]))
+                            .expect("Publish");
+                    }
+                })
+            })
+            .collect();
+        let compactor = {
+            let db = db.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    db.compact_payloads().expect("Compact");
+                }
+            })
+        };
+        for handle in writers {
+            handle.join().unwrap();
+        }
+        compactor.join().unwrap();
+
+        db.compact_payloads().expect("Compact");
+        for i in 0..4 {
+            for j in 0..20 {
+                let stored = db.read(&format!("Session {} {}", i, j)).unwrap();
+                assert_eq!(stored.len(), 1);
+                assert_eq!(*stored[0].payload.bytes, vec![i, j]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_session_locks_forgotten() {
+        let dir = tempdir().unwrap();
+        let db = DB::new(dir.path()).expect("Make db");
+        let mut faker = Faker::new();
+
+        for i in 0..10 {
+            db.read(&format!("Unknown {}", i)).unwrap();
+        }
+        assert!(lock(&db.logs).is_empty());
+
+        db.append("Session 1", faker.make_fake_publish(vec![1]))
+            .unwrap();
+        assert_eq!(lock(&db.logs).len(), 1);
+        SessionStore::remove_session(&db, "Session 1").unwrap();
+        assert!(lock(&db.logs).is_empty());
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let dir = tempdir().unwrap();
+        let db = DB::new(dir.path()).expect("Make db");
+        let imported_dir = tempdir().unwrap();
+        let imported = DB::new(imported_dir.path()).expect("Make db");
+        testing::check_archive_round_trip(&db, &imported);
+    }
+
+    #[test]
+    fn test_golden_old_versions_migrate() {
+        for version in ["v0", "v1", "v2", "v3"] {
+            let dir = tempdir().unwrap();
+            write_tree(
+                dir.path(),
+                &read_tree(&golden_dir(env!("CARGO_MANIFEST_DIR"), version)),
+            );
+
+            let db = DB::new(dir.path()).expect("Make db");
+            assert_golden_contents(&db);
+            assert_eq!(
+                read_tree(dir.path()),
+                read_tree(&golden_dir(env!("CARGO_MANIFEST_DIR"), "v4")),
+                "{}",
+                version
+            );
+        }
+    }
+
+    /// Set `UPDATE_GOLDEN=1` to rewrite `testdata/v4` after a deliberate
+    /// format change, along with a new version and migration.
+    #[test]
+    fn test_golden_v4() {
+        let dir = tempdir().unwrap();
+        golden_fill(&DB::new(dir.path()).expect("Make db"));
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            let _ = remove_dir_all(golden_dir(env!("CARGO_MANIFEST_DIR"), "v4"));
+            write_tree(
+                &golden_dir(env!("CARGO_MANIFEST_DIR"), "v4"),
+                &read_tree(dir.path()),
+            );
+        }
+        assert_eq!(
+            read_tree(dir.path()),
+            read_tree(&golden_dir(env!("CARGO_MANIFEST_DIR"), "v4"))
+        );
+
+        let dir = tempdir().unwrap();
+        write_tree(
+            dir.path(),
+            &read_tree(&golden_dir(env!("CARGO_MANIFEST_DIR"), "v4")),
+        );
+        assert_golden_contents(&DB::new(dir.path()).expect("Make db"));
     }
 
     struct Faker {
         packet_id: u16,
+        payload_id: u64,
     }
 
     impl Faker {
         fn new() -> Self {
-            Faker { packet_id: 100 }
+            Faker {
+                packet_id: 100,
+                payload_id: 1000,
+            }
         }
 
         fn make_fake_publish(&mut self, payload: Vec<u8>) -> Publish {
             self.packet_id += 2;
+            self.payload_id += 1;
 
             Publish {
                 packet_id: self.packet_id,
-                payload: Arc::new(payload),
+                payload: Payload {
+                    id: self.payload_id,
+                    bytes: Arc::new(payload),
+                },
                 retain: true,
                 topic_name: "fake".to_owned(),
             }
@@ -0,0 +1,1305 @@
+//! Keeps a whole store in one preallocated file of fixed-size pages, avoiding
+//! the file system metadata updates of the directory based layouts.
+//!
+//! Pages 0 and 1 are superblock slots, written alternately, each pointing at
+//! a catalog of where every session's record pages and every payload are.
+//! Pages are never overwritten while the committed catalog uses them: a change
+//! writes new pages and a new catalog, then the other superblock slot, so a
+//! crash at any point leaves the last committed state readable. Pages no
+//! catalog uses make up the free list, which is rebuilt when the file is opened.
+
+use serde_derive::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, create_dir_all, File, OpenOptions};
+use std::io::{self, prelude::*, SeekFrom};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+#[cfg(feature = "tokio")]
+pub use session_store::AsyncStore;
+pub use session_store::{
+    CorruptionPolicy, Durability, Inspect, Payload, PayloadInfo, Publish, SessionStore,
+    StoreConfig, StoreError,
+};
+
+use session_store::format::{self, strip_header, ChecksumAlgorithm, Header, Migration};
+use session_store::{encode_session_id, lock, sync_dir, write_atomic};
+
+/// Format version of the store written by this build, the first.
+pub const FORMAT_VERSION: u16 = 0;
+
+/// Upgrades from each older format version, see `format::migrate`.
+const MIGRATIONS: &[Migration] = &[];
+
+/// Size of every page of the store file.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Pages a new store file is preallocated with, which `clean` never shrinks
+/// it below.
+const INITIAL_PAGES: u64 = 256;
+
+/// The file grows by as many pages as it already has, up to this many at once.
+const MAX_GROWTH_PAGES: u64 = 16384;
+
+/// Pages 0 and 1, the superblock slots.
+const SUPERBLOCK_PAGES: u64 = 2;
+
+/// Length prefix and checksum stored around the data of an extent.
+const EXTENT_OVERHEAD: u64 = 8 + 4;
+
+/// A run of consecutive pages.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct Extent {
+    start: u64,
+    pages: u64,
+}
+
+impl Extent {
+    fn offset(&self) -> u64 {
+        self.start * PAGE_SIZE
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.pages
+    }
+}
+
+/// Pages taken up by an extent holding `len` bytes of data.
+fn pages_for(len: u64) -> u64 {
+    (len + EXTENT_OVERHEAD).div_ceil(PAGE_SIZE)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct DiskPublish {
+    packet_id: u16,
+    retain: bool,
+    topic_name: String,
+    payload_id: u64,
+}
+
+/// A payload's pages, and how many queued publishes reference it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct StoredPayload {
+    extent: Extent,
+    refcount: u64,
+}
+
+/// Contents of a superblock slot. The slot with the highest generation that
+/// passes its checksum is the committed state.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct Superblock {
+    generation: u64,
+    page_count: u64,
+    catalog: Option<Extent>,
+}
+
+/// Where everything in the file is, rewritten by every commit. Sessions list
+/// their record pages oldest first.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+struct Catalog {
+    sessions: BTreeMap<String, Vec<Extent>>,
+    payloads: BTreeMap<u64, StoredPayload>,
+}
+
+/// A page of a session's records, or a longer extent holding a single record
+/// too big for one page.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct RecordPage {
+    extent: Extent,
+    records: Vec<DiskPublish>,
+    /// Why the page couldn't be read, in which case `records` is empty.
+    corrupt: Option<String>,
+}
+
+/// The store file and the committed catalog, loaded into memory along with
+/// every session's records.
+struct PageFile {
+    path: PathBuf,
+    file: File,
+    durability: Durability,
+    generation: u64,
+    page_count: u64,
+    catalog: Option<Extent>,
+    sessions: BTreeMap<String, Vec<RecordPage>>,
+    payloads: BTreeMap<u64, StoredPayload>,
+    /// Free runs of pages by their first page, never adjacent to each other.
+    free: BTreeMap<u64, u64>,
+    /// Pages the committed catalog still uses, free once the next commit is.
+    released: Vec<Extent>,
+    /// Whether anything changed since the last commit.
+    dirty: bool,
+    /// Set once a change fails part way, after which memory and the file no
+    /// longer agree and every call fails until the store is reopened.
+    failed: Option<(io::ErrorKind, String)>,
+}
+
+impl PageFile {
+    /// Opens the file at `path`, creating and preallocating it if needed.
+    fn open(path: &Path, durability: Durability) -> Result<Self, StoreError> {
+        let created = !path.exists();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut page_file = PageFile {
+            path: path.to_owned(),
+            file,
+            durability,
+            generation: 0,
+            page_count: INITIAL_PAGES,
+            catalog: None,
+            sessions: BTreeMap::new(),
+            payloads: BTreeMap::new(),
+            free: BTreeMap::new(),
+            released: Vec::new(),
+            dirty: true,
+            failed: None,
+        };
+
+        match page_file.read_superblock()? {
+            Some(superblock) => page_file.load(superblock)?,
+            // New, or torn before its first superblock was written
+            None => {
+                page_file.file.set_len(INITIAL_PAGES * PAGE_SIZE)?;
+                page_file.free_now(Extent {
+                    start: SUPERBLOCK_PAGES,
+                    pages: INITIAL_PAGES - SUPERBLOCK_PAGES,
+                });
+                page_file.commit()?;
+            }
+        }
+        if created {
+            if let Some(parent) = path.parent() {
+                sync_dir(parent, durability)?;
+            }
+        }
+
+        Ok(page_file)
+    }
+
+    /// Reads both superblock slots, returning the newest valid one, or `None`
+    /// if neither was ever written.
+    fn read_superblock(&mut self) -> Result<Option<Superblock>, StoreError> {
+        let len = self.file.metadata()?.len();
+        if len < SUPERBLOCK_PAGES * PAGE_SIZE {
+            return Ok(None);
+        }
+
+        let mut newest: Option<Superblock> = None;
+        let mut error = None;
+        for slot in 0..SUPERBLOCK_PAGES {
+            let mut page = vec![0; PAGE_SIZE as usize];
+            self.file.seek(SeekFrom::Start(slot * PAGE_SIZE))?;
+            self.file.read_exact(&mut page)?;
+            if page.iter().all(|b| *b == 0) {
+                continue;
+            }
+
+            match decode_superblock(&self.path, slot * PAGE_SIZE, &page) {
+                Ok(superblock) => {
+                    if newest.is_none_or(|newest| superblock.generation > newest.generation) {
+                        newest = Some(superblock);
+                    }
+                }
+                Err(e @ StoreError::UnsupportedVersion { .. }) => return Err(e),
+                Err(e) => error = Some(e),
+            }
+        }
+
+        match (newest, error) {
+            (None, Some(error)) => Err(error),
+            (newest, _) => Ok(newest),
+        }
+    }
+
+    /// Loads the catalog `superblock` points at, and every session's records.
+    fn load(&mut self, superblock: Superblock) -> Result<(), StoreError> {
+        self.generation = superblock.generation;
+        self.page_count = superblock.page_count;
+        self.catalog = superblock.catalog;
+        self.dirty = false;
+
+        let catalog: Catalog = match superblock.catalog {
+            Some(extent) => bincode::deserialize(&self.read_extent(extent)?)?,
+            None => Catalog::default(),
+        };
+
+        let mut used: Vec<Extent> = vec![Extent {
+            start: 0,
+            pages: SUPERBLOCK_PAGES,
+        }];
+        used.extend(superblock.catalog);
+        used.extend(catalog.sessions.values().flatten());
+        used.extend(catalog.payloads.values().map(|stored| stored.extent));
+        used.sort_unstable_by_key(|extent| extent.start);
+
+        let mut next = 0;
+        for extent in used {
+            if extent.start < next || extent.end() > self.page_count {
+                return Err(StoreError::corrupt(
+                    &self.path,
+                    extent.offset(),
+                    format!("catalog uses pages {:?} twice or past the end", extent),
+                ));
+            }
+            if extent.start > next {
+                self.free_now(Extent {
+                    start: next,
+                    pages: extent.start - next,
+                });
+            }
+            next = extent.end();
+        }
+        if next < self.page_count {
+            self.free_now(Extent {
+                start: next,
+                pages: self.page_count - next,
+            });
+        }
+
+        for (session_id, extents) in catalog.sessions {
+            let mut pages = Vec::with_capacity(extents.len());
+            for extent in extents {
+                let page = match self.read_extent(extent).and_then(|data| {
+                    bincode::deserialize(&data).map_err(|e| {
+                        StoreError::corrupt(
+                            &self.path,
+                            extent.offset(),
+                            format!("bad records: {}", e),
+                        )
+                    })
+                }) {
+                    Ok(records) => RecordPage {
+                        extent,
+                        records,
+                        corrupt: None,
+                    },
+                    Err(StoreError::Corrupt { reason, .. }) => RecordPage {
+                        extent,
+                        records: Vec::new(),
+                        corrupt: Some(reason),
+                    },
+                    Err(e) => return Err(e),
+                };
+                pages.push(page);
+            }
+            self.sessions.insert(session_id, pages);
+        }
+        self.payloads = catalog.payloads;
+
+        Ok(())
+    }
+
+    /// Fails if an earlier change failed part way.
+    fn check(&self) -> Result<(), StoreError> {
+        match &self.failed {
+            Some((kind, message)) => Err(io::Error::new(*kind, message.clone()).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the catalog and then a superblock pointing at it, after which
+    /// the pages released since the last commit can be reused.
+    fn commit(&mut self) -> Result<(), StoreError> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let catalog = Catalog {
+            sessions: self
+                .sessions
+                .iter()
+                .map(|(session_id, pages)| {
+                    (
+                        session_id.clone(),
+                        pages.iter().map(|page| page.extent).collect(),
+                    )
+                })
+                .collect(),
+            payloads: self.payloads.clone(),
+        };
+        let data = bincode::serialize(&catalog)?;
+        let extent = self.allocate(pages_for(data.len() as u64))?;
+        self.write_extent(extent, &data)?;
+        if let Some(old) = self.catalog.replace(extent) {
+            self.release(old);
+        }
+
+        // Everything the superblock points at has to be on disk before it is
+        self.sync()?;
+        let superblock = Superblock {
+            generation: self.generation + 1,
+            page_count: self.page_count,
+            catalog: self.catalog,
+        };
+        let data = bincode::serialize(&superblock)?;
+        let header = Header::current();
+        let mut sealed = Vec::new();
+        header.checksum.seal(&data, &mut sealed);
+        let mut bytes = header.encode().to_vec();
+        bytes.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&sealed);
+        self.file.seek(SeekFrom::Start(
+            superblock.generation % SUPERBLOCK_PAGES * PAGE_SIZE,
+        ))?;
+        self.file.write_all(&bytes)?;
+        self.sync()?;
+
+        self.generation = superblock.generation;
+        for extent in mem::take(&mut self.released) {
+            self.free_now(extent);
+        }
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        match self.durability {
+            Durability::None => Ok(()),
+            Durability::Flush => self.file.sync_data(),
+            Durability::Fsync => self.file.sync_all(),
+        }
+    }
+
+    /// Takes the first free run long enough, growing the file if there is none.
+    fn allocate(&mut self, pages: u64) -> Result<Extent, StoreError> {
+        let found = self
+            .free
+            .iter()
+            .find(|(_, len)| **len >= pages)
+            .map(|(start, len)| (*start, *len));
+        let (start, len) = match found {
+            Some(run) => run,
+            None => {
+                self.grow(pages)?;
+                return self.allocate(pages);
+            }
+        };
+
+        self.free.remove(&start);
+        if len > pages {
+            self.free.insert(start + pages, len - pages);
+        }
+        self.dirty = true;
+
+        Ok(Extent { start, pages })
+    }
+
+    /// Adds at least `pages` free pages to the end of the file.
+    fn grow(&mut self, pages: u64) -> Result<(), StoreError> {
+        let growth = cmp::max(pages, cmp::min(self.page_count, MAX_GROWTH_PAGES));
+        let start = self.page_count;
+        self.page_count += growth;
+        self.file.set_len(self.page_count * PAGE_SIZE)?;
+        self.free_now(Extent {
+            start,
+            pages: growth,
+        });
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    /// Frees pages the committed catalog may still use, once it no longer does.
+    fn release(&mut self, extent: Extent) {
+        self.released.push(extent);
+        self.dirty = true;
+    }
+
+    /// Adds pages to the free list, merging them with neighbouring runs.
+    fn free_now(&mut self, extent: Extent) {
+        let mut start = extent.start;
+        let mut pages = extent.pages;
+
+        let before = self.free.range(..start).next_back().map(|(s, l)| (*s, *l));
+        if let Some((before_start, before_pages)) = before {
+            if before_start + before_pages == start {
+                self.free.remove(&before_start);
+                start = before_start;
+                pages += before_pages;
+            }
+        }
+        if let Some(after_pages) = self.free.remove(&(start + pages)) {
+            pages += after_pages;
+        }
+
+        self.free.insert(start, pages);
+    }
+
+    /// Writes `data`, its length and checksum to the start of `extent`.
+    fn write_extent(&mut self, extent: Extent, data: &[u8]) -> Result<(), StoreError> {
+        let mut sealed = Vec::with_capacity(data.len() + EXTENT_OVERHEAD as usize);
+        sealed.extend_from_slice(&(data.len() as u64 + 4).to_le_bytes());
+        ChecksumAlgorithm::default().seal(data, &mut sealed);
+        debug_assert!(sealed.len() as u64 <= extent.pages * PAGE_SIZE);
+
+        self.file.seek(SeekFrom::Start(extent.offset()))?;
+        self.file.write_all(&sealed)?;
+
+        Ok(())
+    }
+
+    /// Reads back what `write_extent` wrote, checking its checksum.
+    fn read_extent(&mut self, extent: Extent) -> Result<Vec<u8>, StoreError> {
+        let mut bytes = self.read_raw(extent)?;
+
+        let mut len = [0; 8];
+        len.copy_from_slice(&bytes[..8]);
+        let len = u64::from_le_bytes(len);
+        if len > extent.pages * PAGE_SIZE - 8 {
+            return Err(StoreError::corrupt(
+                &self.path,
+                extent.offset(),
+                format!("extent of {} pages can't hold {} bytes", extent.pages, len),
+            ));
+        }
+
+        bytes.truncate(8 + len as usize);
+        let data = ChecksumAlgorithm::default()
+            .open(&bytes[8..])
+            .map_err(|reason| StoreError::corrupt(&self.path, extent.offset() + 8, reason))?
+            .to_vec();
+
+        Ok(data)
+    }
+
+    fn read_raw(&mut self, extent: Extent) -> Result<Vec<u8>, StoreError> {
+        let mut bytes = vec![0; (extent.pages * PAGE_SIZE) as usize];
+        self.file.seek(SeekFrom::Start(extent.offset()))?;
+        self.file.read_exact(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    /// Adds a reference to a payload, storing it if it isn't already.
+    fn store_payload(&mut self, payload: &Payload) -> Result<(), StoreError> {
+        if let Some(stored) = self.payloads.get_mut(&payload.id) {
+            stored.refcount += 1;
+            self.dirty = true;
+            return Ok(());
+        }
+
+        let extent = self.allocate(pages_for(payload.bytes.len() as u64))?;
+        self.write_extent(extent, &payload.bytes)?;
+        self.payloads.insert(
+            payload.id,
+            StoredPayload {
+                extent,
+                refcount: 1,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Drops a reference to a payload, releasing it once there are none left.
+    fn drop_ref(&mut self, payload_id: u64) {
+        let released = match self.payloads.get_mut(&payload_id) {
+            Some(stored) => {
+                stored.refcount = stored.refcount.saturating_sub(1);
+                stored.refcount == 0
+            }
+            None => false,
+        };
+        if released {
+            let stored = self.payloads.remove(&payload_id).expect("payload stored");
+            self.release(stored.extent);
+        }
+        self.dirty = true;
+    }
+
+    /// Appends records whose payloads are already referenced to the end of a
+    /// session, filling its last page before starting new ones.
+    fn append_records(
+        &mut self,
+        session_id: &str,
+        records: Vec<DiskPublish>,
+    ) -> Result<(), StoreError> {
+        let mut pages = self.sessions.remove(session_id).unwrap_or_default();
+        self.dirty = true;
+
+        let mut chunk = Vec::new();
+        if !records.is_empty() {
+            if let Some(tail) = pages.last() {
+                if tail.corrupt.is_none() && tail.extent.pages == 1 {
+                    let tail = pages.pop().expect("tail page");
+                    self.release(tail.extent);
+                    chunk = tail.records;
+                }
+            }
+        }
+
+        let mut len = bincode::serialized_size(&chunk)?;
+        for record in records {
+            let record_len = bincode::serialized_size(&record)?;
+            if !chunk.is_empty() && pages_for(len + record_len) > 1 {
+                pages.push(self.write_records(mem::take(&mut chunk))?);
+                len = bincode::serialized_size(&chunk)?;
+            }
+            chunk.push(record);
+            len += record_len;
+        }
+        if !chunk.is_empty() {
+            pages.push(self.write_records(chunk)?);
+        }
+
+        self.sessions.insert(session_id.to_owned(), pages);
+
+        Ok(())
+    }
+
+    fn write_records(&mut self, records: Vec<DiskPublish>) -> Result<RecordPage, StoreError> {
+        let data = bincode::serialize(&records)?;
+        let extent = self.allocate(pages_for(data.len() as u64))?;
+        self.write_extent(extent, &data)?;
+
+        Ok(RecordPage {
+            extent,
+            records,
+            corrupt: None,
+        })
+    }
+
+    /// Removes a session's pages, dropping the references its records hold.
+    fn remove_pages(&mut self, pages: Vec<RecordPage>) {
+        for page in pages {
+            self.release(page.extent);
+            for record in page.records {
+                self.drop_ref(record.payload_id);
+            }
+        }
+    }
+}
+
+fn decode_superblock(path: &Path, offset: u64, page: &[u8]) -> Result<Superblock, StoreError> {
+    let (header, rest) = strip_header(path, page)?;
+
+    let mut len = [0; 4];
+    len.copy_from_slice(&rest[..4]);
+    let len = u32::from_le_bytes(len) as usize;
+    let sealed = rest
+        .get(4..4 + len)
+        .ok_or_else(|| StoreError::corrupt(path, offset, "superblock too long"))?;
+    let data = header
+        .checksum
+        .open(sealed)
+        .map_err(|reason| StoreError::corrupt(path, offset, reason))?;
+
+    Ok(bincode::deserialize(data)?)
+}
+
+/// Safe to share between threads, though every call takes the same lock, as
+/// all changes are committed through the one pair of superblock slots.
+pub struct DB {
+    file: Mutex<PageFile>,
+    loaded_payloads: Mutex<HashMap<u64, Weak<Vec<u8>>>>,
+    /// Copies of session pages with unreadable data, see `CorruptionPolicy`.
+    corrupt: PathBuf,
+    durability: Durability,
+    on_corrupt: CorruptionPolicy,
+}
+
+impl DB {
+    pub fn new(location: &Path) -> Result<Self, StoreError> {
+        Self::with_config(location, StoreConfig::default())
+    }
+
+    pub fn with_durability(location: &Path, durability: Durability) -> Result<Self, StoreError> {
+        let config = StoreConfig {
+            durability,
+            ..StoreConfig::default()
+        };
+        Self::with_config(location, config)
+    }
+
+    /// Creates `location` if needed. The store is the `Pages` file in it.
+    pub fn with_config(location: &Path, config: StoreConfig) -> Result<Self, StoreError> {
+        if !location.exists() {
+            create_dir_all(location)?;
+        }
+        format::migrate(location, config.durability, FORMAT_VERSION, MIGRATIONS)?;
+        let file = PageFile::open(&location.join("Pages"), config.durability)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+            loaded_payloads: Mutex::new(HashMap::new()),
+            corrupt: location.join("Corrupt"),
+            durability: config.durability,
+            on_corrupt: config.on_corrupt,
+        })
+    }
+
+    pub fn append(&self, session_id: &str, publish: Publish) -> Result<(), StoreError> {
+        self.append_batch(&[(session_id, publish)])
+    }
+
+    /// Appends `publish` to each of `session_ids`, storing its payload once.
+    pub fn append_fanout(&self, publish: Publish, session_ids: &[&str]) -> Result<(), StoreError> {
+        let writes: Vec<(&str, Publish)> = session_ids
+            .iter()
+            .map(|session_id| (*session_id, publish.clone()))
+            .collect();
+        self.append_batch(&writes)
+    }
+
+    /// Appends several publishes, in order, possibly to different sessions,
+    /// as a single commit: after a crash either all of them are there or none.
+    pub fn append_batch(&self, writes: &[(&str, Publish)]) -> Result<(), StoreError> {
+        for (session_id, _) in writes {
+            encode_session_id(session_id)?;
+        }
+
+        self.update(|file| {
+            let mut records: BTreeMap<&str, Vec<DiskPublish>> = BTreeMap::new();
+            for (session_id, publish) in writes {
+                file.store_payload(&publish.payload)?;
+                records.entry(session_id).or_default().push(DiskPublish {
+                    packet_id: publish.packet_id,
+                    retain: publish.retain,
+                    topic_name: publish.topic_name.clone(),
+                    payload_id: publish.payload.id,
+                });
+            }
+            for (session_id, records) in records {
+                file.append_records(session_id, records)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Removes the oldest publish with the given packet id, rewriting the
+    /// page it was on.
+    pub fn ack(&self, session_id: &str, packet_id: u16) -> Result<(), StoreError> {
+        encode_session_id(session_id)?;
+
+        self.update(|file| {
+            let found = file.sessions.get(session_id).and_then(|pages| {
+                pages.iter().enumerate().find_map(|(i, page)| {
+                    let j = page.records.iter().position(|r| r.packet_id == packet_id)?;
+                    Some((i, j))
+                })
+            });
+            let (i, j) = match found {
+                Some(found) => found,
+                None => return Ok(()),
+            };
+
+            let mut pages = file.sessions.remove(session_id).expect("session loaded");
+            let mut page = pages.remove(i);
+            let record = page.records.remove(j);
+            file.release(page.extent);
+            if !page.records.is_empty() {
+                pages.insert(i, file.write_records(page.records)?);
+            }
+            file.sessions.insert(session_id.to_owned(), pages);
+            file.drop_ref(record.payload_id);
+
+            Ok(())
+        })
+    }
+
+    /// Unreadable pages and payloads are handled as the store's
+    /// `CorruptionPolicy` says.
+    pub fn read(&self, session_id: &str) -> Result<Vec<Publish>, StoreError> {
+        encode_session_id(session_id)?;
+
+        let mut file = lock(&self.file);
+        file.check()?;
+        let pages = match file.sessions.get(session_id) {
+            Some(pages) => pages.clone(),
+            None => return Ok(Vec::new()),
+        };
+
+        let mut result = Vec::new();
+        let mut kept = Vec::new();
+        let mut dropped = false;
+        for page in pages {
+            if let Some(reason) = page.corrupt {
+                if self.on_corrupt == CorruptionPolicy::Error {
+                    return Err(StoreError::corrupt(
+                        &file.path,
+                        page.extent.offset(),
+                        reason,
+                    ));
+                }
+                dropped = true;
+                continue;
+            }
+
+            for body in page.records {
+                let error = match self.get_payload(&mut file, body.payload_id) {
+                    Ok(Some(bytes)) => {
+                        result.push(Publish {
+                            packet_id: body.packet_id,
+                            retain: body.retain,
+                            topic_name: body.topic_name.clone(),
+                            payload: Payload {
+                                id: body.payload_id,
+                                bytes,
+                            },
+                        });
+                        kept.push(body);
+                        continue;
+                    }
+                    Ok(None) => StoreError::MissingPayload {
+                        payload_id: body.payload_id,
+                    },
+                    Err(e @ StoreError::Corrupt { .. }) => e,
+                    Err(e) => return Err(e),
+                };
+
+                if self.on_corrupt == CorruptionPolicy::Error {
+                    return Err(error);
+                }
+                dropped = true;
+            }
+        }
+
+        // `kept` came from the pages as they are now, so keep holding the
+        // lock until they're replaced
+        if dropped && self.on_corrupt == CorruptionPolicy::Quarantine {
+            self.quarantine(&mut file, session_id, kept)?;
+        }
+
+        Ok(result)
+    }
+
+    /// Copies a session's pages into `Corrupt/`, then replaces them with just
+    /// the `kept` records, returning where the copy went. `kept` must have been
+    /// read from the session's pages under the same lock.
+    fn quarantine(
+        &self,
+        file: &mut PageFile,
+        session_id: &str,
+        kept: Vec<DiskPublish>,
+    ) -> Result<PathBuf, StoreError> {
+        if !self.corrupt.exists() {
+            create_dir_all(&self.corrupt)?;
+        }
+
+        // A session can be quarantined more than once, never overwrite
+        let name = encode_session_id(session_id)?;
+        let mut target = self.corrupt.join(&name);
+        let mut attempt = 0;
+        while target.exists() {
+            attempt += 1;
+            target = self.corrupt.join(format!("{}.{}", name, attempt));
+        }
+
+        self.update_locked(file, |file| {
+            let pages = file.sessions.remove(session_id).unwrap_or_default();
+            let mut bytes = Vec::new();
+            for page in pages.iter() {
+                bytes.extend_from_slice(&file.read_raw(page.extent)?);
+            }
+            write_atomic(&target, &bytes, self.durability)?;
+
+            // Kept records hold on to their payloads while the old pages go
+            for record in kept.iter() {
+                if let Some(stored) = file.payloads.get_mut(&record.payload_id) {
+                    stored.refcount += 1;
+                }
+            }
+            file.remove_pages(pages);
+            file.append_records(session_id, kept)
+        })?;
+
+        Ok(target)
+    }
+
+    /// Runs a change to the file and commits it. A failure part way poisons
+    /// the store, since the change can't be rolled back in memory.
+    fn update<F>(&self, change: F) -> Result<(), StoreError>
+    where
+        F: FnOnce(&mut PageFile) -> Result<(), StoreError>,
+    {
+        self.update_locked(&mut lock(&self.file), change)
+    }
+
+    /// `update` for a caller already holding the file lock.
+    fn update_locked<F>(&self, file: &mut PageFile, change: F) -> Result<(), StoreError>
+    where
+        F: FnOnce(&mut PageFile) -> Result<(), StoreError>,
+    {
+        file.check()?;
+
+        let result = change(file).and_then(|_| file.commit());
+        if let Err(e) = &result {
+            let kind = match e {
+                StoreError::Io(e) => e.kind(),
+                _ => io::ErrorKind::Other,
+            };
+            file.failed = Some((kind, e.to_string()));
+        }
+
+        result
+    }
+
+    fn get_payload(
+        &self,
+        file: &mut PageFile,
+        payload_id: u64,
+    ) -> Result<Option<Arc<Vec<u8>>>, StoreError> {
+        if let Some(payload) = lock(&self.loaded_payloads)
+            .get(&payload_id)
+            .and_then(|payload| payload.upgrade())
+        {
+            return Ok(Some(payload));
+        }
+
+        let extent = match file.payloads.get(&payload_id) {
+            Some(stored) => stored.extent,
+            None => return Ok(None),
+        };
+        let bytes = Arc::new(file.read_extent(extent)?);
+        lock(&self.loaded_payloads).insert(payload_id, Arc::downgrade(&bytes));
+
+        Ok(Some(bytes))
+    }
+
+    /// Length of the store file.
+    pub fn file_len(&self) -> Result<u64, StoreError> {
+        Ok(fs::metadata(&lock(&self.file).path)?.len())
+    }
+}
+
+impl SessionStore for DB {
+    fn open(location: &Path) -> Result<Self, StoreError> {
+        Self::new(location)
+    }
+
+    fn append(&self, session_id: &str, publish: Publish) -> Result<(), StoreError> {
+        self.append(session_id, publish)
+    }
+
+    fn append_batch(&self, writes: &[(&str, Publish)]) -> Result<(), StoreError> {
+        self.append_batch(writes)
+    }
+
+    fn read(&self, session_id: &str) -> Result<Vec<Publish>, StoreError> {
+        self.read(session_id)
+    }
+
+    fn ack(&self, session_id: &str, packet_id: u16) -> Result<(), StoreError> {
+        self.ack(session_id, packet_id)
+    }
+
+    fn remove_session(&self, session_id: &str) -> Result<(), StoreError> {
+        encode_session_id(session_id)?;
+
+        self.update(|file| {
+            if let Some(pages) = file.sessions.remove(session_id) {
+                file.remove_pages(pages);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn list_sessions(&self) -> Result<Vec<String>, StoreError> {
+        let file = lock(&self.file);
+        file.check()?;
+
+        Ok(file.sessions.keys().cloned().collect())
+    }
+
+    /// Payloads are released as soon as nothing references them, so this
+    /// recounts references, in case unreadable pages held some, and gives
+    /// free pages at the end of the file back to the file system.
+    fn clean(&self) -> Result<(), StoreError> {
+        self.update(|file| {
+            let mut counts: HashMap<u64, u64> = HashMap::new();
+            for page in file.sessions.values().flatten() {
+                for record in page.records.iter() {
+                    *counts.entry(record.payload_id).or_default() += 1;
+                }
+            }
+
+            let payloads = mem::take(&mut file.payloads);
+            for (payload_id, mut stored) in payloads {
+                let refcount = counts.get(&payload_id).copied().unwrap_or(0);
+                if refcount != stored.refcount {
+                    stored.refcount = refcount;
+                    file.dirty = true;
+                }
+                if refcount == 0 {
+                    file.release(stored.extent);
+                } else {
+                    file.payloads.insert(payload_id, stored);
+                }
+            }
+
+            // Only pages free in the committed state, released ones may still be in use
+            let last = file.free.iter().next_back().map(|(s, l)| (*s, *l));
+            if let Some((start, pages)) = last {
+                let shrunk = cmp::max(start, INITIAL_PAGES);
+                if start + pages == file.page_count && shrunk < file.page_count {
+                    file.free.remove(&start);
+                    if shrunk > start {
+                        file.free.insert(start, shrunk - start);
+                    }
+                    file.page_count = shrunk;
+                    file.dirty = true;
+                }
+            }
+
+            Ok(())
+        })?;
+
+        let file = lock(&self.file);
+        file.file.set_len(file.page_count * PAGE_SIZE)?;
+        drop(file);
+        lock(&self.loaded_payloads).retain(|_, val| val.strong_count() > 0);
+
+        Ok(())
+    }
+}
+
+impl Inspect for DB {
+    /// Payloads are keyed by id, and take up whole pages.
+    fn payloads(&self) -> Result<Vec<PayloadInfo>, StoreError> {
+        let file = lock(&self.file);
+        file.check()?;
+
+        Ok(file
+            .payloads
+            .iter()
+            .map(|(payload_id, stored)| PayloadInfo {
+                key: payload_id.to_string(),
+                stored_bytes: stored.extent.pages * PAGE_SIZE,
+                refcount: stored.refcount,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use session_store::testing;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_read_write() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
+        db.append("Session 1", publish.clone()).expect("Publish 1");
+
+        let stored = db.read("Session 1").unwrap();
+        assert_eq!(stored, vec![publish.clone()]);
+        assert!(db.read("Session 2").unwrap().is_empty());
+        assert_eq!(db.file_len().unwrap(), INITIAL_PAGES * PAGE_SIZE);
+
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![publish]);
+    }
+
+    #[test]
+    fn test_append_ack() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let first = faker.make_fake_publish(vec![1]);
+        let second = faker.make_fake_publish(vec![2]);
+        let mut again = faker.make_fake_publish(vec![3]);
+        again.packet_id = first.packet_id;
+        for publish in [&first, &second, &again].iter() {
+            db.append("Session 1", (*publish).clone()).expect("Publish");
+        }
+
+        db.ack("Session 1", first.packet_id).unwrap();
+        db.ack("Session 1", 9999).unwrap();
+        db.ack("Session 2", first.packet_id).unwrap();
+        assert_eq!(
+            db.read("Session 1").unwrap(),
+            vec![second.clone(), again.clone()]
+        );
+
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![second, again]);
+        assert_eq!(db.payloads().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_pages_reused() {
+        let dir = tempdir().unwrap();
+        let db = DB::with_durability(dir.path(), Durability::None).expect("Make db");
+        let mut faker = Faker::new();
+
+        for _ in 0..1000 {
+            let publish = faker.make_fake_publish(vec![7; 5000]);
+            db.append("Session 1", publish.clone()).expect("Publish");
+            db.ack("Session 1", publish.packet_id).expect("Ack");
+        }
+
+        assert!(db.read("Session 1").unwrap().is_empty());
+        assert!(db.payloads().unwrap().is_empty());
+        assert_eq!(db.file_len().unwrap(), INITIAL_PAGES * PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_grow_and_clean() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::with_durability(path, Durability::None).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publishes: Vec<Publish> = (0..200)
+            .map(|_| faker.make_fake_publish(vec![1; 2 * PAGE_SIZE as usize]))
+            .collect();
+        for publish in publishes.iter() {
+            db.append("Session 1", publish.clone()).expect("Publish");
+        }
+        db.append("Session 2", publishes[0].clone())
+            .expect("Publish");
+        assert!(db.file_len().unwrap() > INITIAL_PAGES * PAGE_SIZE);
+
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), publishes);
+
+        db.remove_session("Session 1").unwrap();
+        db.clean().unwrap();
+        assert_eq!(db.file_len().unwrap(), INITIAL_PAGES * PAGE_SIZE);
+        assert_eq!(db.list_sessions().unwrap(), vec!["Session 2"]);
+        assert_eq!(db.read("Session 2").unwrap(), vec![publishes[0].clone()]);
+        assert_eq!(db.payloads().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_large_record() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let small = faker.make_fake_publish(vec![1]);
+        let mut large = faker.make_fake_publish(vec![2]);
+        large.topic_name = "t".repeat(3 * PAGE_SIZE as usize);
+        let publishes = vec![small.clone(), large, small];
+        for publish in publishes.iter() {
+            db.append("Session 1", publish.clone()).expect("Publish");
+        }
+
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), publishes);
+    }
+
+    #[test]
+    fn test_append_fanout() {
+        let dir = tempdir().unwrap();
+        let db = DB::new(dir.path()).expect("Make db");
+        let mut faker = Faker::new();
+
+        let shared = faker.make_fake_publish(vec![1, 2, 3]);
+        db.append_fanout(shared.clone(), &["Session 1", "Session 2", "Session 3"])
+            .expect("Fanout");
+        let generation = lock(&db.file).generation;
+        let other = faker.make_fake_publish(vec![4, 5]);
+        db.append_batch(&[("Session 2", other.clone()), ("Session 1", shared.clone())])
+            .expect("Batch");
+        // one commit per batch
+        assert_eq!(lock(&db.file).generation, generation + 1);
+
+        assert_eq!(
+            db.read("Session 1").unwrap(),
+            vec![shared.clone(), shared.clone()]
+        );
+        assert_eq!(db.read("Session 2").unwrap(), vec![shared.clone(), other]);
+        let payloads = db.payloads().unwrap();
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[0].refcount, 4);
+
+        // an invalid session id fails the whole batch before anything is written
+        assert!(matches!(
+            db.append_fanout(shared, &["Session 4", ""]),
+            Err(StoreError::InvalidSessionId(_))
+        ));
+        assert_eq!(db.list_sessions().unwrap().len(), 3);
+
+        for session_id in db.list_sessions().unwrap() {
+            db.remove_session(&session_id).unwrap();
+        }
+        assert!(db.payloads().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_torn_superblock() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let first = faker.make_fake_publish(vec![1]);
+        db.append("Session 1", first.clone()).expect("Publish 1");
+        db.append("Session 1", faker.make_fake_publish(vec![2]))
+            .expect("Publish 2");
+        let generation = lock(&db.file).generation;
+        drop(db);
+
+        // crash while writing the last superblock
+        let mut bytes = fs::read(path.join("Pages")).unwrap();
+        let slot = (generation % SUPERBLOCK_PAGES * PAGE_SIZE) as usize;
+        bytes[slot + 20] ^= 1;
+        fs::write(path.join("Pages"), &bytes).unwrap();
+
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(lock(&db.file).generation, generation - 1);
+        assert_eq!(db.read("Session 1").unwrap(), vec![first.clone()]);
+
+        // and carries on from there
+        let third = faker.make_fake_publish(vec![3]);
+        db.append("Session 1", third.clone()).expect("Publish 3");
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![first, third]);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        drop(DB::new(path).expect("Make db"));
+
+        let mut bytes = fs::read(path.join("Pages")).unwrap();
+        bytes[PAGE_SIZE as usize + 4] = 9;
+        fs::write(path.join("Pages"), &bytes).unwrap();
+
+        assert!(matches!(
+            DB::new(path),
+            Err(StoreError::UnsupportedVersion { version: 9, .. })
+        ));
+
+        // A store newer than this build isn't touched
+        let newer = Header {
+            version: FORMAT_VERSION + 1,
+            ..Header::current()
+        };
+        fs::write(path.join("Format"), newer.encode()).unwrap();
+        assert!(matches!(
+            DB::new(path),
+            Err(StoreError::UnsupportedVersion { version, .. }) if version == FORMAT_VERSION + 1
+        ));
+    }
+
+    /// Fills two pages of "Session 1" and corrupts the first.
+    fn corrupt_page(path: &Path) -> (Vec<Publish>, Vec<Publish>) {
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publishes: Vec<Publish> = (0..4)
+            .map(|i| {
+                let mut publish = faker.make_fake_publish(vec![i]);
+                publish.topic_name = "t".repeat(1500);
+                publish
+            })
+            .collect();
+        for publish in publishes.iter() {
+            db.append("Session 1", publish.clone()).expect("Publish");
+        }
+        let extent = lock(&db.file).sessions["Session 1"][0].extent;
+        drop(db);
+
+        let mut bytes = fs::read(path.join("Pages")).unwrap();
+        bytes[extent.offset() as usize + 100] ^= 1;
+        fs::write(path.join("Pages"), &bytes).unwrap();
+
+        (publishes[..2].to_vec(), publishes[2..].to_vec())
+    }
+
+    #[test]
+    fn test_corrupt_page() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let (_, readable) = corrupt_page(path);
+
+        let db = DB::new(path).expect("Open db");
+        assert!(matches!(
+            db.read("Session 1"),
+            Err(StoreError::Corrupt { .. })
+        ));
+        drop(db);
+
+        let config = StoreConfig {
+            on_corrupt: CorruptionPolicy::Skip,
+            ..StoreConfig::default()
+        };
+        let db = DB::with_config(path, config).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), readable);
+        drop(db);
+
+        let config = StoreConfig {
+            on_corrupt: CorruptionPolicy::Quarantine,
+            ..StoreConfig::default()
+        };
+        let db = DB::with_config(path, config).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), readable);
+        assert!(path.join("Corrupt").join("%53ession%201").exists());
+        drop(db);
+
+        // the unreadable page is gone for good
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), readable);
+        db.clean().unwrap();
+        assert_eq!(db.payloads().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_session_id() {
+        let dir = tempdir().unwrap();
+        let db = DB::new(dir.path()).expect("Make db");
+        testing::check_invalid_session_id(&db, dir.path());
+    }
+
+    #[test]
+    fn test_concurrent_sessions() {
+        let dir = tempdir().unwrap();
+        let db = DB::with_durability(dir.path(), Durability::None).expect("Make db");
+        testing::check_concurrent_sessions(&db, 50);
+    }
+
+    struct Faker {
+        packet_id: u16,
+        payload_id: u64,
+    }
+
+    impl Faker {
+        fn new() -> Self {
+            Faker {
+                packet_id: 100,
+                payload_id: 1000,
+            }
+        }
+
+        fn make_fake_publish(&mut self, payload: Vec<u8>) -> Publish {
+            self.packet_id += 2;
+            self.payload_id += 1;
+
+            Publish {
+                packet_id: self.packet_id,
+                payload: Payload {
+                    id: self.payload_id,
+                    bytes: Arc::new(payload),
+                },
+                retain: true,
+                topic_name: "fake".to_owned(),
+            }
+        }
+    }
+}
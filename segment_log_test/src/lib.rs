@@ -0,0 +1,1380 @@
+//! Log-structured store: every session appends to the same segment files,
+//! with an index in memory of where each queued publish and each payload is.
+//!
+//! Each call appends one frame, a single log record holding every entry the
+//! call writes, so a crash keeps all of them or none. Acks and removed
+//! sessions are written as tombstones. Once a segment is full the next one is
+//! started, and when enough of the full segments is dead they are compacted:
+//! their live entries are copied into one new segment, named by the range of
+//! segments it replaces, and the old ones are deleted.
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, create_dir_all, read_dir, remove_file, File};
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+#[cfg(feature = "tokio")]
+pub use session_store::AsyncStore;
+pub use session_store::{
+    CorruptionPolicy, Durability, Inspect, Payload, PayloadInfo, Publish, SessionStore,
+    StoreConfig, StoreError,
+};
+
+use session_store::format::{self, Migration};
+use session_store::log::{append_log, decode_record, encode_record, new_log, scan_log};
+use session_store::{encode_session_id, is_temp_file, lock, sync_dir, write_atomic};
+
+/// Format version of the store written by this build, the first.
+pub const FORMAT_VERSION: u16 = 0;
+
+/// Upgrades from each older format version, see `format::migrate`.
+const MIGRATIONS: &[Migration] = &[];
+
+/// A segment is full once it is at least this long.
+const SEGMENT_LEN: u64 = 4 << 20;
+
+/// Full segments are compacted once they hold at least this many dead bytes,
+/// and no more live ones.
+const MIN_COMPACTION_BYTES: u64 = 1 << 20;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct DiskPublish {
+    packet_id: u16,
+    retain: bool,
+    topic_name: String,
+    payload_id: u64,
+}
+
+/// One entry of a frame.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+enum Entry {
+    /// Written along with the first publish referencing the payload.
+    Payload {
+        payload_id: u64,
+        bytes: Arc<Vec<u8>>,
+    },
+    Publish {
+        session_id: String,
+        sequence: u64,
+        body: DiskPublish,
+    },
+    /// Tombstone for an acked publish.
+    Ack { session_id: String, sequence: u64 },
+    /// Tombstone for every publish of a session written before it.
+    RemoveSession { session_id: String },
+}
+
+/// Where an entry is: the frame holding it, and its place in the frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Location {
+    /// First segment number of the segment file.
+    segment: u64,
+    offset: u64,
+    len: usize,
+    index: usize,
+    /// Encoded length of the entry, counted as live bytes of its segment.
+    size: u64,
+}
+
+impl Location {
+    fn is(&self, segment: u64, offset: u64, index: usize) -> bool {
+        self.segment == segment && self.offset == offset && self.index == index
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct QueuedPublish {
+    packet_id: u16,
+    payload_id: u64,
+    location: Location,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct StoredPayload {
+    location: Location,
+    refcount: u64,
+}
+
+/// A segment file, holding data from segments `first..=last`: just one
+/// until it is compacted together with others.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Segment {
+    last: u64,
+    len: u64,
+    live: u64,
+}
+
+/// Counters for comparing write amplification with other stores.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SegmentStats {
+    /// Segment files, including the one being appended to.
+    pub segments: usize,
+    /// Total length of the segment files.
+    pub file_bytes: u64,
+    /// Encoded length of the entries still needed.
+    pub live_bytes: u64,
+    /// Bytes appended by writes, acks and removals since the store was opened.
+    pub appended_bytes: u64,
+    /// Bytes written by compaction since the store was opened.
+    pub compacted_bytes: u64,
+    /// Compactions started by an append that failed, leaving the segments
+    /// they would have replaced in place.
+    pub compaction_errors: u64,
+    pub last_compaction_error: Option<String>,
+}
+
+/// The index, and the segments it points into.
+#[derive(Default)]
+struct State {
+    /// Segment files by first segment number.
+    segments: BTreeMap<u64, Segment>,
+    /// Number of the segment being appended to, which has no file until the
+    /// first frame is written to it.
+    active: u64,
+    /// Queued publishes of each session by sequence number. Sessions with
+    /// none are left out.
+    sessions: HashMap<String, BTreeMap<u64, QueuedPublish>>,
+    payloads: HashMap<u64, StoredPayload>,
+    appended_bytes: u64,
+    compacted_bytes: u64,
+    compaction_errors: u64,
+    last_compaction_error: Option<String>,
+    /// Set once a frame fails to be appended, after which the active
+    /// segment's tail is unknown and every call fails until the store is
+    /// reopened.
+    failed: Option<(io::ErrorKind, String)>,
+}
+
+impl State {
+    /// Updates the index for an entry found at `location`, when it is
+    /// appended or replayed.
+    fn apply(&mut self, entry: &Entry, location: Location) {
+        match entry {
+            Entry::Payload { payload_id, .. } => {
+                // Written again after its refcount dropped to zero
+                let refcount = match self.payloads.remove(payload_id) {
+                    Some(stored) => {
+                        self.kill(stored.location);
+                        stored.refcount
+                    }
+                    None => 0,
+                };
+                self.payloads
+                    .insert(*payload_id, StoredPayload { location, refcount });
+                self.revive(location);
+            }
+            Entry::Publish {
+                session_id,
+                sequence,
+                body,
+            } => {
+                let queued = QueuedPublish {
+                    packet_id: body.packet_id,
+                    payload_id: body.payload_id,
+                    location,
+                };
+                if let Some(stored) = self.payloads.get_mut(&body.payload_id) {
+                    stored.refcount += 1;
+                }
+                let replaced = self
+                    .sessions
+                    .entry(session_id.clone())
+                    .or_default()
+                    .insert(*sequence, queued);
+                if let Some(replaced) = replaced {
+                    self.unqueue(replaced);
+                }
+                self.revive(location);
+            }
+            Entry::Ack {
+                session_id,
+                sequence,
+            } => {
+                let removed = match self.sessions.get_mut(session_id) {
+                    Some(queue) => {
+                        let removed = queue.remove(sequence);
+                        if queue.is_empty() {
+                            self.sessions.remove(session_id);
+                        }
+                        removed
+                    }
+                    None => None,
+                };
+                if let Some(removed) = removed {
+                    self.unqueue(removed);
+                }
+            }
+            Entry::RemoveSession { session_id } => {
+                if let Some(queue) = self.sessions.remove(session_id) {
+                    for removed in queue.into_values() {
+                        self.unqueue(removed);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drops a publish's reference to its payload, which is dead once there
+    /// are none left.
+    fn unqueue(&mut self, removed: QueuedPublish) {
+        self.kill(removed.location);
+
+        let released = match self.payloads.get_mut(&removed.payload_id) {
+            Some(stored) => {
+                stored.refcount = stored.refcount.saturating_sub(1);
+                stored.refcount == 0
+            }
+            None => false,
+        };
+        if released {
+            let stored = self.payloads.remove(&removed.payload_id).expect("payload");
+            self.kill(stored.location);
+        }
+    }
+
+    fn revive(&mut self, location: Location) {
+        if let Some(segment) = self.segments.get_mut(&location.segment) {
+            segment.live += location.size;
+        }
+    }
+
+    fn kill(&mut self, location: Location) {
+        if let Some(segment) = self.segments.get_mut(&location.segment) {
+            segment.live = segment.live.saturating_sub(location.size);
+        }
+    }
+
+    fn check(&self) -> Result<(), StoreError> {
+        match &self.failed {
+            Some((kind, message)) => Err(io::Error::new(*kind, message.clone()).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Full segments, which are compacted together.
+    fn sealed(&self) -> Vec<u64> {
+        self.segments
+            .keys()
+            .copied()
+            .filter(|first| *first != self.active)
+            .collect()
+    }
+}
+
+/// Safe to share between threads, though every call takes the same lock, as
+/// every session appends to the same segment.
+pub struct DB {
+    segments_dir: PathBuf,
+    /// Copies of segments with unreadable frames, see `CorruptionPolicy`.
+    corrupt: PathBuf,
+    durability: Durability,
+    on_corrupt: CorruptionPolicy,
+    segment_len: u64,
+    min_compaction_bytes: u64,
+    state: Mutex<State>,
+    loaded_payloads: Mutex<HashMap<u64, Weak<Vec<u8>>>>,
+}
+
+impl DB {
+    pub fn new(location: &Path) -> Result<Self, StoreError> {
+        Self::with_config(location, StoreConfig::default())
+    }
+
+    pub fn with_durability(location: &Path, durability: Durability) -> Result<Self, StoreError> {
+        let config = StoreConfig {
+            durability,
+            ..StoreConfig::default()
+        };
+        Self::with_config(location, config)
+    }
+
+    /// Creates `location` if needed, replaying every segment into the index.
+    /// Unreadable frames are handled as `config.on_corrupt` says, failing the
+    /// open by default, since they can't be told apart by session.
+    pub fn with_config(location: &Path, config: StoreConfig) -> Result<Self, StoreError> {
+        if !location.exists() {
+            create_dir_all(location)?;
+        }
+        format::migrate(location, config.durability, FORMAT_VERSION, MIGRATIONS)?;
+
+        let segments_dir = location.join("Segments");
+        if !segments_dir.exists() {
+            create_dir_all(&segments_dir)?;
+        }
+
+        let db = DB {
+            segments_dir,
+            corrupt: location.join("Corrupt"),
+            durability: config.durability,
+            on_corrupt: config.on_corrupt,
+            segment_len: SEGMENT_LEN,
+            min_compaction_bytes: MIN_COMPACTION_BYTES,
+            state: Mutex::new(State::default()),
+            loaded_payloads: Mutex::new(HashMap::new()),
+        };
+        db.replay()?;
+
+        Ok(db)
+    }
+
+    pub fn append(&self, session_id: &str, publish: Publish) -> Result<(), StoreError> {
+        self.append_batch(&[(session_id, publish)])
+    }
+
+    /// Appends `publish` to each of `session_ids`, storing its payload once.
+    pub fn append_fanout(&self, publish: Publish, session_ids: &[&str]) -> Result<(), StoreError> {
+        let writes: Vec<(&str, Publish)> = session_ids
+            .iter()
+            .map(|session_id| (*session_id, publish.clone()))
+            .collect();
+        self.append_batch(&writes)
+    }
+
+    /// Appends several publishes, in order, possibly to different sessions,
+    /// as a single frame, with each payload not yet stored written once.
+    pub fn append_batch(&self, writes: &[(&str, Publish)]) -> Result<(), StoreError> {
+        for (session_id, _) in writes {
+            encode_session_id(session_id)?;
+        }
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        let mut state = lock(&self.state);
+        state.check()?;
+
+        let mut entries = Vec::new();
+        let mut next_sequences: HashMap<&str, u64> = HashMap::new();
+        for (session_id, publish) in writes {
+            let payload_id = publish.payload.id;
+            let written = entries.iter().any(
+                |entry| matches!(entry, Entry::Payload { payload_id: id, .. } if *id == payload_id),
+            );
+            if !state.payloads.contains_key(&payload_id) && !written {
+                entries.push(Entry::Payload {
+                    payload_id,
+                    bytes: publish.payload.bytes.clone(),
+                });
+            }
+
+            let sequence = next_sequences.entry(session_id).or_insert_with(|| {
+                state
+                    .sessions
+                    .get(*session_id)
+                    .and_then(|queue| queue.keys().next_back())
+                    .map_or(0, |last| last + 1)
+            });
+            entries.push(Entry::Publish {
+                session_id: session_id.to_string(),
+                sequence: *sequence,
+                body: DiskPublish {
+                    packet_id: publish.packet_id,
+                    retain: publish.retain,
+                    topic_name: publish.topic_name.clone(),
+                    payload_id,
+                },
+            });
+            *sequence += 1;
+        }
+
+        self.write_frame(&mut state, &entries)
+    }
+
+    /// Writes a tombstone for the oldest publish with the given packet id.
+    pub fn ack(&self, session_id: &str, packet_id: u16) -> Result<(), StoreError> {
+        encode_session_id(session_id)?;
+
+        let mut state = lock(&self.state);
+        state.check()?;
+
+        let sequence = state.sessions.get(session_id).and_then(|queue| {
+            queue
+                .iter()
+                .find(|(_, queued)| queued.packet_id == packet_id)
+                .map(|(sequence, _)| *sequence)
+        });
+        match sequence {
+            Some(sequence) => {
+                let tombstone = Entry::Ack {
+                    session_id: session_id.to_owned(),
+                    sequence,
+                };
+                self.write_frame(&mut state, &[tombstone])
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Unreadable frames and payloads are handled as the store's
+    /// `CorruptionPolicy` says.
+    pub fn read(&self, session_id: &str) -> Result<Vec<Publish>, StoreError> {
+        encode_session_id(session_id)?;
+
+        let mut state = lock(&self.state);
+        state.check()?;
+        let queue: Vec<(u64, QueuedPublish)> = match state.sessions.get(session_id) {
+            Some(queue) => queue.iter().map(|(s, q)| (*s, *q)).collect(),
+            None => return Ok(Vec::new()),
+        };
+
+        let mut result = Vec::new();
+        let mut dropped = Vec::new();
+        let mut damaged = Vec::new();
+        let mut frame: Option<(Location, Vec<Entry>)> = None;
+        for (sequence, queued) in queue {
+            let error = match self.load_publish(&state, queued.location, &mut frame) {
+                Ok(body) => match self.get_payload(&state, body.payload_id) {
+                    Ok(Some(bytes)) => {
+                        result.push(Publish {
+                            packet_id: body.packet_id,
+                            retain: body.retain,
+                            topic_name: body.topic_name,
+                            payload: Payload {
+                                id: body.payload_id,
+                                bytes,
+                            },
+                        });
+                        continue;
+                    }
+                    Ok(None) => StoreError::MissingPayload {
+                        payload_id: body.payload_id,
+                    },
+                    Err(e @ StoreError::Corrupt { .. }) => {
+                        if let Some(stored) = state.payloads.get(&body.payload_id) {
+                            damaged.push(stored.location.segment);
+                        }
+                        e
+                    }
+                    Err(e) => return Err(e),
+                },
+                Err(e @ StoreError::Corrupt { .. }) => {
+                    damaged.push(queued.location.segment);
+                    e
+                }
+                Err(e) => return Err(e),
+            };
+
+            if self.on_corrupt == CorruptionPolicy::Error {
+                return Err(error);
+            }
+            dropped.push(sequence);
+        }
+
+        if !dropped.is_empty() && self.on_corrupt == CorruptionPolicy::Quarantine {
+            damaged.sort_unstable();
+            damaged.dedup();
+            for first in damaged {
+                let last = state.segments[&first].last;
+                self.quarantine(&self.segment_path(first, last))?;
+            }
+            let tombstones: Vec<Entry> = dropped
+                .into_iter()
+                .map(|sequence| Entry::Ack {
+                    session_id: session_id.to_owned(),
+                    sequence,
+                })
+                .collect();
+            self.write_frame(&mut state, &tombstones)?;
+        }
+
+        Ok(result)
+    }
+
+    /// Copies every full segment's live entries into a single new segment,
+    /// deleting the old ones.
+    pub fn compact(&self) -> Result<(), StoreError> {
+        let mut state = lock(&self.state);
+        state.check()?;
+
+        self.compact_locked(&mut state)
+    }
+
+    pub fn stats(&self) -> SegmentStats {
+        let state = lock(&self.state);
+
+        SegmentStats {
+            segments: state.segments.len(),
+            file_bytes: state.segments.values().map(|s| s.len).sum(),
+            live_bytes: state.segments.values().map(|s| s.live).sum(),
+            appended_bytes: state.appended_bytes,
+            compacted_bytes: state.compacted_bytes,
+            compaction_errors: state.compaction_errors,
+            last_compaction_error: state.last_compaction_error.clone(),
+        }
+    }
+
+    /// Segment files are named by the range of segment numbers they hold.
+    fn segment_path(&self, first: u64, last: u64) -> PathBuf {
+        self.segments_dir
+            .join(format!("{:016x}-{:016x}", first, last))
+    }
+
+    /// Rebuilds the index from the segment files, deleting any left behind by
+    /// an interrupted compaction, whose data is in the segment replacing them.
+    fn replay(&self) -> Result<(), StoreError> {
+        let mut files: Vec<(u64, u64)> = Vec::new();
+        for entry in read_dir(&self.segments_dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if is_temp_file(&name) {
+                continue;
+            }
+            let range = name.split_once('-').and_then(|(first, last)| {
+                let first = u64::from_str_radix(first, 16).ok()?;
+                let last = u64::from_str_radix(last, 16).ok()?;
+                Some((first, last))
+            });
+            match range {
+                Some(range) => files.push(range),
+                None => return Err(StoreError::corrupt(&path, 0, "not a segment")),
+            }
+        }
+
+        // A compacted segment has a lower first number or a higher last one
+        files.sort_unstable_by_key(|(first, last)| (*first, u64::MAX - *last));
+        let mut state = lock(&self.state);
+        let mut covered = None;
+        for (first, last) in files {
+            if covered.is_some_and(|covered| last <= covered) {
+                remove_file(self.segment_path(first, last))?;
+                continue;
+            }
+            covered = Some(last);
+
+            let path = self.segment_path(first, last);
+            let scanned = scan_log::<Vec<Entry>>(&path)?;
+            if let Some(error) = scanned.corrupt.into_iter().next() {
+                match self.on_corrupt {
+                    CorruptionPolicy::Error => return Err(error),
+                    CorruptionPolicy::Skip => {}
+                    CorruptionPolicy::Quarantine => {
+                        self.quarantine(&path)?;
+                    }
+                }
+            }
+
+            state.segments.insert(
+                first,
+                Segment {
+                    last,
+                    len: fs::metadata(&path)?.len(),
+                    live: 0,
+                },
+            );
+            for frame in scanned.entries {
+                for (index, entry) in frame.record.iter().enumerate() {
+                    let location = Location {
+                        segment: first,
+                        offset: frame.offset,
+                        len: frame.len,
+                        index,
+                        size: bincode::serialized_size(entry)?,
+                    };
+                    state.apply(entry, location);
+                }
+            }
+        }
+        sync_dir(&self.segments_dir, self.durability)?;
+
+        // Only left by frames lost to corruption
+        let orphans: Vec<u64> = state
+            .payloads
+            .iter()
+            .filter(|(_, stored)| stored.refcount == 0)
+            .map(|(payload_id, _)| *payload_id)
+            .collect();
+        for payload_id in orphans {
+            let stored = state.payloads.remove(&payload_id).expect("payload");
+            state.kill(stored.location);
+        }
+
+        state.active = state
+            .segments
+            .values()
+            .map(|s| s.last + 1)
+            .max()
+            .unwrap_or(0);
+
+        Ok(())
+    }
+
+    /// Appends a frame to the active segment and indexes its entries, then
+    /// starts a new segment if it is full, compacting the full ones if enough
+    /// of them is dead. The frame is durable by then, so a failed compaction
+    /// is only recorded in the stats.
+    fn write_frame(&self, state: &mut State, entries: &[Entry]) -> Result<(), StoreError> {
+        let mut sizes = Vec::with_capacity(entries.len());
+        for entry in entries {
+            sizes.push(bincode::serialized_size(entry)?);
+        }
+        let mut bytes = Vec::new();
+        encode_record(&entries, &mut bytes)?;
+
+        let active = state.active;
+        let offset = match append_log(&self.segment_path(active, active), &bytes, self.durability) {
+            Ok(offset) => offset,
+            Err(e) => {
+                state.failed = Some((e.kind(), e.to_string()));
+                return Err(e.into());
+            }
+        };
+
+        let segment = state.segments.entry(active).or_insert(Segment {
+            last: active,
+            ..Segment::default()
+        });
+        segment.len = offset + bytes.len() as u64;
+        let full = segment.len >= self.segment_len;
+        state.appended_bytes += bytes.len() as u64;
+
+        for (index, (entry, size)) in entries.iter().zip(sizes).enumerate() {
+            let location = Location {
+                segment: active,
+                offset,
+                len: bytes.len(),
+                index,
+                size,
+            };
+            state.apply(entry, location);
+        }
+
+        if full {
+            state.active += 1;
+
+            let sealed = state.sealed();
+            let len: u64 = sealed.iter().map(|first| state.segments[first].len).sum();
+            let live: u64 = sealed.iter().map(|first| state.segments[first].live).sum();
+            let dead = len - live;
+            if sealed.len() > 1 && dead >= self.min_compaction_bytes && dead >= live {
+                if let Err(e) = self.compact_locked(state) {
+                    state.compaction_errors += 1;
+                    state.last_compaction_error = Some(e.to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compact_locked(&self, state: &mut State) -> Result<(), StoreError> {
+        let sealed = state.sealed();
+        let (first, last) = match (sealed.first(), sealed.last()) {
+            (Some(first), Some(last)) => (*first, state.segments[last].last),
+            _ => return Ok(()),
+        };
+
+        // Payloads go first, so they are indexed before what references them
+        let mut payloads = new_log();
+        let mut publishes = Vec::new();
+        let mut moved_payloads = Vec::new();
+        let mut moved_publishes = Vec::new();
+        for segment in sealed.iter() {
+            let path = self.segment_path(*segment, state.segments[segment].last);
+            // Corrupt frames were already handled when the store was opened
+            for frame in scan_log::<Vec<Entry>>(&path)?.entries {
+                for (index, entry) in frame.record.into_iter().enumerate() {
+                    let (stored, into, moved) = match &entry {
+                        Entry::Payload { payload_id, .. } => (
+                            state.payloads.get(payload_id).map(|p| p.location),
+                            &mut payloads,
+                            &mut moved_payloads,
+                        ),
+                        Entry::Publish {
+                            session_id,
+                            sequence,
+                            ..
+                        } => (
+                            state
+                                .sessions
+                                .get(session_id)
+                                .and_then(|queue| queue.get(sequence))
+                                .map(|queued| queued.location),
+                            &mut publishes,
+                            &mut moved_publishes,
+                        ),
+                        // Only shadow entries of the segments being compacted
+                        Entry::Ack { .. } | Entry::RemoveSession { .. } => continue,
+                    };
+                    let size = match stored {
+                        Some(location) if location.is(*segment, frame.offset, index) => {
+                            location.size
+                        }
+                        _ => continue,
+                    };
+
+                    let offset = into.len();
+                    encode_record(&vec![&entry], into)?;
+                    let location = Location {
+                        segment: first,
+                        offset: offset as u64,
+                        len: into.len() - offset,
+                        index: 0,
+                        size,
+                    };
+                    moved.push((entry, location));
+                }
+            }
+        }
+
+        let base = payloads.len() as u64;
+        payloads.extend_from_slice(&publishes);
+        write_atomic(&self.segment_path(first, last), &payloads, self.durability)?;
+
+        // The index moves to the new segment before the old ones are deleted,
+        // so it stays usable if deleting them fails
+        let old: Vec<(u64, u64)> = sealed
+            .iter()
+            .filter_map(|segment| state.segments.remove(segment).map(|s| (*segment, s.last)))
+            .collect();
+
+        let mut live = 0;
+        for (entry, location) in moved_payloads {
+            if let Entry::Payload { payload_id, .. } = entry {
+                if let Some(stored) = state.payloads.get_mut(&payload_id) {
+                    stored.location = location;
+                }
+            }
+            live += location.size;
+        }
+        for (entry, mut location) in moved_publishes {
+            location.offset += base;
+            if let Entry::Publish {
+                session_id,
+                sequence,
+                ..
+            } = entry
+            {
+                if let Some(queued) = state
+                    .sessions
+                    .get_mut(&session_id)
+                    .and_then(|queue| queue.get_mut(&sequence))
+                {
+                    queued.location = location;
+                }
+            }
+            live += location.size;
+        }
+        state.segments.insert(
+            first,
+            Segment {
+                last,
+                len: payloads.len() as u64,
+                live,
+            },
+        );
+        state.compacted_bytes += payloads.len() as u64;
+
+        // Left behind by a crash, they are deleted when the store is opened
+        for (segment, old_last) in old {
+            if (segment, old_last) != (first, last) {
+                remove_file(self.segment_path(segment, old_last))?;
+            }
+        }
+        sync_dir(&self.segments_dir, self.durability)?;
+
+        Ok(())
+    }
+
+    /// Reads the publish at `location`, reusing `frame` if it was the last
+    /// frame read.
+    fn load_publish(
+        &self,
+        state: &State,
+        location: Location,
+        frame: &mut Option<(Location, Vec<Entry>)>,
+    ) -> Result<DiskPublish, StoreError> {
+        let cached = frame
+            .as_ref()
+            .is_some_and(|(at, _)| at.segment == location.segment && at.offset == location.offset);
+        if !cached {
+            *frame = Some((location, self.read_frame(state, location)?));
+        }
+
+        match frame
+            .as_ref()
+            .and_then(|(_, entries)| entries.get(location.index))
+        {
+            Some(Entry::Publish { body, .. }) => Ok(body.clone()),
+            _ => Err(self.misplaced(state, location, "publish")),
+        }
+    }
+
+    fn get_payload(
+        &self,
+        state: &State,
+        payload_id: u64,
+    ) -> Result<Option<Arc<Vec<u8>>>, StoreError> {
+        if let Some(payload) = lock(&self.loaded_payloads)
+            .get(&payload_id)
+            .and_then(|payload| payload.upgrade())
+        {
+            return Ok(Some(payload));
+        }
+
+        let location = match state.payloads.get(&payload_id) {
+            Some(stored) => stored.location,
+            None => return Ok(None),
+        };
+        let bytes = match self.read_frame(state, location)?.get(location.index) {
+            Some(Entry::Payload { bytes, .. }) => bytes.clone(),
+            _ => return Err(self.misplaced(state, location, "payload")),
+        };
+        lock(&self.loaded_payloads).insert(payload_id, Arc::downgrade(&bytes));
+
+        Ok(Some(bytes))
+    }
+
+    fn read_frame(&self, state: &State, location: Location) -> Result<Vec<Entry>, StoreError> {
+        let path = self.segment_path(location.segment, state.segments[&location.segment].last);
+        let mut buffer = vec![0; location.len];
+        let mut file = File::open(&path)?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        file.read_exact(&mut buffer)?;
+
+        match decode_record(&path, location.offset, &buffer)? {
+            Some((entries, _)) => Ok(entries),
+            None => Err(StoreError::corrupt(
+                &path,
+                location.offset,
+                "truncated frame",
+            )),
+        }
+    }
+
+    fn misplaced(&self, state: &State, location: Location, expected: &str) -> StoreError {
+        let path = self.segment_path(location.segment, state.segments[&location.segment].last);
+        StoreError::corrupt(
+            path,
+            location.offset,
+            format!("expected a {} at index {}", expected, location.index),
+        )
+    }
+
+    /// Copies a segment into `Corrupt/`, returning where to. Its unreadable
+    /// frames are dropped when it is next compacted.
+    fn quarantine(&self, path: &Path) -> Result<PathBuf, StoreError> {
+        if !self.corrupt.exists() {
+            create_dir_all(&self.corrupt)?;
+        }
+
+        // A segment can be quarantined more than once, never overwrite
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let mut target = self.corrupt.join(name.as_ref());
+        let mut attempt = 0;
+        while target.exists() {
+            attempt += 1;
+            target = self.corrupt.join(format!("{}.{}", name, attempt));
+        }
+
+        write_atomic(&target, &fs::read(path)?, self.durability)?;
+
+        Ok(target)
+    }
+}
+
+impl SessionStore for DB {
+    fn open(location: &Path) -> Result<Self, StoreError> {
+        Self::new(location)
+    }
+
+    fn append(&self, session_id: &str, publish: Publish) -> Result<(), StoreError> {
+        self.append(session_id, publish)
+    }
+
+    fn append_batch(&self, writes: &[(&str, Publish)]) -> Result<(), StoreError> {
+        self.append_batch(writes)
+    }
+
+    fn read(&self, session_id: &str) -> Result<Vec<Publish>, StoreError> {
+        self.read(session_id)
+    }
+
+    fn ack(&self, session_id: &str, packet_id: u16) -> Result<(), StoreError> {
+        self.ack(session_id, packet_id)
+    }
+
+    fn remove_session(&self, session_id: &str) -> Result<(), StoreError> {
+        encode_session_id(session_id)?;
+
+        let mut state = lock(&self.state);
+        state.check()?;
+        if !state.sessions.contains_key(session_id) {
+            return Ok(());
+        }
+
+        let tombstone = Entry::RemoveSession {
+            session_id: session_id.to_owned(),
+        };
+        self.write_frame(&mut state, &[tombstone])
+    }
+
+    /// Only sessions with queued publishes are kept.
+    fn list_sessions(&self) -> Result<Vec<String>, StoreError> {
+        let state = lock(&self.state);
+        state.check()?;
+
+        Ok(state.sessions.keys().cloned().collect())
+    }
+
+    /// Starts a new segment and compacts every full one, however little of
+    /// them is dead.
+    fn clean(&self) -> Result<(), StoreError> {
+        let mut state = lock(&self.state);
+        state.check()?;
+
+        if state.segments.contains_key(&state.active) {
+            state.active += 1;
+        }
+        self.compact_locked(&mut state)?;
+        drop(state);
+        lock(&self.loaded_payloads).retain(|_, val| val.strong_count() > 0);
+
+        Ok(())
+    }
+}
+
+impl Inspect for DB {
+    /// Payloads are keyed by id. Their stored size is that of their entry.
+    fn payloads(&self) -> Result<Vec<PayloadInfo>, StoreError> {
+        let state = lock(&self.state);
+        state.check()?;
+
+        let mut payloads: Vec<(u64, StoredPayload)> =
+            state.payloads.iter().map(|(id, p)| (*id, *p)).collect();
+        payloads.sort_unstable_by_key(|(payload_id, _)| *payload_id);
+
+        Ok(payloads
+            .into_iter()
+            .map(|(payload_id, stored)| PayloadInfo {
+                key: payload_id.to_string(),
+                stored_bytes: stored.location.size,
+                refcount: stored.refcount,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use session_store::format::Header;
+    use session_store::testing;
+    use std::fs::{create_dir, remove_dir};
+    use tempfile::tempdir;
+
+    fn segment_names(root: &Path) -> Vec<String> {
+        let mut names: Vec<String> = read_dir(root.join("Segments"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_read_write() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let publish = faker.make_fake_publish(vec![1, 2, 3, 4, 5]);
+        db.append("Session 1", publish.clone()).expect("Publish 1");
+
+        assert_eq!(db.read("Session 1").unwrap(), vec![publish.clone()]);
+        assert!(db.read("Session 2").unwrap().is_empty());
+
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![publish]);
+    }
+
+    #[test]
+    fn test_append_ack() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let first = faker.make_fake_publish(vec![1]);
+        let second = faker.make_fake_publish(vec![2]);
+        let mut again = faker.make_fake_publish(vec![3]);
+        again.packet_id = first.packet_id;
+        for publish in [&first, &second, &again].iter() {
+            db.append("Session 1", (*publish).clone()).expect("Publish");
+        }
+
+        db.ack("Session 1", first.packet_id).unwrap();
+        db.ack("Session 1", 9999).unwrap();
+        db.ack("Session 2", first.packet_id).unwrap();
+        assert_eq!(
+            db.read("Session 1").unwrap(),
+            vec![second.clone(), again.clone()]
+        );
+
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![second, again]);
+        assert_eq!(db.payloads().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_append_fanout() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let shared = faker.make_fake_publish(vec![1, 2, 3]);
+        db.append_fanout(shared.clone(), &["Session 1", "Session 2", "Session 3"])
+            .expect("Fanout");
+        let other = faker.make_fake_publish(vec![4, 5]);
+        db.append_batch(&[("Session 2", other.clone()), ("Session 1", shared.clone())])
+            .expect("Batch");
+
+        // one frame per batch, with each payload once
+        let frames = scan_log::<Vec<Entry>>(&db.segment_path(0, 0))
+            .unwrap()
+            .entries;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].record.len(), 4);
+        assert_eq!(frames[1].record.len(), 3);
+
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(
+            db.read("Session 1").unwrap(),
+            vec![shared.clone(), shared.clone()]
+        );
+        assert_eq!(db.read("Session 2").unwrap(), vec![shared.clone(), other]);
+        assert_eq!(db.payloads().unwrap()[0].refcount, 4);
+
+        // an invalid session id fails the whole batch before anything is written
+        assert!(matches!(
+            db.append_fanout(shared, &["Session 4", ""]),
+            Err(StoreError::InvalidSessionId(_))
+        ));
+        assert_eq!(db.list_sessions().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_remove_session() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let old = faker.make_fake_publish(vec![1]);
+        db.append("Session 1", old.clone()).expect("Publish 1");
+        db.append("Session 2", old).expect("Publish 2");
+        db.remove_session("Session 1").unwrap();
+        let new = faker.make_fake_publish(vec![2]);
+        db.append("Session 1", new.clone()).expect("Publish 3");
+
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![new]);
+        assert_eq!(db.payloads().unwrap().len(), 2);
+        db.remove_session("Session 2").unwrap();
+        assert_eq!(db.payloads().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let mut db = DB::with_durability(path, Durability::None).expect("Make db");
+        db.segment_len = 4096;
+        db.min_compaction_bytes = 8192;
+        let mut faker = Faker::new();
+
+        let kept = faker.make_fake_publish(vec![9; 100]);
+        db.append("Session 0", kept.clone()).expect("Publish");
+        let mut queued = Vec::new();
+        for i in 0..2000 {
+            let publish = faker.make_fake_publish(vec![i as u8; 100]);
+            db.append_fanout(publish.clone(), &["Session 1", "Session 2"])
+                .expect("Publish");
+            queued.push(publish);
+            if queued.len() > 10 {
+                let acked = queued.remove(0);
+                db.ack("Session 1", acked.packet_id).expect("Ack");
+                db.ack("Session 2", acked.packet_id).expect("Ack");
+            }
+        }
+
+        let stats = db.stats();
+        assert!(stats.compacted_bytes > 0);
+        assert!(stats.file_bytes < 64 * 1024, "{:?}", stats);
+        assert!(stats.segments < 10, "{:?}", stats);
+
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 0").unwrap(), vec![kept]);
+        assert_eq!(db.read("Session 1").unwrap(), queued);
+        assert_eq!(db.read("Session 2").unwrap(), queued);
+        assert_eq!(db.payloads().unwrap().len(), queued.len() + 1);
+    }
+
+    #[test]
+    fn test_clean() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let kept = faker.make_fake_publish(vec![1]);
+        let acked = faker.make_fake_publish(vec![2]);
+        db.append_fanout(kept.clone(), &["Session 1", "Session 2"])
+            .expect("Publish 1");
+        db.append("Session 1", acked.clone()).expect("Publish 2");
+        db.ack("Session 1", acked.packet_id).unwrap();
+        db.remove_session("Session 2").unwrap();
+
+        db.clean().unwrap();
+        let stats = db.stats();
+        assert_eq!(stats.segments, 1);
+        assert_eq!(
+            segment_names(path),
+            vec!["0000000000000000-0000000000000000"]
+        );
+        let frames = scan_log::<Vec<Entry>>(&db.segment_path(0, 0))
+            .unwrap()
+            .entries;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(
+            stats.file_bytes,
+            fs::metadata(db.segment_path(0, 0)).unwrap().len()
+        );
+
+        // appends go to a new segment after cleaning
+        let new = faker.make_fake_publish(vec![3]);
+        db.append("Session 1", new.clone()).expect("Publish 3");
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![kept, new]);
+        assert_eq!(db.list_sessions().unwrap(), vec!["Session 1"]);
+        assert_eq!(db.payloads().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_interrupted_compaction() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let mut db = DB::new(path).expect("Make db");
+        db.segment_len = 1;
+        let mut faker = Faker::new();
+
+        let publishes: Vec<Publish> = (0..4).map(|i| faker.make_fake_publish(vec![i])).collect();
+        for publish in publishes.iter() {
+            db.append("Session 1", publish.clone()).expect("Publish");
+        }
+        db.ack("Session 1", publishes[0].packet_id).unwrap();
+        let old: Vec<(String, Vec<u8>)> = segment_names(path)
+            .into_iter()
+            .map(|name| {
+                let bytes = fs::read(path.join("Segments").join(&name)).unwrap();
+                (name, bytes)
+            })
+            .collect();
+        assert_eq!(old.len(), 5);
+
+        db.clean().unwrap();
+        drop(db);
+        assert_eq!(
+            segment_names(path),
+            vec!["0000000000000000-0000000000000004"]
+        );
+
+        // crash before the old segments were deleted
+        for (name, bytes) in old.iter() {
+            fs::write(path.join("Segments").join(name), bytes).unwrap();
+        }
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), publishes[1..].to_vec());
+        assert_eq!(
+            segment_names(path),
+            vec!["0000000000000000-0000000000000004"]
+        );
+    }
+
+    #[test]
+    fn test_failed_compaction_keeps_appends() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let mut db = DB::new(path).expect("Make db");
+        db.segment_len = 1;
+        db.min_compaction_bytes = 0;
+        let mut faker = Faker::new();
+
+        // Compaction can't replace a directory with its new segment
+        let blocked: Vec<PathBuf> = (1..16).map(|last| db.segment_path(0, last)).collect();
+        for path in blocked.iter() {
+            create_dir(path).unwrap();
+        }
+
+        let publishes: Vec<Publish> = (0..4).map(|i| faker.make_fake_publish(vec![i])).collect();
+        for publish in publishes.iter() {
+            db.append("Session 1", publish.clone()).expect("Publish");
+        }
+        for publish in publishes[..3].iter() {
+            db.ack("Session 1", publish.packet_id).expect("Ack");
+        }
+        let stats = db.stats();
+        assert!(stats.compaction_errors > 0, "{:?}", stats);
+        assert!(stats.last_compaction_error.is_some());
+        assert_eq!(db.read("Session 1").unwrap(), publishes[3..].to_vec());
+
+        for path in blocked.iter() {
+            remove_dir(path).unwrap();
+        }
+        db.clean().unwrap();
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), publishes[3..].to_vec());
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        drop(DB::new(path).expect("Make db"));
+
+        let newer = Header {
+            version: FORMAT_VERSION + 1,
+            ..Header::current()
+        };
+        fs::write(path.join("Format"), newer.encode()).unwrap();
+        assert!(matches!(
+            DB::new(path),
+            Err(StoreError::UnsupportedVersion { version, .. }) if version == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn test_torn_tail() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let first = faker.make_fake_publish(vec![1]);
+        db.append("Session 1", first.clone()).expect("Publish 1");
+        db.append("Session 1", faker.make_fake_publish(vec![2]))
+            .expect("Publish 2");
+        drop(db);
+
+        let segment = path
+            .join("Segments")
+            .join("0000000000000000-0000000000000000");
+        let len = fs::metadata(&segment).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&segment)
+            .unwrap()
+            .set_len(len - 5)
+            .unwrap();
+
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![first.clone()]);
+        let third = faker.make_fake_publish(vec![3]);
+        db.append("Session 1", third.clone()).expect("Publish 3");
+        drop(db);
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![first, third]);
+    }
+
+    #[test]
+    fn test_corrupt_frame() {
+        let dir = tempdir().unwrap();
+        let path = dir.path();
+        let db = DB::new(path).expect("Make db");
+        let mut faker = Faker::new();
+
+        let lost = faker.make_fake_publish(vec![1; 32]);
+        let kept = faker.make_fake_publish(vec![2; 32]);
+        db.append("Session 1", lost).expect("Publish 1");
+        db.append("Session 1", kept.clone()).expect("Publish 2");
+        drop(db);
+
+        let segment = path
+            .join("Segments")
+            .join("0000000000000000-0000000000000000");
+        let mut bytes = fs::read(&segment).unwrap();
+        bytes[20] ^= 1;
+        fs::write(&segment, &bytes).unwrap();
+
+        assert!(matches!(DB::new(path), Err(StoreError::Corrupt { .. })));
+
+        let config = StoreConfig {
+            on_corrupt: CorruptionPolicy::Skip,
+            ..StoreConfig::default()
+        };
+        let db = DB::with_config(path, config).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![kept.clone()]);
+        drop(db);
+
+        let config = StoreConfig {
+            on_corrupt: CorruptionPolicy::Quarantine,
+            ..StoreConfig::default()
+        };
+        let db = DB::with_config(path, config).expect("Open db");
+        assert!(path
+            .join("Corrupt")
+            .join("0000000000000000-0000000000000000")
+            .exists());
+        db.clean().unwrap();
+        drop(db);
+
+        // compaction dropped the unreadable frame
+        let db = DB::new(path).expect("Open db");
+        assert_eq!(db.read("Session 1").unwrap(), vec![kept]);
+    }
+
+    #[test]
+    fn test_invalid_session_id() {
+        let dir = tempdir().unwrap();
+        let db = DB::new(dir.path()).expect("Make db");
+        testing::check_invalid_session_id(&db, dir.path());
+    }
+
+    #[test]
+    fn test_concurrent_sessions() {
+        let dir = tempdir().unwrap();
+        let mut db = DB::with_durability(dir.path(), Durability::None).expect("Make db");
+        db.segment_len = 4096;
+        db.min_compaction_bytes = 4096;
+        testing::check_concurrent_sessions(&db, 50);
+    }
+
+    struct Faker {
+        packet_id: u16,
+        payload_id: u64,
+    }
+
+    impl Faker {
+        fn new() -> Self {
+            Faker {
+                packet_id: 100,
+                payload_id: 1000,
+            }
+        }
+
+        fn make_fake_publish(&mut self, payload: Vec<u8>) -> Publish {
+            self.packet_id += 2;
+            self.payload_id += 1;
+
+            Publish {
+                packet_id: self.packet_id,
+                payload: Payload {
+                    id: self.payload_id,
+                    bytes: Arc::new(payload),
+                },
+                retain: true,
+                topic_name: "fake".to_owned(),
+            }
+        }
+    }
+}
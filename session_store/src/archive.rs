@@ -0,0 +1,264 @@
+//! Portable archive of a store's sessions, for moving them between devices or
+//! between store layouts. An archive is JSON Lines, one object per line,
+//! tagged by `type`:
+//!
+//! - `{"type":"header","version":1}` always comes first.
+//! - `{"type":"payload","id":1,"data":"AQID"}` holds a payload, base64
+//!   encoded. Later publishes with that payload id use it, until another
+//!   payload record with the same id replaces it.
+//! - `{"type":"publish","session":"Session 1","packet_id":1,"retain":false,"topic_name":"a/b","payload_id":1}`
+//!   queues a publish at the end of its session.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+
+use crate::{Payload, Publish, SessionStore, StoreError};
+
+/// Archive version written by this build.
+pub const ARCHIVE_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Line {
+    Header {
+        version: u32,
+    },
+    Payload {
+        id: u64,
+        data: String,
+    },
+    Publish {
+        session: String,
+        packet_id: u16,
+        retain: bool,
+        topic_name: String,
+        payload_id: u64,
+    },
+}
+
+/// What an export or import moved.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ArchiveStats {
+    pub sessions: usize,
+    pub publishes: usize,
+    pub payloads: usize,
+}
+
+/// Writes every session in `store` to `writer`, sessions in order of id and
+/// each session's publishes in queue order.
+pub fn export<S, W>(store: &S, mut writer: W) -> Result<ArchiveStats, StoreError>
+where
+    S: SessionStore,
+    W: Write,
+{
+    let mut stats = ArchiveStats::default();
+    write_line(
+        &mut writer,
+        &Line::Header {
+            version: ARCHIVE_VERSION,
+        },
+    )?;
+
+    let mut session_ids = store.list_sessions()?;
+    session_ids.sort();
+
+    // Hash of the payload last written under each id, so shared payloads are
+    // written once without holding on to every one of them
+    let mut written: HashMap<u64, blake3::Hash> = HashMap::new();
+    for session_id in session_ids {
+        for publish in store.read(&session_id)? {
+            let payload = publish.payload;
+            let hash = blake3::hash(&payload.bytes);
+            if written.get(&payload.id) != Some(&hash) {
+                let line = Line::Payload {
+                    id: payload.id,
+                    data: STANDARD.encode(payload.bytes.as_slice()),
+                };
+                write_line(&mut writer, &line)?;
+                written.insert(payload.id, hash);
+                stats.payloads += 1;
+            }
+
+            let line = Line::Publish {
+                session: session_id.clone(),
+                packet_id: publish.packet_id,
+                retain: publish.retain,
+                topic_name: publish.topic_name,
+                payload_id: payload.id,
+            };
+            write_line(&mut writer, &line)?;
+            stats.publishes += 1;
+        }
+        stats.sessions += 1;
+    }
+    writer.flush()?;
+
+    Ok(stats)
+}
+
+/// Appends every publish in the archive read from `reader` to `store`. Sessions
+/// already in the store keep their publishes, with the imported ones queued
+/// after them.
+pub fn import<S, R>(store: &S, reader: R) -> Result<ArchiveStats, StoreError>
+where
+    S: SessionStore,
+    R: BufRead,
+{
+    let mut stats = ArchiveStats::default();
+    let mut payloads: HashMap<u64, Arc<Vec<u8>>> = HashMap::new();
+    let mut sessions = HashSet::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let number = i + 1;
+        let line: Line = serde_json::from_str(&line).map_err(|e| invalid(number, e))?;
+
+        match line {
+            Line::Header { version } if number == 1 => {
+                if version != ARCHIVE_VERSION {
+                    return Err(invalid(
+                        number,
+                        format!("unsupported archive version {}", version),
+                    ));
+                }
+            }
+            _ if number == 1 => return Err(invalid(number, "missing header")),
+            Line::Header { .. } => return Err(invalid(number, "unexpected header")),
+            Line::Payload { id, data } => {
+                let bytes = STANDARD.decode(data).map_err(|e| invalid(number, e))?;
+                payloads.insert(id, Arc::new(bytes));
+                stats.payloads += 1;
+            }
+            Line::Publish {
+                session,
+                packet_id,
+                retain,
+                topic_name,
+                payload_id,
+            } => {
+                let bytes = match payloads.get(&payload_id) {
+                    Some(bytes) => bytes.clone(),
+                    None => {
+                        return Err(invalid(
+                            number,
+                            format!("payload {} is not in the archive", payload_id),
+                        ))
+                    }
+                };
+                let publish = Publish {
+                    packet_id,
+                    retain,
+                    topic_name,
+                    payload: Payload {
+                        id: payload_id,
+                        bytes,
+                    },
+                };
+                store.append(&session, publish)?;
+
+                sessions.insert(session);
+                stats.publishes += 1;
+            }
+        }
+    }
+    stats.sessions = sessions.len();
+
+    Ok(stats)
+}
+
+fn write_line<W: Write>(writer: &mut W, line: &Line) -> Result<(), StoreError> {
+    serde_json::to_writer(&mut *writer, line).map_err(io::Error::from)?;
+    writer.write_all(b"\n")?;
+
+    Ok(())
+}
+
+fn invalid(line: usize, reason: impl ToString) -> StoreError {
+    StoreError::InvalidArchive {
+        line,
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+
+    fn publish(packet_id: u16, payload_id: u64, bytes: Vec<u8>) -> Publish {
+        Publish {
+            packet_id,
+            retain: packet_id > 2,
+            topic_name: "a/b".to_owned(),
+            payload: Payload {
+                id: payload_id,
+                bytes: Arc::new(bytes),
+            },
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let store = MemoryStore::default();
+        store
+            .append("Session 1", publish(1, 1, vec![1, 2, 3]))
+            .unwrap();
+        store.append("Session 1", publish(2, 2, vec![])).unwrap();
+        store
+            .append("Session 2", publish(3, 1, vec![1, 2, 3]))
+            .unwrap();
+        // same id, different bytes
+        store.append("Session 2", publish(4, 1, vec![9])).unwrap();
+
+        let mut archive = Vec::new();
+        let stats = export(&store, &mut archive).unwrap();
+        assert_eq!(
+            stats,
+            ArchiveStats {
+                sessions: 2,
+                publishes: 4,
+                payloads: 3,
+            }
+        );
+        let text = String::from_utf8(archive.clone()).unwrap();
+        assert_eq!(text.lines().count(), 8);
+        assert!(text.starts_with("{\"type\":\"header\",\"version\":1}\n"));
+
+        let imported = MemoryStore::default();
+        assert_eq!(import(&imported, archive.as_slice()).unwrap(), stats);
+        for session_id in ["Session 1", "Session 2"] {
+            assert_eq!(
+                imported.read(session_id).unwrap(),
+                store.read(session_id).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_import_invalid() {
+        let store = MemoryStore::default();
+        for (archive, line) in [
+            ("", None),
+            ("{\"type\":\"payload\",\"id\":1,\"data\":\"\"}\n", Some(1)),
+            ("{\"type\":\"header\",\"version\":2}\n", Some(1)),
+            (
+                "{\"type\":\"header\",\"version\":1}\n{\"type\":\"publish\",\"session\":\"a\",\"packet_id\":1,\"retain\":false,\"topic_name\":\"t\",\"payload_id\":5}\n",
+                Some(2),
+            ),
+            ("{\"type\":\"header\",\"version\":1}\nnot json\n", Some(2)),
+        ] {
+            match (import(&store, archive.as_bytes()), line) {
+                (Ok(stats), None) => assert_eq!(stats, ArchiveStats::default()),
+                (Err(StoreError::InvalidArchive { line: at, .. }), Some(line)) => {
+                    assert_eq!(at, line)
+                }
+                (other, _) => panic!("unexpected {:?} for {:?}", other, archive),
+            }
+        }
+        assert!(store.list_sessions().unwrap().is_empty());
+    }
+}
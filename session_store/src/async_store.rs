@@ -0,0 +1,232 @@
+use std::io;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::{Publish, SessionStore, StoreError};
+
+/// Async front for a `SessionStore`. Every call runs the blocking store method
+/// on tokio's blocking pool, so file I/O never stalls the caller's runtime.
+/// Cheap to clone; clones share the same store.
+pub struct AsyncStore<S> {
+    store: Arc<S>,
+}
+
+impl<S> Clone for AsyncStore<S> {
+    fn clone(&self) -> Self {
+        AsyncStore {
+            store: self.store.clone(),
+        }
+    }
+}
+
+impl<S: SessionStore + 'static> AsyncStore<S> {
+    pub fn new(store: S) -> Self {
+        Self::from_arc(Arc::new(store))
+    }
+
+    /// Shares a store that is also used synchronously elsewhere.
+    pub fn from_arc(store: Arc<S>) -> Self {
+        AsyncStore { store }
+    }
+
+    pub async fn open(location: PathBuf) -> Result<Self, StoreError> {
+        let store = blocking(move || S::open(&location)).await?;
+
+        Ok(Self::new(store))
+    }
+
+    /// The underlying store, for calls made from blocking code.
+    pub fn inner(&self) -> &Arc<S> {
+        &self.store
+    }
+
+    pub async fn append(&self, session_id: &str, publish: Publish) -> Result<(), StoreError> {
+        let session_id = session_id.to_owned();
+        self.run(move |store| store.append(&session_id, publish))
+            .await
+    }
+
+    pub async fn append_batch(&self, writes: Vec<(String, Publish)>) -> Result<(), StoreError> {
+        self.run(move |store| {
+            let writes: Vec<(&str, Publish)> = writes
+                .iter()
+                .map(|(session_id, publish)| (session_id.as_str(), publish.clone()))
+                .collect();
+            store.append_batch(&writes)
+        })
+        .await
+    }
+
+    pub async fn append_fanout(
+        &self,
+        publish: Publish,
+        session_ids: &[&str],
+    ) -> Result<(), StoreError> {
+        let session_ids: Vec<String> = session_ids.iter().map(|s| s.to_string()).collect();
+        self.run(move |store| {
+            let session_ids: Vec<&str> = session_ids.iter().map(String::as_str).collect();
+            store.append_fanout(publish, &session_ids)
+        })
+        .await
+    }
+
+    pub async fn read(&self, session_id: &str) -> Result<Vec<Publish>, StoreError> {
+        let session_id = session_id.to_owned();
+        self.run(move |store| store.read(&session_id)).await
+    }
+
+    pub async fn ack(&self, session_id: &str, packet_id: u16) -> Result<(), StoreError> {
+        let session_id = session_id.to_owned();
+        self.run(move |store| store.ack(&session_id, packet_id))
+            .await
+    }
+
+    pub async fn remove_session(&self, session_id: &str) -> Result<(), StoreError> {
+        let session_id = session_id.to_owned();
+        self.run(move |store| store.remove_session(&session_id))
+            .await
+    }
+
+    pub async fn list_sessions(&self) -> Result<Vec<String>, StoreError> {
+        self.run(|store| store.list_sessions()).await
+    }
+
+    pub async fn clean(&self) -> Result<(), StoreError> {
+        self.run(|store| store.clean()).await
+    }
+
+    /// Runs any store-specific method on the blocking pool.
+    pub async fn run<F, T>(&self, f: F) -> Result<T, StoreError>
+    where
+        F: FnOnce(&S) -> Result<T, StoreError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = self.store.clone();
+        blocking(move || f(&store)).await
+    }
+}
+
+async fn blocking<F, T>(f: F) -> Result<T, StoreError>
+where
+    F: FnOnce() -> Result<T, StoreError> + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        // A panic in the store is the caller's, as if it had called it directly
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(StoreError::Io(io::Error::other(e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+    use crate::Payload;
+
+    fn publish(packet_id: u16) -> Publish {
+        Publish {
+            packet_id,
+            retain: false,
+            topic_name: "fake".to_owned(),
+            payload: Payload {
+                id: packet_id as u64,
+                bytes: Arc::new(vec![1, 2, 3]),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_round_trip() {
+        let store = AsyncStore::<MemoryStore>::open(PathBuf::new())
+            .await
+            .expect("Open");
+
+        store.append("Session 1", publish(1)).await.expect("Append");
+        store.append("Session 1", publish(2)).await.expect("Append");
+        store.ack("Session 1", 1).await.expect("Ack");
+
+        assert_eq!(store.read("Session 1").await.unwrap(), vec![publish(2)]);
+        assert_eq!(store.list_sessions().await.unwrap(), vec!["Session 1"]);
+        assert_eq!(store.inner().read("Session 1").unwrap().len(), 1);
+
+        store.remove_session("Session 1").await.expect("Remove");
+        store.clean().await.expect("Clean");
+        assert!(store.read("Session 1").await.unwrap().is_empty());
+
+        store
+            .append_fanout(publish(3), &["Session 1", "Session 2"])
+            .await
+            .expect("Fan out");
+        store
+            .append_batch(vec![("Session 2".to_owned(), publish(4))])
+            .await
+            .expect("Batch");
+        assert_eq!(store.read("Session 1").await.unwrap(), vec![publish(3)]);
+        assert_eq!(
+            store.read("Session 2").await.unwrap(),
+            vec![publish(3), publish(4)]
+        );
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "store panicked")]
+    async fn test_async_panic_resumed() {
+        let store = AsyncStore::new(MemoryStore::default());
+
+        let _ = store
+            .run(|_| -> Result<(), StoreError> { panic!("store panicked") })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_async_error_keeps_kind() {
+        let store = AsyncStore::new(FailingStore);
+
+        match store.clean().await {
+            Err(StoreError::InvalidSessionId(session_id)) => assert_eq!(session_id, "clean"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    /// Fails every call, naming it, for checking how errors are passed on.
+    struct FailingStore;
+
+    impl FailingStore {
+        fn fail<T>(call: &str) -> Result<T, StoreError> {
+            Err(StoreError::InvalidSessionId(call.to_owned()))
+        }
+    }
+
+    impl SessionStore for FailingStore {
+        fn open(_location: &std::path::Path) -> Result<Self, StoreError> {
+            Ok(FailingStore)
+        }
+
+        fn append(&self, _session_id: &str, _publish: Publish) -> Result<(), StoreError> {
+            Self::fail("append")
+        }
+
+        fn read(&self, _session_id: &str) -> Result<Vec<Publish>, StoreError> {
+            Self::fail("read")
+        }
+
+        fn ack(&self, _session_id: &str, _packet_id: u16) -> Result<(), StoreError> {
+            Self::fail("ack")
+        }
+
+        fn remove_session(&self, _session_id: &str) -> Result<(), StoreError> {
+            Self::fail("remove_session")
+        }
+
+        fn list_sessions(&self) -> Result<Vec<String>, StoreError> {
+            Self::fail("list_sessions")
+        }
+
+        fn clean(&self) -> Result<(), StoreError> {
+            Self::fail("clean")
+        }
+    }
+}
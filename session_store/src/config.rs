@@ -0,0 +1,22 @@
+use crate::Durability;
+
+/// What a store does when it finds a record that fails its checksum or
+/// doesn't decode.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CorruptionPolicy {
+    /// Fails the call with `StoreError::Corrupt`, leaving the data in place.
+    #[default]
+    Error,
+    /// Leaves the corrupt data in place and carries on without it.
+    Skip,
+    /// Moves the corrupt data out of the way, keeping a copy for inspection,
+    /// and carries on without it.
+    Quarantine,
+}
+
+/// Options shared by every store.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StoreConfig {
+    pub durability: Durability,
+    pub on_corrupt: CorruptionPolicy,
+}
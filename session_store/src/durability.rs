@@ -0,0 +1,143 @@
+use std::fs::{rename, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// How hard a store tries to get a write onto stable storage before returning.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Durability {
+    /// Atomic replace only; data may still be in the OS page cache on return.
+    None,
+    /// Syncs file contents before the rename, but not the directory entry.
+    Flush,
+    /// Syncs file contents and the parent directory, surviving power loss.
+    #[default]
+    Fsync,
+}
+
+/// Replaces the file at `path` with `bytes` by writing a temp file next to it
+/// and renaming it over the original, so a crash never leaves a torn file.
+pub fn write_atomic(path: &Path, bytes: &[u8], durability: Durability) -> io::Result<()> {
+    let temp = temp_path(path);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(&temp)?;
+    file.write_all(bytes)?;
+
+    match durability {
+        Durability::None => {}
+        Durability::Flush => file.sync_data()?,
+        Durability::Fsync => file.sync_all()?,
+    }
+    drop(file);
+
+    rename(&temp, path)?;
+
+    if let Some(parent) = path.parent() {
+        sync_dir(parent, durability)?;
+    }
+
+    Ok(())
+}
+
+/// Appends `bytes` to the end of the file at `path`, creating it if needed.
+pub fn write_append(path: &Path, bytes: &[u8], durability: Durability) -> io::Result<()> {
+    let created = !path.exists();
+
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    file.write_all(bytes)?;
+
+    match durability {
+        Durability::None => {}
+        Durability::Flush | Durability::Fsync => file.sync_data()?,
+    }
+
+    if created {
+        if let Some(parent) = path.parent() {
+            sync_dir(parent, durability)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Persists directory entries (created, renamed or removed files) under `path`.
+/// Only does any work for `Durability::Fsync`.
+pub fn sync_dir(path: &Path, durability: Durability) -> io::Result<()> {
+    if durability == Durability::Fsync {
+        sync_dir_entries(path)?;
+    }
+
+    Ok(())
+}
+
+/// Whether a directory entry is an in-flight (or abandoned) `write_atomic` temp file.
+pub fn is_temp_file(name: &str) -> bool {
+    name.ends_with(TEMP_SUFFIX)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(TEMP_SUFFIX);
+    path.with_file_name(name)
+}
+
+#[cfg(unix)]
+fn sync_dir_entries(path: &Path) -> io::Result<()> {
+    std::fs::File::open(path)?.sync_all()
+}
+
+// Directories can't be opened as files on Windows; NTFS journals the rename.
+#[cfg(not(unix))]
+fn sync_dir_entries(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read, read_dir};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file");
+
+        for durability in &[Durability::None, Durability::Flush, Durability::Fsync] {
+            write_atomic(&path, &[1, 2, 3, 4, 5], *durability).expect("Write 1");
+            write_atomic(&path, &[6, 7], *durability).expect("Write 2");
+
+            assert_eq!(read(&path).unwrap(), vec![6, 7]);
+        }
+
+        // only the target remains, no temp files are left behind
+        assert_eq!(read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_write_append() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file");
+
+        write_append(&path, &[1, 2, 3], Durability::Fsync).expect("Append 1");
+        write_append(&path, &[4, 5], Durability::None).expect("Append 2");
+
+        assert_eq!(read(&path).unwrap(), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_is_temp_file() {
+        let path = temp_path(Path::new("Sessions/Session 1"));
+        let name = path.file_name().unwrap().to_str().unwrap();
+
+        assert_eq!(path.parent(), Some(Path::new("Sessions")));
+        assert!(is_temp_file(name));
+        assert!(!is_temp_file("Session 1"));
+    }
+}
@@ -0,0 +1,98 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Everything that can go wrong in a store, split so callers can tell errors
+/// worth retrying (`Io`) from data that has to be dropped or quarantined.
+#[derive(Debug)]
+pub enum StoreError {
+    /// The filesystem failed, e.g. the disk is full.
+    Io(io::Error),
+    /// A record could not be encoded or decoded.
+    Serialization(bincode::Error),
+    /// Stored data is unreadable from `offset` onwards.
+    Corrupt {
+        path: PathBuf,
+        offset: u64,
+        reason: String,
+    },
+    /// A session references a payload that is no longer stored.
+    MissingPayload { payload_id: u64 },
+    /// The session id can't be stored.
+    InvalidSessionId(String),
+    /// Data is in a format version this build can't read.
+    UnsupportedVersion { path: PathBuf, version: u16 },
+    /// The store was opened read-only.
+    ReadOnly,
+    /// An archive being imported is malformed at `line`, counting from 1.
+    InvalidArchive { line: usize, reason: String },
+}
+
+impl StoreError {
+    pub fn corrupt(path: impl Into<PathBuf>, offset: u64, reason: impl fmt::Display) -> Self {
+        StoreError::Corrupt {
+            path: path.into(),
+            offset,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "I/O error: {}", e),
+            StoreError::Serialization(e) => write!(f, "serialization error: {}", e),
+            StoreError::Corrupt {
+                path,
+                offset,
+                reason,
+            } => write!(
+                f,
+                "{} is corrupt at offset {}: {}",
+                path.display(),
+                offset,
+                reason
+            ),
+            StoreError::MissingPayload { payload_id } => {
+                write!(f, "payload {} is missing", payload_id)
+            }
+            StoreError::InvalidSessionId(session_id) => {
+                write!(f, "invalid session id {:?}", session_id)
+            }
+            StoreError::UnsupportedVersion { path, version } => write!(
+                f,
+                "{} is in unsupported format version {}",
+                path.display(),
+                version
+            ),
+            StoreError::ReadOnly => write!(f, "store is open read-only"),
+            StoreError::InvalidArchive { line, reason } => {
+                write!(f, "archive line {}: {}", line, reason)
+            }
+        }
+    }
+}
+
+impl Error for StoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StoreError::Io(e) => Some(e),
+            StoreError::Serialization(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for StoreError {
+    fn from(e: bincode::Error) -> Self {
+        StoreError::Serialization(e)
+    }
+}
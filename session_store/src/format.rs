@@ -0,0 +1,491 @@
+//! Versioning of the on-disk format. Every file a store decodes starts with a
+//! `Header`, and each store root holds a `Format` file with the store's own
+//! format version, so an older store can be upgraded when opened.
+
+use std::fs;
+use std::path::Path;
+
+use crate::names::decode_unescaped_uppercase;
+use crate::{
+    encode_session_id, is_session_id_file, is_temp_file, save_session_id, sync_dir, write_atomic,
+    Durability, StoreError,
+};
+
+/// First bytes of every versioned file.
+pub const MAGIC: [u8; 4] = *b"MQSS";
+
+/// Version of the header and checksum encoding every store's files are
+/// written in by this build. Version 1 added headers, and version 2 checksums.
+/// Logs have moved on to `log::LOG_VERSION`. Each store has its own format
+/// version on top, for its layout.
+pub const HEADER_VERSION: u16 = 2;
+
+pub const HEADER_LEN: usize = 8;
+
+/// Name of the file in a store root recording the store's format version.
+const FORMAT_FILE: &str = "Format";
+
+/// How the contents of a file, or each record of a log, are checksummed. The
+/// checksum follows the data it covers.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ChecksumAlgorithm {
+    /// Only found in version 1 files.
+    None,
+    /// Little endian CRC32C, which has hardware support on most CPUs.
+    #[default]
+    Crc32c,
+}
+
+impl ChecksumAlgorithm {
+    fn id(self) -> u8 {
+        match self {
+            ChecksumAlgorithm::None => 0,
+            ChecksumAlgorithm::Crc32c => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(ChecksumAlgorithm::None),
+            1 => Some(ChecksumAlgorithm::Crc32c),
+            _ => None,
+        }
+    }
+
+    /// Appends `data` followed by its checksum to `buffer`.
+    pub fn seal(self, data: &[u8], buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(data);
+        match self {
+            ChecksumAlgorithm::None => {}
+            ChecksumAlgorithm::Crc32c => {
+                buffer.extend_from_slice(&crc32c::crc32c(data).to_le_bytes())
+            }
+        }
+    }
+
+    /// Checks the checksum at the end of `sealed`, returning the data it covers.
+    pub fn open(self, sealed: &[u8]) -> Result<&[u8], String> {
+        match self {
+            ChecksumAlgorithm::None => Ok(sealed),
+            ChecksumAlgorithm::Crc32c => {
+                if sealed.len() < 4 {
+                    return Err("too short for a checksum".to_owned());
+                }
+
+                let (data, checksum) = sealed.split_at(sealed.len() - 4);
+                let expected =
+                    u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+                let actual = crc32c::crc32c(data);
+                if actual == expected {
+                    Ok(data)
+                } else {
+                    Err(format!(
+                        "checksum mismatch, expected {:08x} but found {:08x}",
+                        expected, actual
+                    ))
+                }
+            }
+        }
+    }
+}
+
+/// Magic, little endian `u16` format version, checksum algorithm id and a
+/// reserved zero byte.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub checksum: ChecksumAlgorithm,
+}
+
+impl Header {
+    pub fn current() -> Self {
+        Header {
+            version: HEADER_VERSION,
+            checksum: ChecksumAlgorithm::default(),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..4].copy_from_slice(&MAGIC);
+        bytes[4..6].copy_from_slice(&self.version.to_le_bytes());
+        bytes[6] = self.checksum.id();
+        bytes
+    }
+
+    /// Decodes the header at the start of the contents of the file at `path`.
+    pub fn decode(path: &Path, bytes: &[u8]) -> Result<Self, StoreError> {
+        if bytes.len() < HEADER_LEN || bytes[..4] != MAGIC {
+            return Err(StoreError::corrupt(path, 0, "missing file header"));
+        }
+
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        let checksum = ChecksumAlgorithm::from_id(bytes[6]).ok_or_else(|| {
+            StoreError::corrupt(path, 6, format!("unknown checksum algorithm {}", bytes[6]))
+        })?;
+
+        Ok(Header { version, checksum })
+    }
+
+    /// Whether `bytes` starts with a header, of any version.
+    pub fn is_present(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+}
+
+/// Contents of a whole-file record: the current header, then `data` and its
+/// checksum.
+pub fn seal(data: &[u8]) -> Vec<u8> {
+    let header = Header::current();
+    let mut bytes = header.encode().to_vec();
+    header.checksum.seal(data, &mut bytes);
+    bytes
+}
+
+/// Reverses `seal`, checking the header version and the checksum.
+pub fn unseal<'a>(path: &Path, bytes: &'a [u8]) -> Result<&'a [u8], StoreError> {
+    let (header, sealed) = strip_header(path, bytes)?;
+    header
+        .checksum
+        .open(sealed)
+        .map_err(|reason| StoreError::corrupt(path, HEADER_LEN as u64, reason))
+}
+
+/// Checks the header of the contents of the file at `path` is for this build's
+/// version, returning the rest of the contents.
+pub fn strip_header<'a>(path: &Path, bytes: &'a [u8]) -> Result<(Header, &'a [u8]), StoreError> {
+    let header = Header::decode(path, bytes)?;
+    if header.version != HEADER_VERSION {
+        return Err(StoreError::UnsupportedVersion {
+            path: path.to_owned(),
+            version: header.version,
+        });
+    }
+
+    Ok((header, &bytes[HEADER_LEN..]))
+}
+
+/// Upgrades the store at `root` from one version to the next. Must be safe to
+/// rerun if interrupted, since the `Format` file is only updated afterwards.
+pub type Migration = fn(root: &Path, durability: Durability) -> Result<(), StoreError>;
+
+/// Brings the store at `root` up to its `current` format version, running
+/// `migrations[n]` to go from version `n` to `n + 1`. A root without a
+/// `Format` file is a new store if it is empty, and a version 0 store
+/// otherwise. Panics unless there is a migration for every older version.
+pub fn migrate(
+    root: &Path,
+    durability: Durability,
+    current: u16,
+    migrations: &[Migration],
+) -> Result<(), StoreError> {
+    assert_eq!(
+        migrations.len(),
+        current as usize,
+        "migrations given for format version {}",
+        current
+    );
+
+    let path = root.join(FORMAT_FILE);
+    let mut version = version(root, current)?;
+
+    if version > current {
+        return Err(StoreError::UnsupportedVersion { path, version });
+    }
+
+    while version < current {
+        migrations[version as usize](root, durability)?;
+        version += 1;
+        write_format(&path, version, durability)?;
+    }
+    if !path.exists() {
+        write_format(&path, version, durability)?;
+    }
+
+    Ok(())
+}
+
+/// Format version of the store at `root`, without upgrading it. An empty
+/// root is taken to be a new store in the `current` version.
+pub fn version(root: &Path, current: u16) -> Result<u16, StoreError> {
+    let path = root.join(FORMAT_FILE);
+    if path.exists() {
+        Ok(Header::decode(&path, &fs::read(&path)?)?.version)
+    } else if is_empty(root)? {
+        Ok(current)
+    } else {
+        Ok(0)
+    }
+}
+
+/// Fails unless the store at `root` is in its `current` format version, for
+/// opening it without upgrading it.
+pub fn check_version(root: &Path, current: u16) -> Result<(), StoreError> {
+    let version = version(root, current)?;
+    if version != current {
+        return Err(StoreError::UnsupportedVersion {
+            path: root.join(FORMAT_FILE),
+            version,
+        });
+    }
+
+    Ok(())
+}
+
+/// Version 0 to 1 step: prefixes the file at `path` with a version 1 header,
+/// unless it already has one.
+pub fn add_v1_header(path: &Path, durability: Durability) -> Result<(), StoreError> {
+    let bytes = fs::read(path)?;
+    if !Header::is_present(&bytes) {
+        let header = Header {
+            version: 1,
+            checksum: ChecksumAlgorithm::None,
+        };
+        let mut upgraded = header.encode().to_vec();
+        upgraded.extend_from_slice(&bytes);
+        write_atomic(path, &upgraded, durability)?;
+    }
+
+    Ok(())
+}
+
+/// Version 1 to 2 step: rewrites a whole-file record with a version 1 header
+/// as a sealed one.
+pub fn reseal_v1(path: &Path, durability: Durability) -> Result<(), StoreError> {
+    let bytes = fs::read(path)?;
+    if Header::decode(path, &bytes)?.version == 1 {
+        write_atomic(path, &seal(&bytes[HEADER_LEN..]), durability)?;
+    }
+
+    Ok(())
+}
+
+/// Version 1 to 2 step: seals a file that held raw data with no header.
+pub fn seal_raw_v1(path: &Path, durability: Durability) -> Result<(), StoreError> {
+    let bytes = fs::read(path)?;
+    // Raw data could start with a header by chance, but not a valid checksum too
+    if unseal(path, &bytes).is_err() {
+        write_atomic(path, &seal(&bytes), durability)?;
+    }
+
+    Ok(())
+}
+
+/// Renames every entry of the directory `dir` from a raw session id, the
+/// version 0 layout, to its encoded form, after `upgrade` has rewritten the
+/// entry in place. Entries are moved into a sibling directory that replaces
+/// `dir` once done, so a rerun can tell which were already renamed.
+pub fn encode_session_names<F>(
+    dir: &Path,
+    durability: Durability,
+    upgrade: F,
+) -> Result<(), StoreError>
+where
+    F: Fn(&Path) -> Result<(), StoreError>,
+{
+    let mut staging = dir.as_os_str().to_owned();
+    staging.push("-v1");
+    let staging = Path::new(&staging);
+
+    if dir.exists() && !staging.exists() {
+        fs::create_dir(staging)?;
+    }
+    if staging.exists() && dir.exists() {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if is_temp_file(&name) {
+                continue;
+            }
+
+            upgrade(&entry.path())?;
+            save_session_id(staging, &name, durability)?;
+            fs::rename(entry.path(), staging.join(encode_session_id(&name)?))?;
+        }
+        sync_dir(staging, durability)?;
+        // Only abandoned temp files are left
+        fs::remove_dir_all(dir)?;
+    }
+    if staging.exists() {
+        fs::rename(staging, dir)?;
+        if let Some(parent) = dir.parent() {
+            sync_dir(parent, durability)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Renames every entry of the directory `dir` named by the encoding from
+/// before uppercase letters were escaped to its current encoding, saving the
+/// id of one that is now hashed. Each entry is renamed by itself, so a rerun
+/// skips the ones already done.
+pub fn escape_uppercase_names(dir: &Path, durability: Durability) -> Result<(), StoreError> {
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    let mut renamed = false;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if is_temp_file(&name) || is_session_id_file(&name) {
+            continue;
+        }
+
+        // Entries that can't be decoded are left for the store to report
+        let session_id = match decode_unescaped_uppercase(&name) {
+            Some(session_id) => session_id,
+            None => continue,
+        };
+        let encoded = encode_session_id(&session_id)?;
+        if encoded != name {
+            save_session_id(dir, &session_id, durability)?;
+            fs::rename(entry.path(), dir.join(encoded))?;
+            renamed = true;
+        }
+    }
+    if renamed {
+        sync_dir(dir, durability)?;
+    }
+
+    Ok(())
+}
+
+fn write_format(path: &Path, version: u16, durability: Durability) -> Result<(), StoreError> {
+    let header = Header {
+        version,
+        ..Header::current()
+    };
+    write_atomic(path, &header.encode(), durability)?;
+
+    Ok(())
+}
+
+fn is_empty(root: &Path) -> Result<bool, StoreError> {
+    for entry in fs::read_dir(root)? {
+        if !is_temp_file(&entry?.file_name().to_string_lossy()) {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_header_round_trip() {
+        let bytes = Header::current().encode();
+        assert_eq!(bytes, [b'M', b'Q', b'S', b'S', 2, 0, 1, 0]);
+        assert!(Header::is_present(&bytes));
+
+        let path = Path::new("file");
+        assert_eq!(Header::decode(path, &bytes).unwrap(), Header::current());
+        assert!(Header::decode(path, &bytes[..7]).is_err());
+        assert!(Header::decode(path, b"MQSX\x02\0\x01\0").is_err());
+        assert!(Header::decode(path, b"MQSS\x02\0\x7f\0").is_err());
+
+        assert!(matches!(
+            strip_header(path, b"MQSS\x03\0\x01\0rest"),
+            Err(StoreError::UnsupportedVersion { version: 3, .. })
+        ));
+        let (_, rest) = strip_header(path, b"MQSS\x02\0\x01\0rest").unwrap();
+        assert_eq!(rest, b"rest");
+    }
+
+    #[test]
+    fn test_seal() {
+        let path = Path::new("file");
+        let mut bytes = seal(b"data");
+        assert_eq!(bytes.len(), HEADER_LEN + 4 + 4);
+        assert_eq!(unseal(path, &bytes).unwrap(), b"data");
+
+        for i in HEADER_LEN..bytes.len() {
+            bytes[i] ^= 1;
+            assert!(
+                matches!(unseal(path, &bytes), Err(StoreError::Corrupt { offset, .. }) if offset == HEADER_LEN as u64),
+                "flipped byte {}",
+                i
+            );
+            bytes[i] ^= 1;
+        }
+    }
+
+    #[test]
+    fn test_escape_uppercase_names() {
+        let dir = tempdir().unwrap();
+        let long = format!("S{}", "s".repeat(254));
+        for name in ["Session%201", "abc", "x.tmp", long.as_str()] {
+            fs::write(dir.path().join(name), name).unwrap();
+        }
+
+        for _ in 0..2 {
+            escape_uppercase_names(dir.path(), Durability::None).unwrap();
+            let mut session_ids = crate::list_session_ids(dir.path()).unwrap();
+            session_ids.sort();
+            assert_eq!(
+                session_ids,
+                vec!["Session 1".to_owned(), long.clone(), "abc".to_owned()]
+            );
+        }
+        assert_eq!(
+            fs::read(dir.path().join("%53ession%201")).unwrap(),
+            b"Session%201"
+        );
+        assert!(dir.path().join("x.tmp").exists());
+    }
+
+    fn mark(root: &Path, _durability: Durability) -> Result<(), StoreError> {
+        fs::write(root.join("migrated"), b"")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let migrations: &[Migration] = &[mark, mark, mark];
+        let current = Header {
+            version: 3,
+            ..Header::current()
+        };
+
+        // A new store starts out current
+        migrate(root, Durability::None, 3, migrations).unwrap();
+        assert!(!root.join("migrated").exists());
+        assert_eq!(fs::read(root.join(FORMAT_FILE)).unwrap(), current.encode());
+        assert_eq!(version(root, 3).unwrap(), 3);
+
+        // Data without a format file is version 0
+        fs::remove_file(root.join(FORMAT_FILE)).unwrap();
+        fs::write(root.join("data"), b"").unwrap();
+        assert_eq!(version(root, 3).unwrap(), 0);
+        assert!(matches!(
+            check_version(root, 3),
+            Err(StoreError::UnsupportedVersion { version: 0, .. })
+        ));
+        migrate(root, Durability::None, 3, migrations).unwrap();
+        assert!(root.join("migrated").exists());
+        assert_eq!(fs::read(root.join(FORMAT_FILE)).unwrap(), current.encode());
+        check_version(root, 3).unwrap();
+
+        // Newer than this build
+        assert!(matches!(
+            migrate(root, Durability::None, 2, &[mark, mark]),
+            Err(StoreError::UnsupportedVersion { version: 3, .. })
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "migrations given for format version 4")]
+    fn test_migrate_needs_every_version() {
+        let dir = tempdir().unwrap();
+        let _ = migrate(dir.path(), Durability::None, 4, &[mark, mark, mark]);
+    }
+}
@@ -0,0 +1,115 @@
+use serde_derive::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+pub mod archive;
+#[cfg(feature = "tokio")]
+mod async_store;
+mod config;
+mod durability;
+mod error;
+pub mod format;
+pub mod log;
+#[cfg(test)]
+mod memory;
+mod names;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod wal;
+
+#[cfg(feature = "tokio")]
+pub use async_store::AsyncStore;
+pub use config::{CorruptionPolicy, StoreConfig};
+pub use durability::{is_temp_file, sync_dir, write_append, write_atomic, Durability};
+pub use error::StoreError;
+pub use names::{
+    decode_session_id, encode_session_id, is_session_id_file, list_session_ids, read_session_id,
+    remove_session_id, save_session_id,
+};
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Publish {
+    pub packet_id: u16,
+    pub retain: bool,
+    pub topic_name: String,
+    pub payload: Payload,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Payload {
+    pub bytes: Arc<Vec<u8>>,
+    pub id: u64,
+}
+
+/// Common interface over the persistence strategies, so callers can swap
+/// between them without changing call sites. Stores are shared between threads,
+/// with different sessions read and written in parallel.
+pub trait SessionStore: Sized + Send + Sync {
+    /// Opens (or creates) a store rooted at `location`.
+    fn open(location: &Path) -> Result<Self, StoreError>;
+
+    /// Appends a single publish to the end of a session's queue.
+    fn append(&self, session_id: &str, publish: Publish) -> Result<(), StoreError>;
+
+    /// Appends several publishes, possibly to different sessions, in order.
+    /// Stores override this to do it in one amortized write, storing a
+    /// payload shared between publishes once; by default they are appended
+    /// one at a time.
+    fn append_batch(&self, writes: &[(&str, Publish)]) -> Result<(), StoreError> {
+        for (session_id, publish) in writes {
+            self.append(session_id, publish.clone())?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends `publish` to each of `session_ids`, e.g. when it matches many
+    /// subscriptions, as one batch.
+    fn append_fanout(&self, publish: Publish, session_ids: &[&str]) -> Result<(), StoreError> {
+        let writes: Vec<(&str, Publish)> = session_ids
+            .iter()
+            .map(|session_id| (*session_id, publish.clone()))
+            .collect();
+        self.append_batch(&writes)
+    }
+
+    /// Reads every publish queued for a session. Unknown sessions are empty.
+    fn read(&self, session_id: &str) -> Result<Vec<Publish>, StoreError>;
+
+    /// Removes the oldest queued publish with the given packet id, e.g. once
+    /// its delivery has been acknowledged. Unknown packet ids are ignored.
+    fn ack(&self, session_id: &str, packet_id: u16) -> Result<(), StoreError>;
+
+    /// Removes a session and all of its queued publishes.
+    fn remove_session(&self, session_id: &str) -> Result<(), StoreError>;
+
+    /// Lists the ids of all sessions with persisted state.
+    fn list_sessions(&self) -> Result<Vec<String>, StoreError>;
+
+    /// Releases storage no longer referenced by any session.
+    fn clean(&self) -> Result<(), StoreError>;
+}
+
+/// A stored payload, as reported to inspection tools.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PayloadInfo {
+    /// How the store names the payload, e.g. its id.
+    pub key: String,
+    /// Space the payload takes up on disk, including any framing.
+    pub stored_bytes: u64,
+    /// Number of queued messages referencing the payload.
+    pub refcount: u64,
+}
+
+/// Read-only views into a store's layout, for debugging and tooling rather
+/// than normal use.
+pub trait Inspect: SessionStore {
+    /// Lists every stored payload, including ones no longer referenced.
+    fn payloads(&self) -> Result<Vec<PayloadInfo>, StoreError>;
+}
+
+/// Locks a mutex, carrying on past a panic in another thread. Store state behind
+/// locks is only ever a cache of what is on disk.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
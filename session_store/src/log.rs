@@ -0,0 +1,499 @@
+//! Append-only logs of length prefixed records. A log starts with a format
+//! `Header` of version `LOG_VERSION`, then each record is stored as a frame:
+//! a little endian `u32` length and its checksum, then the bincode encoded
+//! record and its checksum.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, metadata, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use crate::format::{ChecksumAlgorithm, Header, HEADER_LEN};
+use crate::{write_append, write_atomic, Durability, StoreError};
+
+/// Header version of logs written by this build. Logs before version 3 had
+/// no checksum on the length of a record, so a damaged one could hide every
+/// record after it.
+pub const LOG_VERSION: u16 = 3;
+
+/// Length of a frame's length and its checksum.
+const FRAME_HEADER_LEN: usize = 8;
+
+/// A record found by `scan_log`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogEntry<T> {
+    pub record: T,
+    /// Where the record starts in the log file.
+    pub offset: u64,
+    /// Length of the encoded record.
+    pub len: usize,
+}
+
+/// Everything `scan_log` read from a log.
+#[derive(Debug)]
+pub struct ScannedLog<T> {
+    pub entries: Vec<LogEntry<T>>,
+    /// A `Corrupt` error for each record that failed a checksum or didn't
+    /// decode, in log order. Records after one with a damaged length are
+    /// still found.
+    pub corrupt: Vec<StoreError>,
+    /// Where a record partially written by a crash starts, if the log ends
+    /// with one.
+    pub torn: Option<u64>,
+}
+
+impl<T> ScannedLog<T> {
+    /// Every record with its encoded length, or the first `Corrupt` error.
+    pub fn into_records(self) -> Result<Vec<(T, usize)>, StoreError> {
+        if let Some(error) = self.corrupt.into_iter().next() {
+            return Err(error);
+        }
+
+        Ok(self
+            .entries
+            .into_iter()
+            .map(|entry| (entry.record, entry.len))
+            .collect())
+    }
+}
+
+/// Reads every record in a log file along with its encoded length,
+/// truncating any partially written record left at the end by a crash. A
+/// record that fails its checksum or doesn't decode is reported as `Corrupt`.
+pub fn read_log<T>(path: &Path) -> Result<Vec<(T, usize)>, StoreError>
+where
+    T: DeserializeOwned,
+{
+    scan_log(path)?.into_records()
+}
+
+/// Like `read_log`, but skips over corrupt records, reporting them alongside
+/// the ones that could be read. Only a record whose length checks out but
+/// runs past the end of the file is taken to be torn and cut off; any other
+/// damage is left in place.
+pub fn scan_log<T>(path: &Path) -> Result<ScannedLog<T>, StoreError>
+where
+    T: DeserializeOwned,
+{
+    let scanned = peek_log(path)?;
+    if let Some(torn) = scanned.torn {
+        OpenOptions::new().write(true).open(path)?.set_len(torn)?;
+    }
+
+    Ok(scanned)
+}
+
+/// Like `scan_log`, but never writes to the log, for checking a store
+/// without changing it. A torn record is only reported in `torn`.
+pub fn peek_log<T>(path: &Path) -> Result<ScannedLog<T>, StoreError>
+where
+    T: DeserializeOwned,
+{
+    let mut scanned = ScannedLog {
+        entries: Vec::new(),
+        corrupt: Vec::new(),
+        torn: None,
+    };
+    if !path.exists() {
+        return Ok(scanned);
+    }
+
+    let mut buffer = Vec::new();
+    File::open(path)?.read_to_end(&mut buffer)?;
+
+    // Torn while the log was being created
+    if buffer.len() < HEADER_LEN && log_header().encode().starts_with(&buffer) {
+        if !buffer.is_empty() {
+            scanned.torn = Some(0);
+        }
+        return Ok(scanned);
+    }
+    let header = Header::decode(path, &buffer)?;
+    if header.version != LOG_VERSION {
+        return Err(StoreError::UnsupportedVersion {
+            path: path.to_owned(),
+            version: header.version,
+        });
+    }
+
+    let mut offset = HEADER_LEN;
+    while offset < buffer.len() {
+        let rest = &buffer[offset..];
+        match frame(rest) {
+            Frame::Complete(sealed) => {
+                let len = FRAME_HEADER_LEN + sealed.len();
+                match decode_sealed(path, offset, sealed, header.checksum) {
+                    Ok(record) => scanned.entries.push(LogEntry {
+                        record,
+                        offset: offset as u64,
+                        len,
+                    }),
+                    Err(e) => scanned.corrupt.push(e),
+                }
+                offset += len;
+            }
+            Frame::Torn => {
+                scanned.torn = Some(offset as u64);
+                break;
+            }
+            // Zeros are never a valid frame, but a crash can leave them
+            Frame::Damaged if rest.iter().all(|byte| *byte == 0) => {
+                scanned.torn = Some(offset as u64);
+                break;
+            }
+            Frame::Damaged => {
+                scanned.corrupt.push(StoreError::corrupt(
+                    path,
+                    offset as u64,
+                    "record length fails its checksum",
+                ));
+                offset = resync(&buffer, offset + 1, header.checksum);
+            }
+        }
+    }
+
+    Ok(scanned)
+}
+
+/// A buffer holding just the header of a new log, for records to be encoded
+/// into before the whole log is written out.
+pub fn new_log() -> Vec<u8> {
+    log_header().encode().to_vec()
+}
+
+/// Appends encoded records to the log at `path`, starting it with a header if
+/// it is new. Returns the offset the records were written at.
+pub fn append_log(path: &Path, records: &[u8], durability: Durability) -> io::Result<u64> {
+    let len = match metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+
+    if len == 0 {
+        let mut bytes = new_log();
+        bytes.extend_from_slice(records);
+        write_append(path, &bytes, durability)?;
+        Ok(HEADER_LEN as u64)
+    } else {
+        write_append(path, records, durability)?;
+        Ok(len)
+    }
+}
+
+/// Version 1 to 2 step: rewrites a log with a version 1 header with a
+/// version 2 header and a checksum on every record. Records aren't decoded,
+/// so this works for logs of any record type.
+pub fn upgrade_log_v1(path: &Path, durability: Durability) -> Result<(), StoreError> {
+    let buffer = fs::read(path)?;
+    if buffer.is_empty() || Header::decode(path, &buffer)?.version != 1 {
+        return Ok(());
+    }
+
+    let header = Header {
+        version: 2,
+        ..Header::current()
+    };
+    let mut upgraded = header.encode().to_vec();
+    let mut offset = HEADER_LEN;
+    while let Some(data) = unchecked_frame(&buffer[offset..]) {
+        let mut sealed = Vec::new();
+        header.checksum.seal(data, &mut sealed);
+        upgraded.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        upgraded.extend_from_slice(&sealed);
+        offset += 4 + data.len();
+    }
+    write_atomic(path, &upgraded, durability)?;
+
+    Ok(())
+}
+
+/// Version 2 to 3 step: rewrites a log with a version 2 header with the
+/// current header and a checksum on the length of every record. A record
+/// whose length was damaged ends the log, as it did when reading version 2.
+pub fn upgrade_log_v2(path: &Path, durability: Durability) -> Result<(), StoreError> {
+    let buffer = fs::read(path)?;
+    if buffer.is_empty() || Header::decode(path, &buffer)?.version != 2 {
+        return Ok(());
+    }
+
+    let mut upgraded = new_log();
+    let mut offset = HEADER_LEN;
+    while let Some(sealed) = unchecked_frame(&buffer[offset..]) {
+        push_frame(sealed, &mut upgraded);
+        offset += 4 + sealed.len();
+    }
+    write_atomic(path, &upgraded, durability)?;
+
+    Ok(())
+}
+
+/// Encodes a record, with the current checksum algorithm, onto the end of
+/// `buffer`.
+pub fn encode_record<T>(record: &T, buffer: &mut Vec<u8>) -> Result<(), StoreError>
+where
+    T: Serialize,
+{
+    let bytes = bincode::serialize(record)?;
+    let mut sealed = Vec::with_capacity(bytes.len() + 4);
+    Header::current().checksum.seal(&bytes, &mut sealed);
+    push_frame(&sealed, buffer);
+
+    Ok(())
+}
+
+/// Decodes the record at the start of `buffer`, which was read from `offset`
+/// in the current format log at `path`. Returns it with the number of bytes
+/// consumed, or `None` if no complete record is left.
+pub fn decode_record<T>(
+    path: &Path,
+    offset: u64,
+    buffer: &[u8],
+) -> Result<Option<(T, usize)>, StoreError>
+where
+    T: DeserializeOwned,
+{
+    match frame(buffer) {
+        Frame::Complete(sealed) => {
+            let record = decode_sealed(path, offset as usize, sealed, Header::current().checksum)?;
+            Ok(Some((record, FRAME_HEADER_LEN + sealed.len())))
+        }
+        Frame::Torn => Ok(None),
+        Frame::Damaged => Err(StoreError::corrupt(
+            path,
+            offset,
+            "record length fails its checksum",
+        )),
+    }
+}
+
+fn log_header() -> Header {
+    Header {
+        version: LOG_VERSION,
+        ..Header::current()
+    }
+}
+
+fn push_frame(sealed: &[u8], buffer: &mut Vec<u8>) {
+    let len = (sealed.len() as u32).to_le_bytes();
+    Header::current().checksum.seal(&len, buffer);
+    buffer.extend_from_slice(sealed);
+}
+
+/// What the start of a buffer holds.
+enum Frame<'a> {
+    /// The contents of a whole frame.
+    Complete(&'a [u8]),
+    /// Too little for a frame, or a frame running past the end.
+    Torn,
+    /// A length that fails its checksum.
+    Damaged,
+}
+
+fn frame(buffer: &[u8]) -> Frame<'_> {
+    if buffer.len() < FRAME_HEADER_LEN {
+        return Frame::Torn;
+    }
+
+    let len = match Header::current().checksum.open(&buffer[..FRAME_HEADER_LEN]) {
+        Ok(len) => u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize,
+        Err(_) => return Frame::Damaged,
+    };
+    match buffer.get(FRAME_HEADER_LEN..FRAME_HEADER_LEN + len) {
+        Some(sealed) => Frame::Complete(sealed),
+        None => Frame::Torn,
+    }
+}
+
+/// Offset of the first frame from `offset` on whose length and contents both
+/// check out, or the end of `buffer` if there is none.
+fn resync(buffer: &[u8], offset: usize, checksum: ChecksumAlgorithm) -> usize {
+    (offset..buffer.len())
+        .find(|at| match frame(&buffer[*at..]) {
+            Frame::Complete(sealed) => checksum.open(sealed).is_ok(),
+            _ => false,
+        })
+        .unwrap_or(buffer.len())
+}
+
+/// The contents of a frame before version 3, with just a length prefix, at
+/// the start of `buffer`, if it is complete.
+fn unchecked_frame(buffer: &[u8]) -> Option<&[u8]> {
+    if buffer.len() < 4 {
+        return None;
+    }
+
+    let len = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+    buffer.get(4..4 + len)
+}
+
+fn decode_sealed<T>(
+    path: &Path,
+    offset: usize,
+    sealed: &[u8],
+    checksum: ChecksumAlgorithm,
+) -> Result<T, StoreError>
+where
+    T: DeserializeOwned,
+{
+    let data = checksum
+        .open(sealed)
+        .map_err(|reason| StoreError::corrupt(path, offset as u64, reason))?;
+
+    bincode::deserialize(data).map_err(|e| StoreError::corrupt(path, offset as u64, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// A log of `count` records, with the length of each record's frame.
+    fn log_of(count: u64) -> (Vec<u8>, usize) {
+        let mut bytes = new_log();
+        for record in 0..count {
+            encode_record(&record, &mut bytes).unwrap();
+        }
+        let record_len = (bytes.len() - HEADER_LEN) / count as usize;
+        (bytes, record_len)
+    }
+
+    fn records(scanned: &ScannedLog<u64>) -> Vec<u64> {
+        scanned.entries.iter().map(|e| e.record).collect()
+    }
+
+    #[test]
+    fn test_scan_log_skips_corrupt_records() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("log");
+
+        let (mut bytes, record_len) = log_of(3);
+        // flip a bit inside the second record
+        bytes[HEADER_LEN + record_len + FRAME_HEADER_LEN] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+
+        let scanned = scan_log::<u64>(&path).unwrap();
+        assert_eq!(records(&scanned), vec![0, 2]);
+        assert_eq!(
+            scanned.entries[1].offset,
+            (HEADER_LEN + 2 * record_len) as u64
+        );
+        assert_eq!(scanned.corrupt.len(), 1);
+        assert!(matches!(
+            &scanned.corrupt[0],
+            StoreError::Corrupt { offset, .. } if *offset == (HEADER_LEN + record_len) as u64
+        ));
+
+        assert!(matches!(
+            read_log::<u64>(&path),
+            Err(StoreError::Corrupt { .. })
+        ));
+    }
+
+    #[test]
+    fn test_damaged_length_keeps_later_records() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("log");
+
+        let (mut bytes, record_len) = log_of(5);
+        // flip a bit of the third record's length
+        bytes[HEADER_LEN + 2 * record_len + 1] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+
+        let scanned = scan_log::<u64>(&path).unwrap();
+        assert_eq!(records(&scanned), vec![0, 1, 3, 4]);
+        assert_eq!(scanned.torn, None);
+        assert!(matches!(
+            &scanned.corrupt[..],
+            [StoreError::Corrupt { offset, .. }] if *offset == (HEADER_LEN + 2 * record_len) as u64
+        ));
+        // Nothing was cut off
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+
+        // Damage at the very end is kept too
+        let end = bytes.len() - record_len;
+        bytes[end + 1] ^= 1;
+        bytes[HEADER_LEN + 2 * record_len + 1] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        let scanned = scan_log::<u64>(&path).unwrap();
+        assert_eq!(records(&scanned), vec![0, 1, 2, 3]);
+        assert_eq!(scanned.corrupt.len(), 1);
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_torn_tail() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("log");
+        let (bytes, record_len) = log_of(3);
+        let whole = bytes.len() - record_len;
+
+        // Cut inside the length, inside the record, and zeros left by a crash
+        let mut zeroed = bytes[..whole].to_vec();
+        zeroed.resize(bytes.len(), 0);
+        for torn in [
+            bytes[..whole + 3].to_vec(),
+            bytes[..bytes.len() - 1].to_vec(),
+            zeroed,
+        ] {
+            fs::write(&path, &torn).unwrap();
+
+            let peeked = peek_log::<u64>(&path).unwrap();
+            assert_eq!(records(&peeked), vec![0, 1]);
+            assert_eq!(peeked.torn, Some(whole as u64));
+            assert_eq!(fs::read(&path).unwrap(), torn);
+
+            let scanned = scan_log::<u64>(&path).unwrap();
+            assert_eq!(records(&scanned), vec![0, 1]);
+            assert!(scanned.corrupt.is_empty());
+            assert_eq!(fs::read(&path).unwrap(), &bytes[..whole]);
+        }
+
+        // Torn inside the header
+        fs::write(&path, &bytes[..3]).unwrap();
+        assert_eq!(peek_log::<u64>(&path).unwrap().torn, Some(0));
+        assert!(scan_log::<u64>(&path).unwrap().entries.is_empty());
+        assert_eq!(fs::read(&path).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_upgrade_log() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("log");
+
+        let mut v1 = b"MQSS\x01\0\0\0".to_vec();
+        for record in 0..3u64 {
+            let data = bincode::serialize(&record).unwrap();
+            v1.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            v1.extend_from_slice(&data);
+        }
+        fs::write(&path, &v1).unwrap();
+
+        upgrade_log_v1(&path, Durability::None).unwrap();
+        assert_eq!(
+            Header::decode(&path, &fs::read(&path).unwrap())
+                .unwrap()
+                .version,
+            2
+        );
+        assert!(matches!(
+            read_log::<u64>(&path),
+            Err(StoreError::UnsupportedVersion { version: 2, .. })
+        ));
+        upgrade_log_v2(&path, Durability::None).unwrap();
+        let records: Vec<u64> = read_log(&path)
+            .unwrap()
+            .into_iter()
+            .map(|(r, _)| r)
+            .collect();
+        assert_eq!(records, vec![0, 1, 2]);
+        assert_eq!(fs::read(&path).unwrap(), log_of(3).0);
+
+        // already upgraded
+        upgrade_log_v1(&path, Durability::None).unwrap();
+        upgrade_log_v2(&path, Durability::None).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), log_of(3).0);
+    }
+}
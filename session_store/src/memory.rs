@@ -0,0 +1,56 @@
+//! A store kept in memory, for testing code that is generic over stores.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::{lock, Publish, SessionStore, StoreError};
+
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, Vec<Publish>>>,
+}
+
+impl SessionStore for MemoryStore {
+    fn open(_location: &Path) -> Result<Self, StoreError> {
+        Ok(MemoryStore::default())
+    }
+
+    fn append(&self, session_id: &str, publish: Publish) -> Result<(), StoreError> {
+        lock(&self.sessions)
+            .entry(session_id.to_owned())
+            .or_default()
+            .push(publish);
+        Ok(())
+    }
+
+    fn read(&self, session_id: &str) -> Result<Vec<Publish>, StoreError> {
+        Ok(lock(&self.sessions)
+            .get(session_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn ack(&self, session_id: &str, packet_id: u16) -> Result<(), StoreError> {
+        if let Some(publishes) = lock(&self.sessions).get_mut(session_id) {
+            if let Some(i) = publishes.iter().position(|p| p.packet_id == packet_id) {
+                publishes.remove(i);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_session(&self, session_id: &str) -> Result<(), StoreError> {
+        lock(&self.sessions).remove(session_id);
+        Ok(())
+    }
+
+    fn list_sessions(&self) -> Result<Vec<String>, StoreError> {
+        Ok(lock(&self.sessions).keys().cloned().collect())
+    }
+
+    /// Nothing is left behind to clean up.
+    fn clean(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+//! Maps session ids to file names. Any id, e.g. an MQTT client id, is
+//! percent-encoded so it can't escape the store directory, clash with a temp
+//! file, or differ from another id only by bytes the filesystem mangles, such
+//! as the case of letters.
+
+use std::fs;
+use std::path::Path;
+
+use crate::format::{seal, unseal};
+use crate::{is_temp_file, write_atomic, Durability, StoreError};
+
+/// Longest file name most filesystems accept.
+const MAX_NAME_LEN: usize = 255;
+
+/// Starts the name of an id too long to encode, which no encoding does.
+const HASHED_PREFIX: char = '~';
+
+/// Ends the name of the file recording the id behind a hashed name.
+const SIDECAR_SUFFIX: &str = ".id";
+
+/// Encodes a session id as a file name. Only lowercase ASCII letters, digits,
+/// `-` and `_` are kept as they are; every other byte becomes `%XX`. An id
+/// whose encoding is too long for a file name is named by its hash instead,
+/// and needs `save_session_id` before an entry with the name is created.
+pub fn encode_session_id(session_id: &str) -> Result<String, StoreError> {
+    if session_id.is_empty() {
+        return Err(StoreError::InvalidSessionId(session_id.to_owned()));
+    }
+
+    let mut name = String::with_capacity(session_id.len());
+    for byte in session_id.bytes() {
+        if is_kept(byte) {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    if name.len() > MAX_NAME_LEN {
+        let hash = blake3::hash(session_id.as_bytes());
+        name = format!("{}{}", HASHED_PREFIX, &hash.to_hex()[..32]);
+    }
+
+    Ok(name)
+}
+
+/// Reverses `encode_session_id`, returning `None` for names it can't have made.
+/// Hashed names can only be reversed by `read_session_id`.
+pub fn decode_session_id(name: &str) -> Option<String> {
+    let session_id = unescape(name, is_kept)?;
+    // Only the canonical encoding maps back, so each id has one file name
+    if encode_session_id(&session_id).ok()? == name {
+        Some(session_id)
+    } else {
+        None
+    }
+}
+
+/// Decodes a name made before uppercase letters were escaped, which kept them
+/// as they are, for upgrading a store.
+pub(crate) fn decode_unescaped_uppercase(name: &str) -> Option<String> {
+    unescape(name, |byte| is_kept(byte) || byte.is_ascii_uppercase())
+}
+
+/// Records the id behind a hashed name in a file next to the entry in `dir`,
+/// creating `dir` if needed. Call before creating the entry, so every hashed
+/// entry has its id. Does nothing for other ids.
+pub fn save_session_id(
+    dir: &Path,
+    session_id: &str,
+    durability: Durability,
+) -> Result<(), StoreError> {
+    let name = encode_session_id(session_id)?;
+    if !is_hashed(&name) {
+        return Ok(());
+    }
+
+    let path = dir.join(format!("{}{}", name, SIDECAR_SUFFIX));
+    if !path.exists() {
+        fs::create_dir_all(dir)?;
+        write_atomic(&path, &seal(session_id.as_bytes()), durability)?;
+    }
+
+    Ok(())
+}
+
+/// Deletes what `save_session_id` wrote, once the entry itself is gone.
+pub fn remove_session_id(dir: &Path, session_id: &str) -> Result<(), StoreError> {
+    let name = encode_session_id(session_id)?;
+    if !is_hashed(&name) {
+        return Ok(());
+    }
+
+    match fs::remove_file(dir.join(format!("{}{}", name, SIDECAR_SUFFIX))) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether `name` is a file `save_session_id` wrote rather than an entry.
+pub fn is_session_id_file(name: &str) -> bool {
+    name.starts_with(HASHED_PREFIX) && name.ends_with(SIDECAR_SUFFIX)
+}
+
+/// Id of the session stored as the entry `name` of `dir`, decoding the name
+/// or reading the id saved for a hashed one. `None` if the name isn't one
+/// `encode_session_id` can have made.
+pub fn read_session_id(dir: &Path, name: &str) -> Result<Option<String>, StoreError> {
+    if !is_hashed(name) {
+        return Ok(decode_session_id(name));
+    }
+
+    let path = dir.join(format!("{}{}", name, SIDECAR_SUFFIX));
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let session_id = String::from_utf8(unseal(&path, &bytes)?.to_vec())
+        .map_err(|_| StoreError::corrupt(&path, 0, "session id is not UTF-8"))?;
+    if encode_session_id(&session_id)? != name {
+        return Err(StoreError::corrupt(&path, 0, "session id doesn't match"));
+    }
+
+    Ok(Some(session_id))
+}
+
+/// Lists the ids of the sessions stored as entries of `dir`, skipping temp
+/// files. An entry that isn't an encoded session id is reported as corrupt.
+pub fn list_session_ids(dir: &Path) -> Result<Vec<String>, StoreError> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut result = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if is_temp_file(&name) || is_session_id_file(&name) {
+            continue;
+        }
+
+        match read_session_id(dir, &name)? {
+            Some(session_id) => result.push(session_id),
+            None => {
+                return Err(StoreError::corrupt(
+                    entry.path(),
+                    0,
+                    "not an encoded session id",
+                ))
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn is_kept(byte: u8) -> bool {
+    byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-' || byte == b'_'
+}
+
+fn is_hashed(name: &str) -> bool {
+    name.starts_with(HASHED_PREFIX) && !name.ends_with(SIDECAR_SUFFIX)
+}
+
+fn unescape(name: &str, kept: fn(u8) -> bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut chars = name.bytes();
+    while let Some(byte) = chars.next() {
+        if byte == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            let decoded = u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?;
+            bytes.push(decoded);
+        } else if kept(byte) {
+            bytes.push(byte);
+        } else {
+            return None;
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_round_trip() {
+        for session_id in [
+            "Session 1",
+            "client-42_A",
+            "../../etc",
+            "a/b\\c",
+            ".hidden",
+            "x.tmp",
+            "100%",
+            "ünïcödé ✓",
+        ] {
+            let name = encode_session_id(session_id).unwrap();
+            assert!(name
+                .bytes()
+                .all(|b| is_kept(b) || b == b'%' || b.is_ascii_hexdigit()));
+            assert!(!is_temp_file(&name));
+            assert_eq!(decode_session_id(&name).as_deref(), Some(session_id));
+        }
+
+        assert_eq!(encode_session_id("../a b").unwrap(), "%2E%2E%2Fa%20b");
+        assert_eq!(encode_session_id("Ab").unwrap(), "%41b");
+    }
+
+    #[test]
+    fn test_case_insensitive_names() {
+        let names: Vec<String> = ["abc", "ABC", "Abc", "aBC"]
+            .iter()
+            .map(|session_id| encode_session_id(session_id).unwrap().to_lowercase())
+            .collect();
+        for (i, name) in names.iter().enumerate() {
+            assert!(!names[i + 1..].contains(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(encode_session_id("").is_err());
+
+        for name in [
+            "Session 1",
+            "Session%201",
+            "%2e",
+            "%2",
+            "%zz",
+            "%61",
+            "a.tmp",
+            "%FF",
+            "~0123",
+        ] {
+            assert_eq!(decode_session_id(name), None, "{}", name);
+        }
+        assert_eq!(
+            decode_unescaped_uppercase("Session%201").as_deref(),
+            Some("Session 1")
+        );
+    }
+
+    #[test]
+    fn test_long_ids() {
+        let dir = tempdir().unwrap();
+        let longest = "a".repeat(MAX_NAME_LEN);
+        assert_eq!(encode_session_id(&longest).unwrap(), longest);
+
+        let long = "a".repeat(MAX_NAME_LEN + 1);
+        let name = encode_session_id(&long).unwrap();
+        assert!(name.len() <= MAX_NAME_LEN);
+        assert_ne!(
+            name,
+            encode_session_id(&"b".repeat(MAX_NAME_LEN + 1)).unwrap()
+        );
+        assert_eq!(decode_session_id(&name), None);
+
+        save_session_id(dir.path(), &long, Durability::None).unwrap();
+        fs::write(dir.path().join(&name), b"").unwrap();
+        fs::write(dir.path().join(&longest), b"").unwrap();
+        let mut session_ids = list_session_ids(dir.path()).unwrap();
+        session_ids.sort();
+        assert_eq!(session_ids, vec![longest.clone(), long.clone()]);
+
+        // An entry whose id was lost can't be listed
+        remove_session_id(dir.path(), &long).unwrap();
+        assert!(matches!(
+            list_session_ids(dir.path()),
+            Err(StoreError::Corrupt { .. })
+        ));
+    }
+
+    #[test]
+    fn test_list_session_ids() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join(encode_session_id("a/b").unwrap()), b"").unwrap();
+        fs::write(dir.path().join("x.tmp"), b"").unwrap();
+        assert_eq!(list_session_ids(dir.path()).unwrap(), vec!["a/b"]);
+
+        fs::write(dir.path().join("stray file"), b"").unwrap();
+        assert!(matches!(
+            list_session_ids(dir.path()),
+            Err(StoreError::Corrupt { .. })
+        ));
+    }
+}
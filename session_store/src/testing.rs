@@ -0,0 +1,174 @@
+//! Checks every store should pass, shared by their test suites. Enabled by the
+//! `testing` feature, which stores turn on for their dev-dependency.
+
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read, read_dir, write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use crate::archive::{self, ArchiveStats};
+use crate::{Inspect, Payload, Publish, SessionStore, StoreError};
+
+/// The `n`th publish of a session, with the same payload id and bytes in
+/// every session.
+pub fn numbered_publish(n: u8) -> Publish {
+    Publish {
+        packet_id: 100 + 2 * n as u16,
+        retain: true,
+        topic_name: "fake".to_owned(),
+        payload: Payload {
+            id: 1000 + n as u64,
+            bytes: Arc::new(vec![n]),
+        },
+    }
+}
+
+/// Checks that `store`, rooted at `root`, rejects an empty session id and
+/// keeps one that looks like a path inside the store.
+pub fn check_invalid_session_id<S: SessionStore>(store: &S, root: &Path) {
+    assert!(matches!(
+        store.append("", numbered_publish(0)),
+        Err(StoreError::InvalidSessionId(_))
+    ));
+
+    store
+        .append("../escape", numbered_publish(0))
+        .expect("Publish");
+    assert!(!root.join("escape").exists());
+    assert_eq!(store.list_sessions().unwrap(), vec!["../escape"]);
+}
+
+/// Has 8 threads each append `publishes` publishes to a session of their own
+/// and ack the first half, then checks each session holds the rest and that
+/// the payloads they share are stored once.
+pub fn check_concurrent_sessions<S: Inspect>(store: &S, publishes: u8) {
+    thread::scope(|scope| {
+        for i in 0..8 {
+            scope.spawn(move || {
+                let name = format!("Session {}", i);
+                let publishes: Vec<Publish> = (0..publishes).map(numbered_publish).collect();
+                for publish in publishes.iter() {
+                    store.append(&name, publish.clone()).expect("Publish");
+                }
+                let half = publishes.len() / 2;
+                for publish in publishes.iter().take(half) {
+                    store.ack(&name, publish.packet_id).expect("Ack");
+                }
+                assert_eq!(store.read(&name).unwrap(), publishes[half..].to_vec());
+            });
+        }
+    });
+
+    store.clean().unwrap();
+    let payloads = store.payloads().unwrap();
+    assert_eq!(payloads.len(), publishes as usize - publishes as usize / 2);
+    assert!(payloads.iter().all(|p| p.refcount == 8));
+}
+
+/// Exports `store` after filling it with a payload shared by three sessions
+/// and one of its own, then checks importing the archive into the empty
+/// `imported` gives back the same sessions and payloads.
+pub fn check_archive_round_trip<S: Inspect>(store: &S, imported: &S) {
+    let session_ids = ["Session 1", "Session 2", "Session 3"];
+    store
+        .append_fanout(numbered_publish(1), &session_ids)
+        .expect("Fanout");
+    store
+        .append("Session 2", numbered_publish(2))
+        .expect("Publish");
+
+    let mut archive = Vec::new();
+    let stats = archive::export(store, &mut archive).unwrap();
+    assert_eq!(
+        stats,
+        ArchiveStats {
+            sessions: 3,
+            publishes: 4,
+            payloads: 2,
+        }
+    );
+
+    assert_eq!(
+        archive::import(imported, archive.as_slice()).unwrap(),
+        stats
+    );
+    for session_id in session_ids {
+        assert_eq!(
+            imported.read(session_id).unwrap(),
+            store.read(session_id).unwrap()
+        );
+    }
+    // the shared payload is still stored once
+    assert_eq!(imported.payloads().unwrap().len(), 2);
+}
+
+fn golden_publish(packet_id: u16, payload_id: u64, bytes: Vec<u8>, retain: bool) -> Publish {
+    Publish {
+        packet_id,
+        retain,
+        topic_name: if retain { "c" } else { "a/b" }.to_owned(),
+        payload: Payload {
+            id: payload_id,
+            bytes: Arc::new(bytes),
+        },
+    }
+}
+
+/// Writes what the stores under each crate's `testdata/` hold.
+pub fn golden_fill<S: SessionStore>(store: &S) {
+    store
+        .append("Session 1", golden_publish(1, 1, vec![1, 2, 3], false))
+        .expect("Publish 1");
+    store
+        .append("Session 1", golden_publish(2, 2, vec![4, 5], true))
+        .expect("Publish 2");
+    store.ack("Session 1", 1).expect("Ack");
+    store
+        .append("Session 2", golden_publish(3, 1, vec![1, 2, 3], false))
+        .expect("Publish 3");
+}
+
+/// Checks `store` holds what `golden_fill` left.
+pub fn assert_golden_contents<S: SessionStore>(store: &S) {
+    assert_eq!(
+        store.read("Session 1").unwrap(),
+        vec![golden_publish(2, 2, vec![4, 5], true)]
+    );
+    assert_eq!(
+        store.read("Session 2").unwrap(),
+        vec![golden_publish(3, 1, vec![1, 2, 3], false)]
+    );
+}
+
+/// The golden store for format `version` of the crate at `crate_dir`,
+/// i.e. its `CARGO_MANIFEST_DIR`.
+pub fn golden_dir(crate_dir: &str, version: &str) -> PathBuf {
+    Path::new(crate_dir).join("testdata").join(version)
+}
+
+/// Every file under `root` by relative path, with its contents.
+pub fn read_tree(root: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+    let mut files = BTreeMap::new();
+    let mut dirs = vec![root.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                let contents = read(&path).unwrap();
+                files.insert(path.strip_prefix(root).unwrap().to_owned(), contents);
+            }
+        }
+    }
+    files
+}
+
+pub fn write_tree(root: &Path, files: &BTreeMap<PathBuf, Vec<u8>>) {
+    for (path, contents) in files {
+        let path = root.join(path);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(path, contents).unwrap();
+    }
+}
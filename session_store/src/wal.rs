@@ -0,0 +1,190 @@
+//! Write-ahead log shared by many writers. Records committed at about the same
+//! time are appended and synced together (group commit), so a burst of writes
+//! from many threads costs one sync rather than one each.
+
+use serde::Serialize;
+use std::fs::remove_file;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, PoisonError};
+
+use crate::log::{append_log, encode_record};
+use crate::{lock, sync_dir, Durability, StoreError};
+
+/// A log whose records are only durable once `commit` returns. Decode it with
+/// `log::read_log`, it is an ordinary log otherwise.
+pub struct Wal {
+    path: PathBuf,
+    durability: Durability,
+    state: Mutex<WalState>,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct WalState {
+    /// Encoded records waiting for the next group to be written.
+    queued: Vec<u8>,
+    /// Commits are numbered as they are queued; every commit up to `synced`
+    /// is durable.
+    enqueued: u64,
+    synced: u64,
+    /// Whether a writer is currently appending a group for everyone.
+    syncing: bool,
+    /// Set once a group fails to be written, after which the log's tail is
+    /// unknown and every commit fails until the store is reopened.
+    failed: Option<(io::ErrorKind, String)>,
+    /// Records committed since the log was last reset.
+    records: usize,
+}
+
+impl Wal {
+    /// A log at `path`, which is created by the first commit. Existing records
+    /// aren't counted by `len`, they should be replayed and the log reset.
+    pub fn new(path: PathBuf, durability: Durability) -> Self {
+        Wal {
+            path,
+            durability,
+            state: Mutex::new(WalState::default()),
+            synced: Condvar::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of records committed since the log was last reset.
+    pub fn len(&self) -> usize {
+        lock(&self.state).records
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Appends `records` to the log, returning once they are durable. Whoever
+    /// finds no group being written writes everything queued so far, while
+    /// later commits queue up behind it for the next group.
+    pub fn commit<T: Serialize>(&self, records: &[T]) -> Result<(), StoreError> {
+        let mut bytes = Vec::new();
+        for record in records {
+            encode_record(record, &mut bytes)?;
+        }
+
+        let mut state = lock(&self.state);
+        state.queued.extend_from_slice(&bytes);
+        state.records += records.len();
+        state.enqueued += 1;
+        let ticket = state.enqueued;
+
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if let Some((kind, message)) = &state.failed {
+                return Err(io::Error::new(*kind, message.clone()).into());
+            }
+            if state.syncing {
+                state = self
+                    .synced
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+
+            state.syncing = true;
+            let group = mem::take(&mut state.queued);
+            let last = state.enqueued;
+            drop(state);
+
+            let result = append_log(&self.path, &group, self.durability);
+
+            state = lock(&self.state);
+            state.syncing = false;
+            match result {
+                Ok(_) => state.synced = last,
+                Err(e) => state.failed = Some((e.kind(), e.to_string())),
+            }
+            self.synced.notify_all();
+        }
+    }
+
+    /// Deletes the log once everything in it has been applied elsewhere. The
+    /// caller must make sure no commit is in progress.
+    pub fn reset(&self) -> Result<(), StoreError> {
+        let mut state = lock(&self.state);
+        debug_assert!(!state.syncing && state.queued.is_empty());
+
+        match remove_file(&self.path) {
+            Ok(()) => {
+                if let Some(parent) = self.path.parent() {
+                    sync_dir(parent, self.durability)?;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        state.records = 0;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::read_log;
+    use std::sync::Arc;
+    use std::thread;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_concurrent_commits() {
+        let dir = tempdir().unwrap();
+        let wal = Arc::new(Wal::new(dir.path().join("Wal"), Durability::Fsync));
+
+        let handles: Vec<_> = (0..8u64)
+            .map(|i| {
+                let wal = wal.clone();
+                thread::spawn(move || {
+                    for j in 0..50 {
+                        wal.commit(&[i * 100 + j]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(wal.len(), 400);
+
+        let mut records: Vec<u64> = read_log(wal.path())
+            .unwrap()
+            .into_iter()
+            .map(|(record, _)| record)
+            .collect();
+        // each thread's records are in the order it committed them
+        for i in 0..8 {
+            let mine: Vec<u64> = records.iter().copied().filter(|r| r / 100 == i).collect();
+            assert_eq!(mine, (0..50).map(|j| i * 100 + j).collect::<Vec<u64>>());
+        }
+        records.sort_unstable();
+        records.dedup();
+        assert_eq!(records.len(), 400);
+
+        wal.reset().unwrap();
+        assert!(wal.is_empty());
+        assert!(!wal.path().exists());
+    }
+
+    #[test]
+    fn test_failed_group_poisons() {
+        let dir = tempdir().unwrap();
+        let wal = Wal::new(dir.path().join("missing").join("Wal"), Durability::None);
+
+        assert!(matches!(wal.commit(&[1u64]), Err(StoreError::Io(_))));
+        std::fs::create_dir(dir.path().join("missing")).unwrap();
+        assert!(matches!(wal.commit(&[2u64]), Err(StoreError::Io(_))));
+    }
+}
@@ -0,0 +1,363 @@
+//! Converts a store between the two layouts in place. The converted store is
+//! built next to the original, then swapped in, with a marker file recording
+//! how far the conversion got so it can be resumed after a crash.
+
+use std::fs::{self, create_dir_all, read_dir, remove_dir_all, remove_file, rename, File};
+use std::path::{Path, PathBuf};
+
+use session_store::{sync_dir, write_atomic, Durability, SessionStore, StoreError};
+
+use crate::layout::Layout;
+
+/// Marker file, present while a conversion is in progress.
+const MARKER: &str = "Converting";
+/// Where the converted store is built.
+const STAGING: &str = "Converting.new";
+/// Where the original store's files are moved to before being deleted.
+const OLD: &str = "Converting.old";
+
+/// Entries of a store root that belong to its layout. `Corrupt` is left in
+/// place, so quarantined data outlives the conversion.
+const LAYOUT_ENTRIES: &[&str] = &[
+    "Format",
+    "Payloads",
+    "Sessions",
+    "Refcounts",
+    "Wal",
+    "Batches",
+];
+
+/// Steps of a conversion, each safe to rerun if interrupted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Phase {
+    /// Building the converted store in `STAGING`, restarted from scratch.
+    Copy,
+    /// Moving the original store's entries into `OLD`.
+    SwapOut,
+    /// Moving the converted store's entries out of `STAGING` into the root.
+    SwapIn,
+    /// Deleting `OLD` and `STAGING`.
+    Cleanup,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Copy => "copy",
+            Phase::SwapOut => "swap-out",
+            Phase::SwapIn => "swap-in",
+            Phase::Cleanup => "cleanup",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [Phase::Copy, Phase::SwapOut, Phase::SwapIn, Phase::Cleanup]
+            .iter()
+            .copied()
+            .find(|phase| phase.name() == name)
+    }
+}
+
+/// Contents of the marker file: the target layout and the current phase, one
+/// per line.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Marker {
+    to: Layout,
+    phase: Phase,
+}
+
+impl Marker {
+    fn read(root: &Path) -> Result<Option<Self>, StoreError> {
+        let path = root.join(MARKER);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&path)?;
+        let mut lines = contents.lines();
+        let to = lines.next().and_then(Layout::from_name);
+        let phase = lines.next().and_then(Phase::from_name);
+        match (to, phase) {
+            (Some(to), Some(phase)) => Ok(Some(Marker { to, phase })),
+            _ => Err(StoreError::corrupt(path, 0, "unreadable conversion marker")),
+        }
+    }
+
+    fn write(&self, root: &Path, durability: Durability) -> Result<(), StoreError> {
+        let contents = format!("{}\n{}\n", self.to.name(), self.phase.name());
+        write_atomic(&root.join(MARKER), contents.as_bytes(), durability)?;
+
+        Ok(())
+    }
+}
+
+/// What `convert` did.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    Converted,
+    /// The store already had the layout asked for.
+    Unchanged,
+    /// Nothing was done, since an interrupted conversion to another layout
+    /// has to be finished first.
+    ConvertingTo(Layout),
+}
+
+/// Converts the store at `root` to the `to` layout, or finishes converting it
+/// if an earlier conversion was interrupted. Nothing else may use the store
+/// meanwhile, and every message must be readable, see `fsck`.
+pub fn convert(root: &Path, to: Layout, durability: Durability) -> Result<Outcome, StoreError> {
+    let mut marker = match Marker::read(root)? {
+        Some(marker) if marker.to != to => return Ok(Outcome::ConvertingTo(marker.to)),
+        Some(marker) => marker,
+        None if Layout::detect(root)? == Some(to) => return Ok(Outcome::Unchanged),
+        None => {
+            let marker = Marker {
+                to,
+                phase: Phase::Copy,
+            };
+            marker.write(root, durability)?;
+            marker
+        }
+    };
+
+    while let Some(phase) = run(root, marker, durability)? {
+        marker.phase = phase;
+        marker.write(root, durability)?;
+    }
+    remove_file(root.join(MARKER))?;
+    sync_dir(root, durability)?;
+
+    Ok(Outcome::Converted)
+}
+
+/// Runs a phase, returning the one to run next.
+fn run(root: &Path, marker: Marker, durability: Durability) -> Result<Option<Phase>, StoreError> {
+    let staging = root.join(STAGING);
+    let old = root.join(OLD);
+
+    match marker.phase {
+        Phase::Copy => {
+            if staging.exists() {
+                remove_dir_all(&staging)?;
+            }
+            create_dir_all(&staging)?;
+
+            // Staged files only need to be durable once all of them are written
+            match marker.to {
+                Layout::Gc => copy(
+                    &load_consolidate_test::DB::new(root)?,
+                    &gc_test::DB::with_durability(&staging, Durability::None)?,
+                )?,
+                Layout::LoadConsolidate => copy(
+                    &gc_test::DB::new(root)?,
+                    &load_consolidate_test::DB::with_durability(&staging, Durability::None)?,
+                )?,
+            }
+            sync_tree(&staging, durability)?;
+            sync_dir(root, durability)?;
+
+            Ok(Some(Phase::SwapOut))
+        }
+        Phase::SwapOut => {
+            create_dir_all(&old)?;
+            for name in LAYOUT_ENTRIES {
+                let path = root.join(name);
+                if path.exists() {
+                    rename(path, old.join(name))?;
+                }
+            }
+            sync_dir(&old, durability)?;
+            sync_dir(root, durability)?;
+
+            Ok(Some(Phase::SwapIn))
+        }
+        Phase::SwapIn => {
+            for entry in read_dir(&staging)? {
+                let entry = entry?;
+                rename(entry.path(), root.join(entry.file_name()))?;
+            }
+            sync_dir(&staging, durability)?;
+            sync_dir(root, durability)?;
+
+            Ok(Some(Phase::Cleanup))
+        }
+        Phase::Cleanup => {
+            for dir in [&old, &staging].iter() {
+                if dir.exists() {
+                    remove_dir_all(dir)?;
+                }
+            }
+
+            Ok(None)
+        }
+    }
+}
+
+/// Appends every session of `from` to `to`, in order, with the same payloads.
+/// Each store shares payloads its own way, from the ids and bytes it is given.
+fn copy<S, T>(from: &S, to: &T) -> Result<(), StoreError>
+where
+    S: SessionStore,
+    T: SessionStore,
+{
+    let mut session_ids = from.list_sessions()?;
+    session_ids.sort();
+
+    for session_id in session_ids {
+        for publish in from.read(&session_id)? {
+            to.append(&session_id, publish)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Syncs every file and directory under `dir`, for data written with
+/// `Durability::None`.
+fn sync_tree(dir: &Path, durability: Durability) -> Result<(), StoreError> {
+    if durability == Durability::None {
+        return Ok(());
+    }
+
+    let mut dirs: Vec<PathBuf> = vec![dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                File::open(path)?.sync_all()?;
+            }
+        }
+        sync_dir(&dir, durability)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use session_store::{Inspect, Payload, Publish};
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    fn publish(packet_id: u16, payload_id: u64, bytes: Vec<u8>, retain: bool) -> Publish {
+        Publish {
+            packet_id,
+            retain,
+            topic_name: format!("topic/{}", packet_id),
+            payload: Payload {
+                id: payload_id,
+                bytes: Arc::new(bytes),
+            },
+        }
+    }
+
+    fn fill<S: SessionStore>(store: &S) -> Vec<(&'static str, Vec<Publish>)> {
+        let sessions = vec![
+            (
+                "Session 1",
+                vec![
+                    publish(1, 1, vec![1, 2, 3], false),
+                    publish(2, 2, vec![4, 5], true),
+                    publish(3, 1, vec![1, 2, 3], false),
+                ],
+            ),
+            ("Session 2", vec![publish(4, 1, vec![1, 2, 3], true)]),
+        ];
+        for (session_id, publishes) in sessions.iter() {
+            for publish in publishes {
+                store.append(session_id, publish.clone()).expect("Publish");
+            }
+        }
+        sessions
+    }
+
+    fn assert_contents<S: Inspect>(store: &S, sessions: &[(&str, Vec<Publish>)]) {
+        for (session_id, publishes) in sessions {
+            assert_eq!(&store.read(session_id).unwrap(), publishes);
+        }
+        // the shared payload is still stored once
+        assert_eq!(store.payloads().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_convert_both_ways() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let sessions = fill(&gc_test::DB::new(root).expect("Make db"));
+        fs::write(root.join("notes"), b"kept").unwrap();
+
+        assert_eq!(
+            convert(root, Layout::LoadConsolidate, Durability::None).unwrap(),
+            Outcome::Converted
+        );
+        assert_eq!(Layout::detect(root).unwrap(), Some(Layout::LoadConsolidate));
+        assert_contents(
+            &load_consolidate_test::DB::new(root).expect("Open db"),
+            &sessions,
+        );
+        assert_eq!(
+            convert(root, Layout::LoadConsolidate, Durability::None).unwrap(),
+            Outcome::Unchanged
+        );
+
+        assert_eq!(
+            convert(root, Layout::Gc, Durability::Fsync).unwrap(),
+            Outcome::Converted
+        );
+        assert_eq!(Layout::detect(root).unwrap(), Some(Layout::Gc));
+        assert_contents(&gc_test::DB::new(root).expect("Open db"), &sessions);
+
+        let mut entries: Vec<String> = read_dir(root)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec!["Format", "Payloads", "Refcounts", "Sessions", "notes"]
+        );
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let sessions = fill(&gc_test::DB::new(root).expect("Make db"));
+
+        // crash part way through moving the original store out
+        let marker = Marker {
+            to: Layout::LoadConsolidate,
+            phase: Phase::Copy,
+        };
+        marker.write(root, Durability::None).unwrap();
+        assert_eq!(
+            run(root, marker, Durability::None).unwrap(),
+            Some(Phase::SwapOut)
+        );
+        Marker {
+            phase: Phase::SwapOut,
+            ..marker
+        }
+        .write(root, Durability::None)
+        .unwrap();
+        create_dir_all(root.join(OLD)).unwrap();
+        rename(root.join("Sessions"), root.join(OLD).join("Sessions")).unwrap();
+
+        assert_eq!(
+            convert(root, Layout::Gc, Durability::None).unwrap(),
+            Outcome::ConvertingTo(Layout::LoadConsolidate)
+        );
+        assert_eq!(
+            convert(root, Layout::LoadConsolidate, Durability::None).unwrap(),
+            Outcome::Converted
+        );
+        assert!(!root.join(MARKER).exists());
+        assert_contents(
+            &load_consolidate_test::DB::new(root).expect("Open db"),
+            &sessions,
+        );
+    }
+}
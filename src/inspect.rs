@@ -0,0 +1,304 @@
+//! Read-only commands that work against either store layout.
+
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str;
+
+use session_store::{Inspect, Publish, StoreError};
+
+use crate::layout::Layout;
+
+/// Longest payload prefix shown by `dump`.
+const PREVIEW_LEN: usize = 32;
+
+pub enum Command {
+    Sessions,
+    Dump(String),
+    Payloads,
+    Stats,
+}
+
+/// A publish as printed by `dump`, one JSON object per line.
+#[derive(Serialize)]
+struct DumpedPublish<'a> {
+    packet_id: u16,
+    retain: bool,
+    topic_name: &'a str,
+    payload_id: u64,
+    payload_len: usize,
+    /// Start of the payload, if it is text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_text: Option<&'a str>,
+    /// Start of the payload otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_hex: Option<String>,
+}
+
+impl<'a> DumpedPublish<'a> {
+    fn new(publish: &'a Publish) -> Self {
+        let bytes = &publish.payload.bytes;
+        let prefix = &bytes[..bytes.len().min(PREVIEW_LEN)];
+        let text = match str::from_utf8(prefix) {
+            Ok(text) => Some(text),
+            // Cut off in the middle of a character
+            Err(e) if e.error_len().is_none() => str::from_utf8(&prefix[..e.valid_up_to()]).ok(),
+            Err(_) => None,
+        }
+        .filter(|text| text.chars().all(|c| !c.is_control() || c.is_whitespace()));
+        let hex = match text {
+            Some(_) => None,
+            None => Some(prefix.iter().map(|b| format!("{:02x}", b)).collect()),
+        };
+
+        DumpedPublish {
+            packet_id: publish.packet_id,
+            retain: publish.retain,
+            topic_name: &publish.topic_name,
+            payload_id: publish.payload.id,
+            payload_len: bytes.len(),
+            payload_text: text,
+            payload_hex: hex,
+        }
+    }
+}
+
+/// Runs `command` against `store`, opened read-only from `root`, printing
+/// to `out`.
+pub fn run<S: Inspect, W: Write>(
+    store: &S,
+    root: &Path,
+    layout: Layout,
+    command: &Command,
+    out: &mut W,
+) -> Result<(), StoreError> {
+    match command {
+        Command::Sessions => {
+            for (session_id, publishes) in read_all(store)? {
+                writeln!(out, "{}\t{}", session_id, publishes.len())?;
+            }
+        }
+        Command::Dump(session_id) => {
+            for publish in store.read(session_id)? {
+                serde_json::to_writer(&mut *out, &DumpedPublish::new(&publish))
+                    .map_err(json_error)?;
+                writeln!(out)?;
+            }
+        }
+        Command::Payloads => {
+            writeln!(out, "key\tbytes\trefcount")?;
+            for payload in store.payloads()? {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}",
+                    payload.key, payload.stored_bytes, payload.refcount
+                )?;
+            }
+        }
+        Command::Stats => {
+            let sessions = read_all(store)?;
+            let messages: usize = sessions.values().map(Vec::len).sum();
+            let queued_bytes: usize = sessions
+                .values()
+                .flatten()
+                .map(|publish| publish.payload.bytes.len())
+                .sum();
+            let payloads = store.payloads()?;
+            let stored_bytes: u64 = payloads.iter().map(|p| p.stored_bytes).sum();
+            // Garbage waiting to be cleaned up isn't deduplicating anything
+            let referenced_bytes: u64 = payloads
+                .iter()
+                .filter(|p| p.refcount > 0)
+                .map(|p| p.stored_bytes)
+                .sum();
+
+            writeln!(out, "layout\t{}", layout.name())?;
+            writeln!(out, "sessions\t{}", sessions.len())?;
+            writeln!(out, "messages\t{}", messages)?;
+            writeln!(out, "bytes on disk\t{}", disk_usage(root)?)?;
+            writeln!(out, "payloads stored\t{}", payloads.len())?;
+            writeln!(out, "payload bytes stored\t{}", stored_bytes)?;
+            writeln!(out, "payload bytes queued\t{}", queued_bytes)?;
+            if referenced_bytes > 0 {
+                writeln!(
+                    out,
+                    "dedupe ratio\t{:.2}",
+                    queued_bytes as f64 / referenced_bytes as f64
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Failing to write the output is `Io`, anything else couldn't be encoded.
+fn json_error(e: serde_json::Error) -> StoreError {
+    if e.is_io() {
+        StoreError::Io(e.into())
+    } else {
+        StoreError::Serialization(serde::ser::Error::custom(e))
+    }
+}
+
+fn read_all<S: Inspect>(store: &S) -> Result<BTreeMap<String, Vec<Publish>>, StoreError> {
+    store
+        .list_sessions()?
+        .into_iter()
+        .map(|session_id| {
+            let publishes = store.read(&session_id)?;
+            Ok((session_id, publishes))
+        })
+        .collect()
+}
+
+/// Total size of the files under `path`.
+fn disk_usage(path: &Path) -> Result<u64, StoreError> {
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        total += if metadata.is_dir() {
+            disk_usage(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use session_store::{Payload, SessionStore};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    fn publish(packet_id: u16, payload_id: u64, bytes: Vec<u8>) -> Publish {
+        Publish {
+            packet_id,
+            retain: false,
+            topic_name: format!("topic/{}", packet_id),
+            payload: Payload {
+                id: payload_id,
+                bytes: Arc::new(bytes),
+            },
+        }
+    }
+
+    fn preview(bytes: &[u8]) -> (Option<String>, Option<String>) {
+        let publish = publish(1, 1, bytes.to_vec());
+        let dumped = DumpedPublish::new(&publish);
+        assert_eq!(dumped.payload_len, bytes.len());
+        (dumped.payload_text.map(str::to_owned), dumped.payload_hex)
+    }
+
+    #[test]
+    fn test_dump_previews() {
+        assert_eq!(preview(b"hello\n"), (Some("hello\n".to_owned()), None));
+        assert_eq!(preview(&[b'a'; 40]).0.unwrap().len(), PREVIEW_LEN);
+        // a character cut off by the preview is left out of it
+        let text = format!("{}é", "a".repeat(PREVIEW_LEN - 1));
+        assert_eq!(
+            preview(text.as_bytes()).0,
+            Some("a".repeat(PREVIEW_LEN - 1))
+        );
+        assert_eq!(
+            preview(&[0xff, 0x00, 0x10]),
+            (None, Some("ff0010".to_owned()))
+        );
+        assert_eq!(preview(b"a\x07b"), (None, Some("610762".to_owned())));
+    }
+
+    #[test]
+    fn test_dump() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let db = gc_test::DB::new(root).expect("Make db");
+        db.append("Session 1", publish(1, 1, b"hello".to_vec()))
+            .expect("Publish 1");
+        db.append("Session 1", publish(2, 2, vec![0, 1, 2]))
+            .expect("Publish 2");
+        drop(db);
+
+        let db = gc_test::DB::open_read_only(root).expect("Open db");
+        let mut out = Vec::new();
+        let command = Command::Dump("Session 1".to_owned());
+        run(&db, root, Layout::Gc, &command, &mut out).unwrap();
+
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                serde_json::json!({
+                    "packet_id": 1,
+                    "retain": false,
+                    "topic_name": "topic/1",
+                    "payload_id": 1,
+                    "payload_len": 5,
+                    "payload_text": "hello",
+                }),
+                serde_json::json!({
+                    "packet_id": 2,
+                    "retain": false,
+                    "topic_name": "topic/2",
+                    "payload_id": 2,
+                    "payload_len": 3,
+                    "payload_hex": "000102",
+                }),
+            ]
+        );
+    }
+
+    /// Stores one payload queued four times, and one nothing references.
+    fn check_stats<S: Inspect>(layout: Layout, open_read_only: fn(&Path) -> Result<S, StoreError>) {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        let store = S::open(root).expect("Make store");
+        store
+            .append_fanout(publish(1, 1, vec![7; 1000]), &["a", "b", "c", "d"])
+            .expect("Fanout");
+        store
+            .append("e", publish(2, 2, vec![8; 1000]))
+            .expect("Publish");
+        drop(store);
+        let store = S::open(root).expect("Reopen store");
+        store.ack("e", 2).expect("Ack");
+        drop(store);
+
+        let store = open_read_only(root).expect("Open store");
+        let mut out = Vec::new();
+        run(&store, root, layout, &Command::Stats, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let stats: HashMap<&str, &str> = out
+            .lines()
+            .map(|line| line.split_once('\t').unwrap())
+            .collect();
+
+        assert_eq!(stats["layout"], layout.name());
+        assert_eq!(stats["sessions"], "5");
+        assert_eq!(stats["messages"], "4");
+        assert_eq!(stats["payloads stored"], "2");
+        assert_eq!(stats["payload bytes queued"], "4000");
+        // a little under 4 for the framing of the stored payload
+        let ratio: f64 = stats["dedupe ratio"].parse().unwrap();
+        assert!((3.8..4.0).contains(&ratio), "{}", ratio);
+    }
+
+    #[test]
+    fn test_stats() {
+        check_stats(Layout::Gc, gc_test::DB::open_read_only);
+        check_stats(
+            Layout::LoadConsolidate,
+            load_consolidate_test::DB::open_read_only,
+        );
+    }
+}
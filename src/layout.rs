@@ -0,0 +1,90 @@
+use std::fs;
+use std::path::Path;
+
+use session_store::StoreError;
+
+/// The two on-disk layouts, told apart by what `Payloads` is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Layout {
+    /// `gc_test`: a directory of payload files.
+    Gc,
+    /// `load_consolidate_test`: a single payload log.
+    LoadConsolidate,
+}
+
+impl Layout {
+    /// Tells which layout the store at `root` is in. `None` if nothing has
+    /// been stored there yet, including when `root` is empty or missing,
+    /// since either layout can open it then.
+    pub fn detect(root: &Path) -> Result<Option<Self>, StoreError> {
+        let payloads = root.join("Payloads");
+        if payloads.is_dir() {
+            return Ok(Some(Layout::Gc));
+        }
+        if payloads.is_file() {
+            return Ok(Some(Layout::LoadConsolidate));
+        }
+
+        // No payload stored yet, sessions are directories in one and files in the other
+        let sessions = root.join("Sessions");
+        if sessions.is_dir() {
+            if let Some(entry) = fs::read_dir(&sessions)?.next() {
+                return Ok(Some(if entry?.path().is_dir() {
+                    Layout::Gc
+                } else {
+                    Layout::LoadConsolidate
+                }));
+            }
+        }
+
+        if !root.exists()
+            || sessions.is_dir()
+            || root.join("Format").is_file()
+            || fs::read_dir(root)?.next().is_none()
+        {
+            return Ok(None);
+        }
+
+        Err(StoreError::corrupt(root, 0, "not a store"))
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Layout::Gc, Layout::LoadConsolidate]
+            .iter()
+            .copied()
+            .find(|layout| layout.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Gc => "gc_test",
+            Layout::LoadConsolidate => "load_consolidate_test",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_detect() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        assert_eq!(Layout::detect(&root.join("missing")).unwrap(), None);
+        assert_eq!(Layout::detect(root).unwrap(), None);
+
+        drop(load_consolidate_test::DB::new(root).expect("Make db"));
+        assert_eq!(Layout::detect(root).unwrap(), None);
+        drop(gc_test::DB::new(root).expect("Make db"));
+        assert_eq!(Layout::detect(root).unwrap(), Some(Layout::Gc));
+
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("notes"), b"").unwrap();
+        assert!(matches!(
+            Layout::detect(dir.path()),
+            Err(StoreError::Corrupt { .. })
+        ));
+    }
+}
@@ -1,3 +1,222 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+use std::process;
+
+use convert::Outcome;
+use gc_test::fsck::{self, FsckReport};
+use gc_test::{Durability, StoreError};
+use inspect::Command;
+use layout::Layout;
+use session_store::archive::{self, ArchiveStats};
+
+mod convert;
+mod inspect;
+mod layout;
+
+const USAGE: &str = "\
+usage: persistance_prototype <command>
+
+commands:
+    sessions <store root>          list sessions and how many messages each queues
+    dump <store root> <session>    print a session's messages as JSON, one per line
+    payloads <store root>          list stored payloads with their size and refcount
+    stats <store root>             show totals over the whole store
+    export <store root> <archive>  write every session to a portable archive
+    import [--layout <layout>] <archive> <store root>
+                                   queue an archive's publishes in a store, of
+                                   layout gc_test or load_consolidate_test if new
+    convert <store root> <layout>  convert a store to layout gc_test or
+                                   load_consolidate_test, or finish converting it
+    fsck [--repair] <store root>   check a gc_test store, optionally repairing it";
+
+/// Exit code when problems were found and left in place.
+const EXIT_PROBLEMS: i32 = 1;
+/// Exit code for bad arguments or a store that couldn't be checked.
+const EXIT_ERROR: i32 = 2;
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["sessions", root] => run_inspect(Path::new(root), Command::Sessions),
+        ["dump", root, session_id] => {
+            run_inspect(Path::new(root), Command::Dump(session_id.to_string()))
+        }
+        ["payloads", root] => run_inspect(Path::new(root), Command::Payloads),
+        ["stats", root] => run_inspect(Path::new(root), Command::Stats),
+        ["export", root, path] => run_export(Path::new(root), Path::new(path)),
+        ["import", path, root] => run_import(Path::new(path), Path::new(root), None),
+        ["import", "--layout", layout, path, root] => match Layout::from_name(layout) {
+            Some(layout) => run_import(Path::new(path), Path::new(root), Some(layout)),
+            None => {
+                eprintln!("{}", USAGE);
+                process::exit(EXIT_ERROR);
+            }
+        },
+        ["convert", root, layout] => match Layout::from_name(layout) {
+            Some(layout) => run_convert(Path::new(root), layout),
+            None => {
+                eprintln!("{}", USAGE);
+                process::exit(EXIT_ERROR);
+            }
+        },
+        ["fsck", root] => run_fsck(Path::new(root), false),
+        ["fsck", "--repair", root] => run_fsck(Path::new(root), true),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(EXIT_ERROR);
+        }
+    };
+
+    match result {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+/// Opens the store read-only with whichever layout it is in, failing if it
+/// was written by an older version. Returns the exit code.
+fn run_inspect(root: &Path, command: Command) -> Result<i32, StoreError> {
+    let layout = match Layout::detect(root)? {
+        Some(layout) => layout,
+        None => {
+            eprintln!("{} holds no sessions or payloads", root.display());
+            return Ok(0);
+        }
+    };
+    let mut out = io::stdout().lock();
+    match layout {
+        Layout::Gc => {
+            let store = gc_test::DB::open_read_only(root)?;
+            inspect::run(&store, root, layout, &command, &mut out)?
+        }
+        Layout::LoadConsolidate => {
+            let store = load_consolidate_test::DB::open_read_only(root)?;
+            inspect::run(&store, root, layout, &command, &mut out)?
+        }
+    }
+
+    Ok(0)
+}
+
+/// Opens the store read-only, so exporting never changes it.
+fn run_export(root: &Path, path: &Path) -> Result<i32, StoreError> {
+    let layout = match Layout::detect(root)? {
+        Some(layout) => layout,
+        None => {
+            eprintln!("error: {} holds no sessions to export", root.display());
+            return Ok(EXIT_ERROR);
+        }
+    };
+    let stats = match layout {
+        Layout::Gc => {
+            let store = gc_test::DB::open_read_only(root)?;
+            archive::export(&store, BufWriter::new(File::create(path)?))?
+        }
+        Layout::LoadConsolidate => {
+            let store = load_consolidate_test::DB::open_read_only(root)?;
+            archive::export(&store, BufWriter::new(File::create(path)?))?
+        }
+    };
+    print_archive_stats("exported", &stats);
+
+    Ok(0)
+}
+
+/// A store that doesn't exist yet needs `layout`, otherwise it must match.
+fn run_import(path: &Path, root: &Path, layout: Option<Layout>) -> Result<i32, StoreError> {
+    let detected = Layout::detect(root)?;
+    let layout = match (layout, detected) {
+        (Some(layout), Some(detected)) if layout != detected => {
+            eprintln!(
+                "error: {} holds a {} store",
+                root.display(),
+                detected.name()
+            );
+            return Ok(EXIT_ERROR);
+        }
+        (Some(layout), _) | (None, Some(layout)) => layout,
+        (None, None) => {
+            eprintln!("error: {} is not a store, pass --layout", root.display());
+            return Ok(EXIT_ERROR);
+        }
+    };
+
+    let reader = BufReader::new(File::open(path)?);
+    let stats = match layout {
+        Layout::Gc => archive::import(&gc_test::DB::new(root)?, reader)?,
+        Layout::LoadConsolidate => archive::import(&load_consolidate_test::DB::new(root)?, reader)?,
+    };
+    print_archive_stats("imported", &stats);
+
+    Ok(0)
+}
+
+fn print_archive_stats(verb: &str, stats: &ArchiveStats) {
+    println!(
+        "{} {} publishes in {} sessions, with {} payloads",
+        verb, stats.publishes, stats.sessions, stats.payloads
+    );
+}
+
+fn run_convert(root: &Path, layout: Layout) -> Result<i32, StoreError> {
+    match convert::convert(root, layout, Durability::default())? {
+        Outcome::Converted => println!("converted to {}", layout.name()),
+        Outcome::Unchanged => println!("already {}", layout.name()),
+        Outcome::ConvertingTo(to) => {
+            eprintln!(
+                "error: {} is already being converted to {}, finish that first",
+                root.display(),
+                to.name()
+            );
+            return Ok(EXIT_ERROR);
+        }
+    }
+
+    Ok(0)
+}
+
+/// Checks, and optionally repairs, a `gc_test` store. Returns the exit code.
+fn run_fsck(root: &Path, repair: bool) -> Result<i32, StoreError> {
+    if !repair {
+        let report = fsck::check(root)?;
+        print_report(&report);
+        return Ok(if report.is_clean() { 0 } else { EXIT_PROBLEMS });
+    }
+
+    let report = fsck::repair(root, Durability::default())?;
+    print_report(&report);
+    if report.is_clean() {
+        return Ok(0);
+    }
+
+    let remaining = fsck::check(root)?;
+    for problem in remaining.problems.iter() {
+        println!("not repaired: {}", problem);
+    }
+    if remaining.is_clean() {
+        println!("repaired");
+        Ok(0)
+    } else {
+        Ok(EXIT_PROBLEMS)
+    }
+}
+
+fn print_report(report: &FsckReport) {
+    for problem in report.problems.iter() {
+        println!("{}", problem);
+    }
+    println!(
+        "{} sessions, {} messages, {} payloads, {} problems",
+        report.sessions,
+        report.messages,
+        report.payloads,
+        report.problems.len()
+    );
 }